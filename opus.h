@@ -70,11 +70,59 @@ struct OpusDecoder;
  * @param data Input payload. Use a NULL pointer to indicate packet loss.
  * @param len Number of bytes in payload.
  * @param pcm Output signal, with interleaved samples.
- * @returns Number of samples decoded
+ * @returns Number of samples decoded, or a negative error code
  */
 int opus_decoder_decode(struct OpusDecoder *st, const unsigned char *data,
 		        size_t len, float *pcm);
 
+/**
+ * Whether the last decoded frame was a DTX (discontinuous transmission) frame.
+ * Comfort noise is output for DTX frames, and for lost packets during DTX.
+ * @param st Decoder state.
+ * @returns 1 in DTX, 0 otherwise
+ */
+int opus_decoder_get_in_dtx(const struct OpusDecoder *st);
+
 struct OpusDecoder* opus_decoder_create();
 
+/**
+ * Reset the decoder state, e.g. after seeking or a discontinuity in the stream.
+ * @param st Decoder state.
+ */
+void opus_decoder_reset(struct OpusDecoder *st);
+
+/**
+ * Opus multistream decoder state.
+ * Decodes packets made of several Opus streams, mono or stereo, and maps the
+ * decoded channels to the output channels.
+ */
+struct OpusMSDecoder;
+
+/**
+ * Create a multistream decoder.
+ * @param channels Number of output channels.
+ * @param streams Number of streams in each packet.
+ * @param coupled_streams Number of those streams that are stereo.
+ * @param mapping For each output channel, the index of the decoded channel,
+ *                coupled streams first, or 255 for silence.
+ * @returns The decoder, or NULL if the arguments are invalid
+ */
+struct OpusMSDecoder *opus_multistream_decoder_create(int channels, int streams,
+                                                      int coupled_streams,
+                                                      const unsigned char *mapping);
+
+/**
+ * Decode a multistream packet with floating point output.
+ * @param st Multistream decoder state.
+ * @param data Input payload. Use a NULL pointer to indicate packet loss.
+ * @param len Number of bytes in payload.
+ * @param pcm Output signal, with interleaved samples of all the channels. It must have
+ *            room for the duration of the packet, or 20 ms for a lost packet.
+ * @returns Number of samples decoded, or a negative error code
+ */
+int opus_multistream_decoder_decode(struct OpusMSDecoder *st, const unsigned char *data,
+                                    size_t len, float *pcm);
+
+void opus_multistream_decoder_destroy(struct OpusMSDecoder *st);
+
 #endif /* OPUS_H */
@@ -2,6 +2,7 @@ use consts;
 use std;
 use utils;
 
+/// `x` holds the spectrum of each coded channel, `consts::FRAME_SIZE` bins each.
 pub fn anti_collapse(x: &mut [f32],
                      collapse_masks: &[u8],
                      log_e: &[f32],
@@ -9,13 +10,21 @@ pub fn anti_collapse(x: &mut [f32],
                      prev2log_e: &[f32],
                      pulses: &[i32],
                      mut seed: u32) {
+    let channels = x.len() / consts::FRAME_SIZE;
     for i in 0..pulses.len() {
         let n0 = consts::BANDS[i + 1] - consts::BANDS[i];
         let depth = (1 + pulses[i]) / n0 as i32 / 8; // depth in 1/8 bits
         let thresh = (-depth as f32 / 8.0).exp2() / 2.0;
-        for c in 0..2 {
-            let e_diff = prev1log_e[21 * c + i].min(prev2log_e[21 * c + i]) - log_e[21 * c + i];
-            let temp = x.len() / 2 * c;
+        for c in 0..channels {
+            let mut prev1 = prev1log_e[21 * c + i];
+            let mut prev2 = prev2log_e[21 * c + i];
+            if channels == 1 {
+                // The history of a mono stream may come from stereo frames.
+                prev1 = prev1.max(prev1log_e[21 + i]);
+                prev2 = prev2.max(prev2log_e[21 + i]);
+            }
+            let e_diff = prev1.min(prev2) - log_e[21 * c + i];
+            let temp = consts::FRAME_SIZE * c;
             let x_off = &mut x[temp + 8 * consts::BANDS[i]..temp + 8 * consts::BANDS[i + 1]];
             let r = (e_diff.exp2() * 2.0 * std::f32::consts::SQRT_2).min(thresh) /
                     (x_off.len() as f32).sqrt();
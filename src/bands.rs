@@ -86,45 +86,70 @@ fn quant_nosplit(cache: &[i32],
         return 0;
     }
 
-    match lowband {
-        None => for i in 0..x.len() {
-            ctx.seed = utils::lcg_rand(ctx.seed);
-            x[i] = ctx.seed as i32 as f32;
+    let cm = match lowband {
+        None => {
+            for i in 0..x.len() {
+                ctx.seed = utils::lcg_rand(ctx.seed);
+                x[i] = ctx.seed as i32 as f32;
+            }
+            (1 << stride as u32) - 1
         },
-        Some(v) => for i in 0..x.len() {
-            ctx.seed = utils::lcg_rand(ctx.seed);
-            let tmp = if ctx.seed & 0x8000 != 0 { 1.0 / 256.0 } else { -1.0 / 256.0 };
-            x[i] = v[i] + tmp;
+        Some(v) => {
+            for i in 0..x.len() {
+                ctx.seed = utils::lcg_rand(ctx.seed);
+                let tmp = if ctx.seed & 0x8000 != 0 { 1.0 / 256.0 } else { -1.0 / 256.0 };
+                x[i] = v[i] + tmp;
+            }
+            fill
         },
-    }
+    };
     utils::renormalise(x, gain);
-    return fill;
+    return cm;
 }
 
-fn compute_theta(qn: i32,
-                 ec: &mut entdec::EntropyCoder,
+fn compute_theta(ec: &mut entdec::EntropyCoder,
                  ctx: &mut BandCtx,
                  sctx: &mut SplitCtx,
                  n: usize,
                  b: &mut i32,
+                 blocks: i32,
                  b0: i32,
-                 is_stereo: bool) {
+                 lm: i32,
+                 is_stereo: bool,
+                 fill: &mut u32) {
+    let qn = if is_stereo && ctx.i >= ctx.intensity {
+        1
+    } else {
+        bands_utils::compute_qn(ctx.i, n as i32, *b, lm, is_stereo)
+    };
     let tell = ec.tell_frac() as i32;
     sctx.is_inv = false;
-    if is_stereo && ctx.i >= ctx.intensity {
-        if *b > 16 {
-            sctx.is_inv = ec.decode_bit_logp(2) == 1;
-        }
-        sctx.itheta = 0;
-    } else {
+    sctx.itheta = 0;
+    if qn != 1 {
         sctx.itheta = bands_utils::get_theta(ec, qn, b0, is_stereo) * 16384 / qn;
+    } else if is_stereo && *b > 2 * 8 && ctx.remaining_bits > 2 * 8 {
+        // Only need one bit for the side.
+        sctx.is_inv = ec.decode_bit_logp(2) == 1;
     }
     sctx.qalloc = ec.tell_frac() as i32 - tell;
     *b -= sctx.qalloc;
 
-    sctx.imid = bands_utils::bitexact_cos(sctx.itheta as i16) as i32;
-    sctx.iside = bands_utils::bitexact_cos(16384 - sctx.itheta as i16) as i32;
-    sctx.delta = ((n as i32 - 1) * bands_utils::bitexact_log2tan(sctx.iside, sctx.imid) + (1 << 7)) >> 8;
+    if sctx.itheta == 0 {
+        sctx.imid = 32767;
+        sctx.iside = 0;
+        *fill &= (1 << blocks) - 1;
+        sctx.delta = -16384;
+    } else if sctx.itheta == 16384 {
+        sctx.imid = 0;
+        sctx.iside = 32767;
+        *fill &= ((1 << blocks) - 1) << blocks;
+        sctx.delta = 16384;
+    } else {
+        sctx.imid = bands_utils::bitexact_cos(sctx.itheta as i16) as i32;
+        sctx.iside = bands_utils::bitexact_cos(16384 - sctx.itheta as i16) as i32;
+        sctx.delta = bands_utils::frac_mul16((n as i32 - 1) << 7,
+                                             bands_utils::bitexact_log2tan(sctx.iside, sctx.imid));
+    }
 }
 
 /// 4.3.4.4. Split Decoding
@@ -135,7 +160,7 @@ fn quant_partition(v: &Vec<Vec<Option<u32>>>,
                    ctx: &mut BandCtx,
                    x: &mut [f32],
                    mut b: i32,
-                   mut b0: i32,
+                   mut blocks: i32,
                    lowband: Option<&mut [f32]>,
                    mut lm: i32,
                    gain: f32,
@@ -144,14 +169,18 @@ fn quant_partition(v: &Vec<Vec<Option<u32>>>,
     {
         let cache = consts::BITS_CACHE[consts::BITS_CACHE_INDEX[(lm + 1) as usize][ctx.i]];
         if lm == -1 || x.len() <= 2 || b <= cache[cache.len() - 1] as i32 + 11 {
-            return quant_nosplit(cache, v, ec, ctx, x, b, if b0 == 0 { 1 } else { b0 as usize }, lowband, gain, fill);
+            return quant_nosplit(cache, v, ec, ctx, x, b, blocks as usize, lowband, gain, fill);
         }
     }
 
     let n = x.len() / 2;
     let (x, y) = x.split_at_mut(n);
     lm -= 1;
-    fill = (fill & 1) | (fill << 1);
+    if blocks == 1 {
+        fill = (fill & 1) | (fill << 1);
+    }
+    let b0 = blocks;
+    blocks = (blocks + 1) >> 1;
 
     let mut sctx = SplitCtx {
         is_inv: false,
@@ -161,25 +190,19 @@ fn quant_partition(v: &Vec<Vec<Option<u32>>>,
         itheta: 0,
         qalloc: 0,
     };
-    let qn = bands_utils::compute_qn(consts::BAND_WIDTHS[ctx.i], n as i32, b, lm);
-    compute_theta(qn, ec, ctx, &mut sctx, n, &mut b, b0, false);
+    compute_theta(ec, ctx, &mut sctx, n, &mut b, blocks, b0, lm, false, &mut fill);
 
-    b0 /= 2;
-    if b0 > 0 {
+    // Give more bits to low-energy MDCTs than they would otherwise deserve
+    if b0 > 1 && sctx.itheta & 0x3fff != 0 {
         if sctx.itheta > 8192 {
+            // Rough approximation for pre-echo masking
             sctx.delta -= sctx.delta >> (4 - lm);
         } else {
+            // Corresponds to a forward-masking slope of 1.5 dB per 10 ms
             sctx.delta = std::cmp::min(0, sctx.delta + (n as i32 >> (2 - lm)));
         }
     }
 
-    if sctx.itheta == 0 {
-        fill &= (1 << b0) - 1;
-    }
-    if sctx.itheta == 16384 {
-        fill &= ((1 << b0) - 1) << b0;
-    }
-
     let mut mbits = std::cmp::max(0, std::cmp::min(b, (b - sctx.delta) / 2));
     let mut sbits = b - mbits;
     let mid = sctx.imid as f32 / 32768.0;
@@ -190,37 +213,37 @@ fn quant_partition(v: &Vec<Vec<Option<u32>>>,
     match lowband {
         None => {
             if mbits >= sbits {
-                cm = quant_partition(v, ec, ctx, x, mbits, b0, None, lm, gain * mid, fill);
+                cm = quant_partition(v, ec, ctx, x, mbits, blocks, None, lm, gain * mid, fill);
                 rebalance = mbits - (rebalance - ctx.remaining_bits);
                 if rebalance > 3 * 8 && sctx.itheta != 0 {
                     sbits += rebalance - 3 * 8;
                 }
-                cm |= quant_partition(v, ec, ctx, y, sbits, b0, None, lm, gain * side, fill >> b0) << b0;
+                cm |= quant_partition(v, ec, ctx, y, sbits, blocks, None, lm, gain * side, fill >> blocks) << (b0 >> 1);
             } else {
-                cm = quant_partition(v, ec, ctx, y, sbits, b0, None, lm, gain * side, fill >> b0) << b0;
+                cm = quant_partition(v, ec, ctx, y, sbits, blocks, None, lm, gain * side, fill >> blocks) << (b0 >> 1);
                 rebalance = sbits - (rebalance - ctx.remaining_bits);
                 if rebalance > 3 * 8 && sctx.itheta != 16384 {
                     mbits += rebalance - 3 * 8;
                 }
-                cm |= quant_partition(v, ec, ctx, x, mbits, b0, None, lm, gain * mid, fill);
+                cm |= quant_partition(v, ec, ctx, x, mbits, blocks, None, lm, gain * mid, fill);
             }
         },
         Some(lb) => {
             // >32-bit split case
             if mbits >= sbits {
-                cm = quant_partition(v, ec, ctx, x, mbits, b0, Some(&mut lb[..n]), lm, gain * mid, fill);
+                cm = quant_partition(v, ec, ctx, x, mbits, blocks, Some(&mut lb[..n]), lm, gain * mid, fill);
                 rebalance = mbits - (rebalance - ctx.remaining_bits);
                 if rebalance > 3 * 8 && sctx.itheta != 0 {
                     sbits += rebalance - 3 * 8;
                 }
-                cm |= quant_partition(v, ec, ctx, y, sbits, b0, Some(&mut lb[n..]), lm, gain * side, fill >> b0) << b0;
+                cm |= quant_partition(v, ec, ctx, y, sbits, blocks, Some(&mut lb[n..]), lm, gain * side, fill >> blocks) << (b0 >> 1);
             } else {
-                cm = quant_partition(v, ec, ctx, y, sbits, b0, Some(&mut lb[n..]), lm, gain * side, fill >> b0) << b0;
+                cm = quant_partition(v, ec, ctx, y, sbits, blocks, Some(&mut lb[n..]), lm, gain * side, fill >> blocks) << (b0 >> 1);
                 rebalance = sbits - (rebalance - ctx.remaining_bits);
                 if rebalance > 3 * 8 && sctx.itheta != 16384 {
                     mbits += rebalance - 3 * 8;
                 }
-                cm |= quant_partition(v, ec, ctx, x, mbits, b0, Some(&mut lb[..n]), lm, gain * mid, fill);
+                cm |= quant_partition(v, ec, ctx, x, mbits, blocks, Some(&mut lb[..n]), lm, gain * mid, fill);
             }
         },
     }
@@ -277,8 +300,10 @@ fn quant_band_mono(v: &Vec<Vec<Option<u32>>>,
     }
 
     // frequency order -> time order
-    if let Some(ref mut v) = lowband {
-        bands_utils::deinterleave_hadamard(v, b0 << recombine, !transient);
+    if b0 > 1 {
+        if let Some(ref mut v) = lowband {
+            bands_utils::deinterleave_hadamard(v, b0 << recombine, !transient);
+        }
     }
     let mut cm;
     match lowband {
@@ -340,15 +365,8 @@ fn quant_band_stereo(v: &Vec<Vec<Option<u32>>>,
         itheta: 0,
         qalloc: 0,
     };
-    let qn = bands_utils::compute_qn(consts::BAND_WIDTHS[ctx.i], x.len() as i32, b, 3);
-    compute_theta(qn, ec, ctx, &mut sctx, x.len(), &mut b, b0, true);
-
-    if sctx.itheta == 0 {
-        fill &= (1 << b0) - 1;
-    }
-    if sctx.itheta == 16384 {
-        fill &= ((1 << b0) - 1) << b0;
-    }
+    let orig_fill = fill;
+    compute_theta(ec, ctx, &mut sctx, x.len(), &mut b, b0, b0, 3, true, &mut fill);
 
     let mut mbits = std::cmp::max(0, std::cmp::min(b, (b - sctx.delta) / 2));
     let mut sbits = b - mbits;
@@ -366,10 +384,10 @@ fn quant_band_stereo(v: &Vec<Vec<Option<u32>>>,
     } else {
         cm = quant_band_mono(v, ec, ctx, y, sbits, transient, side, None, fill >> b0);
         rebalance = sbits - (rebalance - ctx.remaining_bits);
-        if rebalance > 3 * 8 {
+        if rebalance > 3 * 8 && sctx.itheta != 16384 {
             mbits += rebalance - 3 * 8;
         }
-        cm |= quant_band_mono(v, ec, ctx, x, mbits, transient, 1.0, lowband, fill);
+        cm |= quant_band_mono(v, ec, ctx, x, mbits, transient, 1.0, lowband, orig_fill);
     }
     // Scale output for later folding
     if let Some(v) = lowband_out {
@@ -388,7 +406,10 @@ fn quant_band_stereo(v: &Vec<Vec<Option<u32>>>,
     return cm;
 }
 
+/// Decode the band shapes into `x` and `y`, the spectra of the two channels, with `y` empty for
+/// a mono stream.
 pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
+                       ec: &mut entdec::EntropyCoder,
                        x: &mut [f32],
                        y: &mut [f32],
                        collapse_masks: &mut [u8],
@@ -399,6 +420,7 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
                        intensity: usize,
                        tf_res: &[i32],
                        total_bits: usize,
+                       mut balance: i32,
                        coded_bands: usize) {
     let mut ctx = BandCtx {
         i: 0,
@@ -406,34 +428,36 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
         spread: spread,
         tf_change: 0,
         remaining_bits: 0,
-        seed: st.range,
+        seed: st.seed,
     };
 
     let mut lowband_offset = 0;
-    let mut balance = st.ec.tell_frac() as i32;
     let mut is_update_lowband = true;
     let mut norm_x = vec![0.0; 8 * consts::BANDS[20]];
     let mut norm_y = vec![0.0; 8 * consts::BANDS[20]];
     for i in 0..21 {
         let band = 8 * consts::BANDS[i];
         let n = 8 * consts::BAND_WIDTHS[i];
-        let tell = st.ec.tell_frac() as i32;
+        let tell = ec.tell_frac() as i32;
         ctx.i = i;
         ctx.remaining_bits = total_bits as i32 - tell - 1;
         ctx.tf_change = tf_res[i];
-        balance -= tell;
+        if i != 0 {
+            balance -= tell;
+        }
         let b = if i < coded_bands {
-            pulses[i] + balance / std::cmp::min(3, coded_bands - i) as i32
+            let curr_balance = balance / std::cmp::min(3, coded_bands - i) as i32;
+            std::cmp::min(ctx.remaining_bits + 1, pulses[i] + curr_balance).clamp(0, 16383)
         } else {
             0
         };
-        if is_update_lowband {
+        if i > 0 && (is_update_lowband || lowband_offset == 0) {
             lowband_offset = i;
         }
         let mut x_cm = 0;
         let mut y_cm = 0;
         let mut effective_lowband = 0;
-        bands_utils::get_estimate(lowband_offset, collapse_masks, spread, ctx.tf_change, n,
+        bands_utils::get_estimate(i, lowband_offset, collapse_masks, spread, ctx.tf_change, n,
                                   transient, &mut x_cm, &mut y_cm, &mut effective_lowband);
 
         if is_dual_stereo && i == intensity {
@@ -442,14 +466,29 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
                 norm_x[j] = (norm_x[j] + norm_y[j]) / 2.0;
             }
         }
-        if is_dual_stereo {
+        if y.is_empty() {
+            let lowband = if effective_lowband != -1 {
+                Some(&mut norm_x[effective_lowband as usize..effective_lowband as usize + n])
+            } else {
+                None
+            };
+            x_cm = quant_band_mono(&st.mode.v, ec, &mut ctx, &mut x[band..band + n], b, transient, 1.0, lowband, x_cm as u32) as u8;
+            if i != 20 {
+                // Scale output for later folding
+                let f = (n as f32).sqrt();
+                for j in 0..n {
+                    norm_x[band + j] = f * x[band + j];
+                }
+            }
+            y_cm = x_cm;
+        } else if is_dual_stereo {
             {
                 let lowband = if effective_lowband != -1 {
                     Some(&mut norm_x[effective_lowband as usize..effective_lowband as usize + n])
                 } else {
                     None
                 };
-                x_cm = quant_band_mono(&st.mode.v, &mut st.ec, &mut ctx, &mut x[band..band + n], b / 2, transient, 1.0, lowband, x_cm as u32) as u8;
+                x_cm = quant_band_mono(&st.mode.v, ec, &mut ctx, &mut x[band..band + n], b / 2, transient, 1.0, lowband, x_cm as u32) as u8;
             }
             if i != 20 {
                 // Scale output for later folding
@@ -464,7 +503,7 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
                 } else {
                     None
                 };
-                y_cm = quant_band_mono(&st.mode.v, &mut st.ec, &mut ctx, &mut y[band..band + n], b / 2, transient, 1.0, lowband, y_cm as u32) as u8;
+                y_cm = quant_band_mono(&st.mode.v, ec, &mut ctx, &mut y[band..band + n], b / 2, transient, 1.0, lowband, y_cm as u32) as u8;
             }
             if i != 20 {
                 // Scale output for later folding
@@ -485,7 +524,7 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
             } else {
                 None
             };
-            x_cm = quant_band_stereo(&st.mode.v, &mut st.ec, &mut ctx, &mut x[band..band + n], &mut y[band..band + n], b, transient, lowband, lowband_out, (x_cm | y_cm) as u32) as u8;
+            x_cm = quant_band_stereo(&st.mode.v, ec, &mut ctx, &mut x[band..band + n], &mut y[band..band + n], b, transient, lowband, lowband_out, (x_cm | y_cm) as u32) as u8;
             y_cm = x_cm;
         }
         collapse_masks[i * 2] = x_cm;
@@ -494,5 +533,5 @@ pub fn quant_all_bands(st: &mut opus_decoder::OpusDecoder,
 
         is_update_lowband = b > n as i32 * 8;
     }
-    st.range = ctx.seed;
+    st.seed = ctx.seed;
 }
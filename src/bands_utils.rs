@@ -102,13 +102,22 @@ pub fn haar1(x: &mut [f32], stride: usize) {
 }
 
 const QTHETA_OFFSET: i32 = 4;
+const QTHETA_OFFSET_TWOPHASE: i32 = 16;
+const EXP2_TABLE8: [i32; 8] = [16384, 17866, 19483, 21247, 23170, 25267, 27554, 30048];
 
-pub fn compute_qn(band_width: usize, n: i32, b: i32, lm: i32) -> i32 {
-    let pulse_cap = ((band_width as f32).log2() * 8.0).ceil() as i32 + lm * 8;
-    let offset = pulse_cap / 2 - QTHETA_OFFSET;
-    let qb = b / (2 * n - 1) + offset;
-    let qn = ((qb as f32 / 8.0).exp2() / 2.0).round() as i32 * 2;
-    return std::cmp::min(qn, 256);
+/// Number of quantization steps of the split angle of a band of `n` bins in `lm`, with `b` bits.
+/// A single step means that the angle is not coded.
+pub fn compute_qn(band: usize, n: i32, b: i32, lm: i32, is_stereo: bool) -> i32 {
+    let pulse_cap = consts::LOG_N[band] + lm * 8;
+    let n2 = if is_stereo && n == 2 { 2 * n - 2 } else { 2 * n - 1 };
+    let offset = (pulse_cap >> 1) -
+                 if is_stereo && n == 2 { QTHETA_OFFSET_TWOPHASE } else { QTHETA_OFFSET };
+    let qb = (b + n2 * offset) / n2;
+    let qb = std::cmp::min(8 * 8, std::cmp::min(b - pulse_cap - 4 * 8, qb));
+    if qb < 4 {
+        return 1;
+    }
+    return ((EXP2_TABLE8[qb as usize & 7] >> (14 - (qb >> 3))) + 1) >> 1 << 1;
 }
 
 pub fn get_theta(ec: &mut entdec::EntropyCoder, qn: i32, b0: i32, is_stereo: bool) -> i32 {
@@ -157,7 +166,8 @@ pub fn get_theta(ec: &mut entdec::EntropyCoder, qn: i32, b0: i32, is_stereo: boo
 
 const SPREAD_AGGRESSIVE: i32 = 3;
 
-pub fn get_estimate(offset: usize,
+pub fn get_estimate(band: usize,
+                    offset: usize,
                     collapse_masks: &[u8],
                     spread: i32,
                     tf_change: i32,
@@ -166,7 +176,7 @@ pub fn get_estimate(offset: usize,
                     x_cm: &mut u8,
                     y_cm: &mut u8,
                     lowband: &mut i32) {
-    if offset == 0 || (spread == SPREAD_AGGRESSIVE && tf_change >= 0) {
+    if offset == 0 || (spread == SPREAD_AGGRESSIVE && !transient && tf_change >= 0) {
         *lowband = -1;
         if transient {
             *x_cm = 255;
@@ -176,14 +186,21 @@ pub fn get_estimate(offset: usize,
             *y_cm = 1;
         }
     } else {
-        *lowband = 8 * consts::BANDS[offset] as i32 - n as i32;
+        // This ensures we never repeat spectral content within one band
+        *lowband = std::cmp::max(0, 8 * consts::BANDS[offset] as i32 - n as i32);
+        let mut start = offset - 1;
+        while 8 * consts::BANDS[start] as i32 > *lowband {
+            start -= 1;
+        }
+        let mut end = offset;
+        while end < band && 8 * (consts::BANDS[end] as i32) < *lowband + n as i32 {
+            end += 1;
+        }
         *x_cm = 0;
         *y_cm = 0;
-        let mut i = offset;
-        while 8 * consts::BANDS[i] as i32 > *lowband {
+        for i in start..end {
             *x_cm |= collapse_masks[i * 2];
             *y_cm |= collapse_masks[i * 2 + 1];
-            i -= 1;
         }
     }
 }
@@ -0,0 +1,91 @@
+extern crate rumpus;
+
+use rumpus::compare;
+use std::env;
+use std::fs;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [-s] [-r rate2] <file1.sw> <file2.sw>", program);
+    eprintln!("    <file1.sw> is the reference, 16-bit stereo at 48 kHz.");
+    eprintln!("    <file2.sw> is the 16-bit output at rate2 (48000 by default), mono or stereo");
+    eprintln!("    with -s.");
+    process::exit(1);
+}
+
+/// Read a raw 16-bit little-endian file as floats.
+fn read_pcm16(path: &str) -> Vec<f32> {
+    match fs::read(path) {
+        Ok(data) => {
+            data.chunks_exact(2)
+                .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+                .collect()
+        }
+        Err(err) => {
+            eprintln!("Error opening '{}': {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args.first().map_or("opus_compare", |s| s.as_str());
+    let mut channels = 1;
+    let mut rate = 48000;
+    let mut files = vec![];
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-s" => channels = 2,
+            "-r" => {
+                i += 1;
+                rate = match args.get(i).and_then(|r| r.parse().ok()) {
+                    Some(rate) => rate,
+                    None => usage(program),
+                };
+            }
+            file => files.push(file),
+        }
+        i += 1;
+    }
+    if files.len() != 2 {
+        usage(program);
+    }
+    if ![8000, 12000, 16000, 24000, 48000].contains(&rate) {
+        eprintln!("Sampling rate must be 8000, 12000, 16000, 24000, or 48000");
+        process::exit(1);
+    }
+
+    // The reference is always stereo.
+    let mut x = read_pcm16(files[0]);
+    if channels == 1 {
+        x = x.chunks_exact(2).map(|s| 0.5 * (s[0] + s[1])).collect();
+    } else {
+        x.truncate(x.len() & !1);
+    }
+    let y = read_pcm16(files[1]);
+    let downsample = 48000 / rate as usize;
+    if x.len() / channels != y.len() / channels * downsample {
+        eprintln!("Sample counts do not match ({} != {})",
+                  x.len() / channels,
+                  y.len() / channels * downsample);
+        process::exit(1);
+    }
+    if x.len() / channels < 480 {
+        eprintln!("Insufficient sample data ({} < 480)", x.len() / channels);
+        process::exit(1);
+    }
+
+    let result = compare::compare(&x, &y, channels, rate).unwrap_or_else(|_| usage(program));
+    if result.passes() {
+        eprintln!("Test vector PASSES");
+        eprintln!("Opus quality metric: {:.1} % (internal weighted error is {:.6})",
+                  result.quality,
+                  result.error);
+    } else {
+        eprintln!("Test vector FAILS");
+        eprintln!("Internal weighted error is {:.6}", result.error);
+        process::exit(1);
+    }
+}
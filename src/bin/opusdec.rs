@@ -0,0 +1,211 @@
+extern crate rumpus;
+
+use rumpus::dither::{Dither, NoiseShaping};
+use rumpus::loudness::LoudnessMeter;
+use rumpus::oggopus::PlaybackGain;
+use rumpus::opusfile::OpusFile;
+use rumpus::packet;
+use rumpus::resample::{Quality, Resampler};
+use rumpus::wav::{SampleFormat, WavWriter};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process;
+
+struct Options {
+    format: SampleFormat,
+    rate: u32,
+    gain: PlaybackGain,
+    r128: bool,
+    input: String,
+    output: String,
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [options] <input> <output.wav>", program);
+    eprintln!("    <input> is an Ogg Opus, WebM or MP4 file.");
+    eprintln!("    <output.wav> may be \"-\" for standard output.");
+    eprintln!("Options:");
+    eprintln!("    --bits n     16 or 24-bit integer, or 32-bit float samples (16)");
+    eprintln!("    --float      same as --bits 32");
+    eprintln!("    --rate n     sample rate of the output (48000)");
+    eprintln!("    --gain g     none, header, track or album gain (header)");
+    eprintln!("    --no-gain    same as --gain none");
+    eprintln!("    --r128       measure the loudness, with the header gain, and print the");
+    eprintln!("                 R128_TRACK_GAIN tag normalising it to -23 LUFS");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let args: Vec<String> = env::args().collect();
+    let program = args.first().map_or("opusdec", |s| s.as_str());
+    let mut options = Options {
+        format: SampleFormat::I16,
+        rate: 48000,
+        gain: PlaybackGain::Header,
+        r128: false,
+        input: String::new(),
+        output: String::new(),
+    };
+    let mut files = vec![];
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--float" => options.format = SampleFormat::F32,
+            "--bits" => {
+                i += 1;
+                options.format = match args.get(i).map(|b| b.as_str()) {
+                    Some("16") => SampleFormat::I16,
+                    Some("24") => SampleFormat::I24,
+                    Some("32") => SampleFormat::F32,
+                    _ => usage(program),
+                };
+            }
+            "--no-gain" => options.gain = PlaybackGain::None,
+            "--r128" => options.r128 = true,
+            "--gain" => {
+                i += 1;
+                options.gain = match args.get(i).map(|g| g.as_str()) {
+                    Some("none") => PlaybackGain::None,
+                    Some("header") => PlaybackGain::Header,
+                    Some("track") => PlaybackGain::Track,
+                    Some("album") => PlaybackGain::Album,
+                    _ => usage(program),
+                };
+            }
+            "--rate" => {
+                i += 1;
+                options.rate = match args.get(i).and_then(|r| r.parse().ok()) {
+                    Some(rate) if rate > 0 => rate,
+                    _ => usage(program),
+                };
+            }
+            arg if arg.starts_with("--") => usage(program),
+            file => files.push(file.to_string()),
+        }
+        i += 1;
+    }
+    // The R128 gains apply on top of the output gain of the header.
+    if files.len() != 2 || options.r128 && options.gain != PlaybackGain::Header {
+        usage(program);
+    }
+    options.output = files.pop().unwrap();
+    options.input = files.pop().unwrap();
+    options
+}
+
+/// Write `pcm` to `wav`, dithered to 16-bit when `dither` is given.
+fn write<W: Write>(wav: &mut WavWriter<W>,
+                   dither: &mut Option<Dither>,
+                   pcm: &[f32],
+                   buffer: &mut Vec<i16>)
+                   -> io::Result<()> {
+    match *dither {
+        Some(ref mut dither) => {
+            buffer.resize(pcm.len(), 0);
+            dither.process(pcm, buffer);
+            wav.write_i16(buffer)
+        }
+        None => wav.write(pcm),
+    }
+}
+
+fn decode<R, W>(reader: &mut OpusFile<R>,
+                options: &Options,
+                meter: &mut Option<LoudnessMeter>,
+                output: W)
+                -> io::Result<WavWriter<W>>
+    where R: Read,
+          W: Write
+{
+    let channels = reader.channels();
+    let mut resampler = if options.rate != 48000 {
+        Some(Resampler::new(channels, 48000, options.rate, Quality::High)?)
+    } else {
+        None
+    };
+    let mut wav = WavWriter::new(output, channels, options.rate, options.format)?;
+    let mut pcm = vec![0.0; channels * packet::MAX_PACKET_DURATION];
+    let mut resampled = vec![];
+    let mut dither = if options.format == SampleFormat::I16 {
+        Some(Dither::new(channels, NoiseShaping::for_rate(options.rate)))
+    } else {
+        None
+    };
+    let mut dithered = vec![];
+    loop {
+        let n = reader.read(&mut pcm)?;
+        if n == 0 {
+            break;
+        }
+        if reader.channels() != channels {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "channel count changes between links"));
+        }
+        if let Some(ref mut meter) = *meter {
+            meter.process(&pcm[..n * channels]);
+        }
+        match resampler {
+            Some(ref mut resampler) => {
+                resampled.clear();
+                resampler.process(&pcm[..n * channels], &mut resampled);
+                write(&mut wav, &mut dither, &resampled, &mut dithered)?;
+            }
+            None => write(&mut wav, &mut dither, &pcm[..n * channels], &mut dithered)?,
+        }
+    }
+    if let Some(ref mut resampler) = resampler {
+        resampled.clear();
+        resampler.flush(&mut resampled);
+        write(&mut wav, &mut dither, &resampled, &mut dithered)?;
+    }
+    Ok(wav)
+}
+
+fn run(options: &Options) -> io::Result<u64> {
+    let input = File::open(&options.input)?;
+    let mut reader = OpusFile::new(BufReader::new(input))?;
+    reader.set_playback_gain(options.gain);
+    eprintln!("Playback gain: {:.2} dB", reader.gain());
+    let mut meter = if options.r128 {
+        Some(LoudnessMeter::new(reader.channels(), 48000)?)
+    } else {
+        None
+    };
+    let length;
+    // Standard output may be a pipe, where the header is left with unknown sizes.
+    if options.output == "-" {
+        let stdout = io::stdout();
+        let wav = decode(&mut reader, options, &mut meter, BufWriter::new(stdout.lock()))?;
+        length = wav.length();
+        wav.into_inner()?;
+    } else {
+        let output = BufWriter::new(File::create(&options.output)?);
+        let wav = decode(&mut reader, options, &mut meter, output)?;
+        length = wav.length();
+        wav.finish()?;
+    }
+    if let Some(meter) = meter {
+        match meter.integrated() {
+            Some(loudness) => {
+                eprintln!("Integrated loudness: {:.1} LUFS, true peak: {:.1} dBTP",
+                          loudness,
+                          meter.true_peak());
+                eprintln!("R128_TRACK_GAIN={}", meter.track_gain().unwrap());
+            }
+            None => eprintln!("Integrated loudness: below the absolute gate"),
+        }
+    }
+    Ok(length)
+}
+
+fn main() {
+    let options = parse_args();
+    match run(&options) {
+        Ok(samples) => eprintln!("Decoded {} samples.", samples),
+        Err(err) => {
+            eprintln!("{}: {}", options.input, err);
+            process::exit(1);
+        }
+    }
+}
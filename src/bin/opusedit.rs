@@ -0,0 +1,90 @@
+extern crate rumpus;
+
+use rumpus::edit;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+
+enum Command {
+    Cut {
+        input: String,
+        output: String,
+        start: u64,
+        end: Option<u64>,
+    },
+    Concat { inputs: Vec<String>, output: String },
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} cut <input> <output> <start> [<end>]", program);
+    eprintln!("       {} concat <output> <input>...", program);
+    eprintln!("    Cut or concatenate Ogg Opus files without re-encoding.");
+    eprintln!("    <start> and <end> are in samples at 48 kHz, or in seconds with an \"s\"");
+    eprintln!("    suffix, from the start of the audio after the pre-skip.");
+    process::exit(1);
+}
+
+/// A time in samples at 48 kHz, such as "48000", or in seconds, such as "1.5s".
+fn parse_time(time: &str) -> Option<u64> {
+    match time.strip_suffix('s') {
+        Some(seconds) => {
+            let seconds: f64 = seconds.parse().ok()?;
+            if seconds.is_finite() && seconds >= 0.0 {
+                Some((seconds * 48000.0).round() as u64)
+            } else {
+                None
+            }
+        }
+        None => time.parse().ok(),
+    }
+}
+
+fn parse_args() -> Command {
+    let args: Vec<String> = env::args().collect();
+    let program = args.first().map_or("opusedit", |s| s.as_str());
+    match args.get(1).map(|c| c.as_str()) {
+        Some("cut") if args.len() == 5 || args.len() == 6 => {
+            let start = parse_time(&args[4]).unwrap_or_else(|| usage(program));
+            let end = args.get(5).map(|end| parse_time(end).unwrap_or_else(|| usage(program)));
+            Command::Cut {
+                input: args[2].clone(),
+                output: args[3].clone(),
+                start,
+                end,
+            }
+        }
+        Some("concat") if args.len() >= 4 => {
+            Command::Concat {
+                inputs: args[3..].to_vec(),
+                output: args[2].clone(),
+            }
+        }
+        _ => usage(program),
+    }
+}
+
+fn run(command: &Command) -> io::Result<()> {
+    match *command {
+        Command::Cut { ref input, ref output, start, end } => {
+            let input = BufReader::new(File::open(input)?);
+            let output = BufWriter::new(File::create(output)?);
+            edit::cut(input, output, start, end)?.flush()
+        }
+        Command::Concat { ref inputs, ref output } => {
+            let inputs = inputs.iter()
+                .map(|input| File::open(input).map(BufReader::new))
+                .collect::<io::Result<Vec<_>>>()?;
+            let output = BufWriter::new(File::create(output)?);
+            edit::concat(inputs, output)?.flush()
+        }
+    }
+}
+
+fn main() {
+    let command = parse_args();
+    if let Err(err) = run(&command) {
+        eprintln!("opusedit: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,315 @@
+use error::Error;
+use std;
+
+const NBANDS: usize = 21;
+const NFREQS: usize = 240;
+const TEST_WIN_SIZE: usize = 480;
+const TEST_WIN_STEP: usize = 120;
+
+const BANDS: [usize; NBANDS + 1] = [0, 2, 4, 6, 8, 10, 12, 14, 16, 20, 24, 28, 32, 40, 48, 56, 68,
+                                    80, 96, 120, 156, 200];
+
+/// Result of `compare`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Internal weighted error.
+    pub error: f64,
+    /// Opus quality metric, in percent. The test vector passes when it is not negative.
+    pub quality: f64,
+}
+
+impl Quality {
+    pub fn passes(&self) -> bool {
+        self.quality >= 0.0
+    }
+}
+
+/// Bands and windows of the spectral analysis of a signal.
+#[derive(Clone, Copy)]
+struct Analysis<'a> {
+    bands: &'a [usize],
+    channels: usize,
+    frames: usize,
+    window_size: usize,
+    step: usize,
+    /// Ratio of 48 kHz to the sample rate of the signal.
+    downsample: usize,
+}
+
+/// Power spectrum of each window of `input`, in `ps`, and its average energy in each band, in
+/// `out`.
+fn band_energy(mut out: Option<&mut [f32]>, ps: &mut [f32], input: &[f32], analysis: Analysis) {
+    let Analysis { bands, channels, frames, window_size, step, downsample } = analysis;
+    let pi = std::f64::consts::PI;
+    let window: Vec<f32> = (0..window_size)
+        .map(|j| 0.5 - 0.5 * (2.0 * pi / (window_size - 1) as f64 * j as f64).cos() as f32)
+        .collect();
+    let c: Vec<f32> =
+        (0..window_size).map(|j| (2.0 * pi / window_size as f64 * j as f64).cos() as f32).collect();
+    let s: Vec<f32> =
+        (0..window_size).map(|j| (2.0 * pi / window_size as f64 * j as f64).sin() as f32).collect();
+    let ps_size = window_size / 2;
+    let mut x = vec![0.0; channels * window_size];
+    for i in 0..frames {
+        for ch in 0..channels {
+            for k in 0..window_size {
+                x[ch * window_size + k] = window[k] * input[(i * step + k) * channels + ch];
+            }
+        }
+        let mut j = 0;
+        for b in 0..bands.len() - 1 {
+            let mut p = [0.0; 2];
+            while j < bands[b + 1] {
+                for ch in 0..channels {
+                    let mut t = 0;
+                    let mut re = 0.0;
+                    let mut im = 0.0;
+                    for k in 0..window_size {
+                        re += c[t] * x[ch * window_size + k];
+                        im -= s[t] * x[ch * window_size + k];
+                        t += j;
+                        if t >= window_size {
+                            t -= window_size;
+                        }
+                    }
+                    re *= downsample as f32;
+                    im *= downsample as f32;
+                    let power = re * re + im * im + 100000.0;
+                    ps[(i * ps_size + j) * channels + ch] = power;
+                    p[ch] += power;
+                }
+                j += 1;
+            }
+            if let Some(ref mut out) = out {
+                for ch in 0..channels {
+                    out[(i * NBANDS + b) * channels + ch] = p[ch] / (bands[b + 1] - bands[b]) as f32;
+                }
+            }
+        }
+    }
+}
+
+/// The `opus_compare` quality metric of RFC 6716 conformance testing.
+///
+/// Compares the decoded signal `y`, at `rate`, with the reference `x`, at 48 kHz, both with
+/// `channels` interleaved channels of 1 or 2. The samples are floats in the [-1, 1] range. The
+/// error is a spectral distance, in bands, weighted by a simple model of frequency and temporal
+/// masking of the reference.
+pub fn compare(x: &[f32], y: &[f32], channels: usize, rate: u32) -> Result<Quality, Error> {
+    let nbands = match rate {
+        8000 => 13,
+        12000 => 15,
+        16000 => 17,
+        24000 => 19,
+        48000 => 21,
+        _ => return Err(Error::BadArg),
+    };
+    if channels != 1 && channels != 2 {
+        return Err(Error::BadArg);
+    }
+    let downsample = 48000 / rate as usize;
+    let yfreqs = NFREQS / downsample;
+    let xlength = x.len() / channels;
+    let ylength = y.len() / channels;
+    if xlength != ylength * downsample || xlength < TEST_WIN_SIZE {
+        return Err(Error::BadArg);
+    }
+    let x: Vec<f32> = x.iter().map(|&x| x * 32768.0).collect();
+    let y: Vec<f32> = y.iter().map(|&y| y * 32768.0).collect();
+
+    let frames = (xlength - TEST_WIN_SIZE + TEST_WIN_STEP) / TEST_WIN_STEP;
+    let mut xb = vec![0.0; frames * NBANDS * channels];
+    let mut xs = vec![0.0; frames * NFREQS * channels];
+    let mut ys = vec![0.0; frames * yfreqs * channels];
+    // Compute the per-band spectral energy of the original signal and the error.
+    band_energy(Some(&mut xb),
+                &mut xs,
+                &x,
+                Analysis {
+                    bands: &BANDS,
+                    channels,
+                    frames,
+                    window_size: TEST_WIN_SIZE,
+                    step: TEST_WIN_STEP,
+                    downsample: 1,
+                });
+    band_energy(None,
+                &mut ys,
+                &y,
+                Analysis {
+                    bands: &BANDS[..nbands + 1],
+                    channels,
+                    frames,
+                    window_size: TEST_WIN_SIZE / downsample,
+                    step: TEST_WIN_STEP / downsample,
+                    downsample,
+                });
+
+    for i in 0..frames {
+        let xb_at = |b: usize, ch: usize| (i * NBANDS + b) * channels + ch;
+        // Frequency masking (low to high): 10 dB/Bark slope.
+        for b in 1..NBANDS {
+            for ch in 0..channels {
+                xb[xb_at(b, ch)] += 0.1 * xb[xb_at(b - 1, ch)];
+            }
+        }
+        // Frequency masking (high to low): 15 dB/Bark slope.
+        for b in (0..NBANDS - 1).rev() {
+            for ch in 0..channels {
+                xb[xb_at(b, ch)] += 0.03 * xb[xb_at(b + 1, ch)];
+            }
+        }
+        if i > 0 {
+            // Temporal masking: -3 dB/2.5ms slope.
+            for b in 0..NBANDS {
+                for ch in 0..channels {
+                    xb[xb_at(b, ch)] += 0.5 * xb[xb_at(b, ch) - NBANDS * channels];
+                }
+            }
+        }
+        // Allowing some cross-talk.
+        if channels == 2 {
+            for b in 0..NBANDS {
+                let l = xb[xb_at(b, 0)];
+                let r = xb[xb_at(b, 1)];
+                xb[xb_at(b, 0)] += 0.01 * r;
+                xb[xb_at(b, 1)] += 0.01 * l;
+            }
+        }
+        // Apply masking.
+        for b in 0..nbands {
+            for j in BANDS[b]..BANDS[b + 1] {
+                for ch in 0..channels {
+                    xs[(i * NFREQS + j) * channels + ch] += 0.1 * xb[xb_at(b, ch)];
+                    ys[(i * yfreqs + j) * channels + ch] += 0.1 * xb[xb_at(b, ch)];
+                }
+            }
+        }
+    }
+
+    // Average of consecutive frames to make comparison slightly less sensitive.
+    for j in 0..BANDS[nbands] {
+        for ch in 0..channels {
+            let mut xtmp = xs[j * channels + ch];
+            let mut ytmp = ys[j * channels + ch];
+            for i in 1..frames {
+                let xtmp2 = xs[(i * NFREQS + j) * channels + ch];
+                let ytmp2 = ys[(i * yfreqs + j) * channels + ch];
+                xs[(i * NFREQS + j) * channels + ch] += xtmp;
+                ys[(i * yfreqs + j) * channels + ch] += ytmp;
+                xtmp = xtmp2;
+                ytmp = ytmp2;
+            }
+        }
+    }
+
+    // If working at a lower sampling rate, don't take into account the last 300 Hz to allow for
+    // different transition bands. For 12 kHz, we don't skip anything, because the last band
+    // already skips 400 Hz.
+    let max_compare = match rate {
+        48000 => BANDS[NBANDS],
+        12000 => BANDS[nbands],
+        _ => BANDS[nbands] - 3,
+    };
+    let mut err = 0.0;
+    for i in 0..frames {
+        let mut ef = 0.0;
+        for b in 0..nbands {
+            let mut eb = 0.0;
+            for j in BANDS[b]..std::cmp::min(BANDS[b + 1], max_compare) {
+                for ch in 0..channels {
+                    let re = ys[(i * yfreqs + j) * channels + ch] /
+                             xs[(i * NFREQS + j) * channels + ch];
+                    let mut im = re - re.ln() - 1.0;
+                    // Make comparison less sensitive around the SILK/CELT cross-over to allow
+                    // for mode freedom in the filters.
+                    if (79..=81).contains(&j) {
+                        im *= 0.1;
+                    }
+                    if j == 80 {
+                        im *= 0.1;
+                    }
+                    eb += im as f64;
+                }
+            }
+            eb /= ((BANDS[b + 1] - BANDS[b]) * channels) as f64;
+            ef += eb * eb;
+        }
+        // Using a fixed normalization value of 1/4 frame (=20 * 40 ms) to make the comparison
+        // less sensitive to long files with short errors.
+        ef /= NBANDS as f64;
+        ef *= ef;
+        err += ef * ef;
+    }
+    let error = (err / frames as f64).powf(1.0 / 16.0);
+    Ok(Quality {
+        error,
+        quality: 100.0 * (1.0 - 0.5 * (1.0 + error).ln() / 1.13f64.ln()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chord of `channels` interleaved channels at `rate`, with some noise from `seed`.
+    fn signal(channels: usize, rate: u32, noise: f32, mut seed: u32) -> Vec<f32> {
+        let length = rate as usize / 10;
+        let mut x = Vec::with_capacity(length * channels);
+        for i in 0..length {
+            let t = i as f32 / rate as f32;
+            for ch in 0..channels {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let n = (seed >> 16) as f32 / 65536.0 - 0.5;
+                let f = 440.0 * (ch + 1) as f32;
+                x.push(0.3 * (2.0 * std::f32::consts::PI * f * t).sin() +
+                       0.1 * (2.0 * std::f32::consts::PI * 3.0 * f * t).sin() + noise * n);
+            }
+        }
+        x
+    }
+
+    #[test]
+    fn identical() {
+        for &channels in &[1, 2] {
+            let x = signal(channels, 48000, 0.01, 1);
+            let quality = compare(&x, &x, channels, 48000).unwrap();
+            assert_eq!(quality.error, 0.0);
+            assert_eq!(quality.quality, 100.0);
+            assert!(quality.passes());
+        }
+    }
+
+    #[test]
+    fn slightly_different() {
+        let x = signal(2, 48000, 0.01, 1);
+        let y: Vec<f32> = x.iter().map(|&x| 0.98 * x).collect();
+        let quality = compare(&x, &y, 2, 48000).unwrap();
+        assert!(quality.passes());
+        assert!(quality.quality < 100.0);
+    }
+
+    #[test]
+    fn very_different() {
+        let x = signal(2, 48000, 0.0, 1);
+        let y = signal(2, 48000, 0.5, 2);
+        let quality = compare(&x, &y, 2, 48000).unwrap();
+        assert!(!quality.passes());
+    }
+
+    #[test]
+    fn lower_rate() {
+        let x = signal(1, 48000, 0.01, 1);
+        let y: Vec<f32> = x.iter().step_by(3).cloned().collect();
+        assert!(compare(&x, &y, 1, 16000).unwrap().quality.is_finite());
+    }
+
+    #[test]
+    fn bad_arguments() {
+        let x = signal(2, 48000, 0.01, 1);
+        assert_eq!(compare(&x, &x, 2, 44100), Err(Error::BadArg));
+        assert_eq!(compare(&x, &x, 3, 48000), Err(Error::BadArg));
+        assert_eq!(compare(&x, &x[..x.len() - 2], 2, 48000), Err(Error::BadArg));
+        assert_eq!(compare(&x[..100], &x[..100], 2, 48000), Err(Error::BadArg));
+    }
+}
@@ -1,4 +1,3 @@
-pub const NUM_CHANNELS: i32 = 2;
 pub const NUM_BANDS: usize = 21;
 pub const FRAME_SIZE: usize = 960;
 pub const WINDOW_SIZE: usize = 120;
@@ -24,6 +23,19 @@ pub const BAND_ALLOCATION: [[i32; NUM_BANDS]; NUM_QUALITIES] = [
     [200, 200, 200, 200, 200, 200, 200, 200, 198, 193, 188, 183, 178, 173, 168, 163, 158, 153, 148, 129, 104]
 ];
 
+/// Maximum allocation of each band for frames of 20 ms, in an encoding of libopus
+/// (`cache_caps50`), for 1 and 2 channels.
+pub const CACHE_CAPS: [[i32; NUM_BANDS]; 2] = [
+    [193, 193, 193, 193, 193, 193, 193, 193, 193, 193, 193, 193, 194, 194, 194, 184, 184, 173, 139, 65, 39],
+    [204, 204, 204, 204, 204, 204, 204, 204, 201, 201, 201, 201, 198, 198, 198, 187, 187, 175, 140, 66, 40]
+];
+
+/// Conservative log2 of 0 to 23, in 8th bits.
+pub const LOG2_FRAC: [i32; 24] = [0, 8, 13, 16, 19, 21, 23, 24, 26, 27, 28, 29, 30, 31, 32, 32, 33, 34, 34, 35, 36, 36, 37, 37];
+
+/// log2 of the number of MDCT bins of each band in frames of 2.5 ms, in 8th bits.
+pub const LOG_N: [i32; NUM_BANDS] = [0, 0, 0, 0, 0, 0, 0, 0, 8, 8, 8, 8, 16, 16, 16, 21, 21, 24, 29, 34, 36];
+
 pub const BITS_CACHE_INDEX: [[usize; NUM_BANDS]; 5] = [
     [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 2, 2, 3, 4, 5, 6],
     [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 3, 3, 3, 4, 4, 7, 8, 9, 10],
@@ -0,0 +1,193 @@
+use std;
+
+/// Noise shaping filter of the dither, matched to the sample rate of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseShaping {
+    /// 48 kHz noise shaping filter, sd = 2.34.
+    Rate48000,
+    /// 44.1 kHz noise shaping filter, sd = 2.51.
+    Rate44100,
+    /// Lowpass noise shaping filter, sd = 0.65, for other rates.
+    Lowpass,
+}
+
+impl NoiseShaping {
+    pub fn for_rate(rate: u32) -> NoiseShaping {
+        match rate {
+            48000 => NoiseShaping::Rate48000,
+            44100 => NoiseShaping::Rate44100,
+            _ => NoiseShaping::Lowpass,
+        }
+    }
+
+    /// Feedback coefficients of the error (b), then of the filter output (a).
+    fn coefficients(self) -> [f32; 8] {
+        match self {
+            NoiseShaping::Rate48000 => {
+                [2.2374, -0.7339, -0.1251, -0.6033, 0.9030, 0.0116, -0.5853, -0.2571]
+            }
+            NoiseShaping::Rate44100 => {
+                [2.2061, -0.4706, -0.2534, -0.6214, 1.0587, 0.0676, -0.6054, -0.2738]
+            }
+            NoiseShaping::Lowpass => [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// After this many silent samples, the dither is muted.
+const MUTE_DITHER: usize = 16;
+/// After this many silent samples, the noise shaping filter is cleared.
+const MUTE_FILTER: usize = 64;
+
+/// Conversion of float samples to 16-bit, with TPDF dither and noise shaping (from `opusdec`).
+///
+/// The rounding error of each sample, with the dither, is fed back through a 4-tap filter
+/// which moves the noise to frequencies where it is less audible. In order to avoid replacing
+/// digital silence with quiet dither noise, the dither and the feedback are muted once the
+/// input has been silent for a while.
+pub struct Dither {
+    channels: usize,
+    coefficients: [f32; 8],
+    /// Last rounding errors of each channel, most recent first.
+    b: Vec<[f32; 4]>,
+    /// Last outputs of the filter of each channel, most recent first.
+    a: Vec<[f32; 4]>,
+    /// Number of consecutive silent samples.
+    mute: usize,
+    seed: u32,
+}
+
+impl Dither {
+    pub fn new(channels: usize, shaping: NoiseShaping) -> Dither {
+        Dither {
+            channels,
+            coefficients: shaping.coefficients(),
+            b: vec![[0.0; 4]; channels],
+            a: vec![[0.0; 4]; channels],
+            mute: 0,
+            seed: 22222,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.b = vec![[0.0; 4]; self.channels];
+        self.a = vec![[0.0; 4]; self.channels];
+        self.mute = 0;
+    }
+
+    fn fast_rand(&mut self) -> u32 {
+        self.seed = self.seed.wrapping_mul(96314165).wrapping_add(907633515);
+        self.seed
+    }
+
+    /// Convert interleaved `input`, in [-1, 1], to `output` of the same length.
+    pub fn process(&mut self, input: &[f32], output: &mut [i16]) {
+        let gain = 32768.0 - 15.0;
+        let fcoef = self.coefficients;
+        if self.mute > MUTE_FILTER {
+            self.a = vec![[0.0; 4]; self.channels];
+        }
+        for (input, output) in input.chunks(self.channels).zip(output.chunks_mut(self.channels)) {
+            let mut silent = true;
+            for c in 0..self.channels {
+                silent &= input[c] == 0.0;
+                let mut s = input[c] * gain;
+                let mut err = 0.0;
+                for j in 0..4 {
+                    err += fcoef[j] * self.b[c][j] - fcoef[j + 4] * self.a[c][j];
+                }
+                self.a[c].rotate_right(1);
+                self.b[c].rotate_right(1);
+                self.a[c][0] = err;
+                s -= err;
+                let scale = 1.0 / u32::MAX as f32;
+                let mut r = self.fast_rand() as f32 * scale - self.fast_rand() as f32 * scale;
+                if self.mute > MUTE_DITHER {
+                    r = 0.0;
+                }
+                // Clamp in float out of paranoia that the input will be >96 dBFS and wrap if
+                // the integer is clamped.
+                let si = (s + r).clamp(-32768.0, 32767.0).round() as i16;
+                output[c] = si;
+                // Including clipping in the noise shaping is generally disastrous: the futile
+                // effort to restore the clipped energy results in more clipping. However, small
+                // amounts-- at the level which could normally be created by dither and
+                // rounding-- are harmless and can even reduce clipping somewhat due to the
+                // clipping sometimes reducing the dither+rounding error.
+                self.b[c][0] = if self.mute > MUTE_DITHER {
+                    0.0
+                } else {
+                    (si as f32 - s).clamp(-1.5, 1.5)
+                };
+            }
+            self.mute = if silent { self.mute + 1 } else { 0 };
+        }
+        self.mute = std::cmp::min(self.mute, 960);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAIN: f32 = 32768.0 - 15.0;
+
+    /// Dither a slow sine, and return the error of each output sample.
+    fn errors(shaping: NoiseShaping) -> Vec<f32> {
+        let input: Vec<f32> =
+            (0..48000).map(|i| 0.5 * (i as f32 * 0.001).sin() + 0.1 / GAIN).collect();
+        let mut output = vec![0; input.len()];
+        Dither::new(1, shaping).process(&input, &mut output);
+        input.iter().zip(&output).map(|(&x, &y)| y as f32 - x * GAIN).collect()
+    }
+
+    /// Power of the error, and of its averages over blocks of 32 samples.
+    fn power(errors: &[f32]) -> (f32, f32) {
+        let total = errors.iter().map(|e| e * e).sum::<f32>() / errors.len() as f32;
+        let blocks: Vec<f32> = errors.chunks(32).map(|b| b.iter().sum::<f32>() / 32.0).collect();
+        (total, blocks.iter().map(|e| e * e).sum::<f32>() / blocks.len() as f32)
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(NoiseShaping::for_rate(48000), NoiseShaping::Rate48000);
+        assert_eq!(NoiseShaping::for_rate(44100), NoiseShaping::Rate44100);
+        assert_eq!(NoiseShaping::for_rate(16000), NoiseShaping::Lowpass);
+    }
+
+    #[test]
+    fn noise_shaping() {
+        for &shaping in &[NoiseShaping::Rate48000, NoiseShaping::Rate44100, NoiseShaping::Lowpass] {
+            let errors = errors(shaping);
+            // No DC offset, and the noise moved away from the low frequencies: white noise
+            // would keep 1/32 of its power in the averages.
+            let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+            assert!(mean.abs() < 0.01, "{:?}: {}", shaping, mean);
+            let (total, low) = power(&errors);
+            assert!(total < 10.0 && low < total / 200.0, "{:?}: {} {}", shaping, total, low);
+        }
+    }
+
+    #[test]
+    fn clipping() {
+        let mut output = [0; 4];
+        Dither::new(2, NoiseShaping::Rate48000).process(&[2.0, -2.0, 1.0, -1.0], &mut output);
+        assert_eq!(output[..2], [32767, -32768]);
+        assert!(output[2] > 32700 && output[3] < -32700);
+    }
+
+    #[test]
+    fn silence() {
+        let mut dither = Dither::new(2, NoiseShaping::Rate48000);
+        let mut output = vec![0; 2 * 960];
+        dither.process(&vec![0.25; 2 * 960], &mut output);
+        dither.process(&vec![0.0; 2 * 960], &mut output);
+        // The dither and the filter are muted once the input has been silent for a while.
+        dither.process(&vec![0.0; 2 * 960], &mut output);
+        assert!(output.iter().all(|&s| s == 0));
+        // But not a single silent channel.
+        let input: Vec<f32> = (0..2 * 960).map(|i| if i % 2 == 0 { 0.0 } else { 0.25 }).collect();
+        dither.process(&input, &mut output);
+        assert!(output.iter().step_by(2).any(|&s| s != 0));
+    }
+}
@@ -0,0 +1,252 @@
+use header::OpusHead;
+use oggopus::{self, OggOpusReader, OggOpusWriter};
+use ogg;
+use packet;
+use std;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Next packet of the current link of `reader`, as `next_raw_packet`, checking that it starts
+/// at `*end`, the end of the previous one. The copy counts its granule positions from the
+/// packets written, which a gap in the input, after lost pages, would shift.
+fn next_packet<R: Read>(reader: &mut OggOpusReader<R>,
+                        end: &mut Option<i64>)
+                        -> io::Result<Option<(ogg::Packet, i64)>> {
+    let (p, granule) = match reader.next_raw_packet()? {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+    if end.is_some_and(|end| end != granule) {
+        return Err(invalid_input("gap in the stream"));
+    }
+    *end = Some(granule + packet::duration(&p.data).unwrap_or(0) as i64);
+    Ok(Some((p, granule)))
+}
+
+/// Copy the samples `[start, end)` of the current link of `reader`, counted from the first one
+/// after the pre-skip, into a new stream written to `output`, without decoding them.
+///
+/// The copy starts with the last packet at least `PRE_ROLL` samples before `start`, or with
+/// the first one, for the decoder to converge, and the pre-skip is set to discard the samples
+/// up to `start`. The end is trimmed with the granule position of the last page, at `end` or at
+/// the end of the link, whichever comes first. Streams with lost packets are rejected.
+fn copy_link<R, W>(reader: &mut OggOpusReader<R>,
+                   output: W,
+                   serial: u32,
+                   start: u64,
+                   end: Option<u64>)
+                   -> io::Result<W>
+    where R: Read,
+          W: Write
+{
+    // Packets before `start`, from the one to start the copy with.
+    let mut window: VecDeque<(ogg::Packet, i64)> = VecDeque::new();
+    let mut next = None;
+    // Granule position of the first sample after the pre-skip.
+    let mut origin = None;
+    let mut packet_end = None;
+    while let Some((p, granule)) = next_packet(reader, &mut packet_end)? {
+        let origin = *origin.get_or_insert(granule + reader.head.pre_skip as i64);
+        if granule >= origin + start as i64 {
+            next = Some((p, granule));
+            break;
+        }
+        window.push_back((p, granule));
+        let pre_roll = origin + start as i64 - oggopus::PRE_ROLL;
+        while window.len() > 1 && window[1].1 <= pre_roll {
+            window.pop_front();
+        }
+    }
+    let first = match window.front().or(next.as_ref()) {
+        Some(&(_, granule)) => granule,
+        None => return Err(invalid_input("empty stream")),
+    };
+    let origin = origin.unwrap_or(0);
+    let target = origin + start as i64;
+    let pre_skip = target - first;
+    if pre_skip > u16::MAX as i64 {
+        return Err(invalid_input("start beyond the end of the stream"));
+    }
+    let mut end = end.map_or(i64::MAX, |end| origin + end as i64);
+    if end <= target {
+        return Err(invalid_input("empty range"));
+    }
+
+    let head = OpusHead {
+        pre_skip: pre_skip as usize,
+        ..reader.head.clone()
+    };
+    let mut writer = OggOpusWriter::new(output, serial, &head, &reader.tags)?;
+    let mut packets = window.into_iter().chain(next);
+    let mut last = first;
+    loop {
+        let (p, granule) = match packets.next() {
+            Some(packet) => packet,
+            None => {
+                match next_packet(reader, &mut packet_end)? {
+                    Some(packet) => packet,
+                    None => break,
+                }
+            }
+        };
+        if granule >= end {
+            break;
+        }
+        writer.write_packet(&p.data)?;
+        last = first + writer.granule_position();
+        if p.eos {
+            // The end of the stream, as trimmed by the granule position of its last page.
+            if let Some(granule_position) = p.granule_position {
+                end = std::cmp::min(end, granule_position);
+            }
+        }
+    }
+    let end = std::cmp::min(end, last);
+    if end <= target {
+        return Err(invalid_input("start beyond the end of the stream"));
+    }
+    writer.finish((last - end) as usize)
+}
+
+/// Cut the samples `[start, end)` of the first link of an Ogg Opus file, at 48 kHz from the
+/// first one after the pre-skip, into a new file, without decoding and re-encoding.
+///
+/// The cut is at packet boundaries, with enough packets before `start` for the decoder to
+/// converge, and the pre-skip and the granule position of the last page set for the result to
+/// play exactly the requested samples.
+pub fn cut<R, W>(input: R, output: W, start: u64, end: Option<u64>) -> io::Result<W>
+    where R: Read,
+          W: Write
+{
+    let mut reader = OggOpusReader::new(input)?;
+    let serial = reader.serial();
+    copy_link(&mut reader, output, serial, start, end)
+}
+
+/// Whether streams with headers `a` and `b` can follow each other: same channels, with the
+/// same mapping.
+fn compatible(a: &OpusHead, b: &OpusHead) -> bool {
+    a.channels == b.channels && a.mapping_family == b.mapping_family && a.streams == b.streams &&
+    a.coupled_streams == b.coupled_streams && a.mapping == b.mapping
+}
+
+/// Concatenate Ogg Opus files, without decoding and re-encoding.
+///
+/// Each link of the inputs becomes a link of a chained file, with its own pre-skip and end
+/// trimming, which a single stream can only have at its start and end, for gapless playback of
+/// the exact samples of each. The streams must have the same channels and mapping.
+pub fn concat<R, W>(inputs: Vec<R>, mut output: W) -> io::Result<W>
+    where R: Read,
+          W: Write
+{
+    let mut first: Option<OpusHead> = None;
+    let mut serial = None;
+    for input in inputs {
+        let mut reader = OggOpusReader::new(input)?;
+        loop {
+            match first {
+                Some(ref head) if !compatible(head, &reader.head) => {
+                    return Err(invalid_input("incompatible streams"));
+                }
+                Some(_) => {}
+                None => first = Some(reader.head.clone()),
+            }
+            // Each link needs a serial number of its own.
+            let s = serial.map_or(reader.serial(), |s: u32| s.wrapping_add(1));
+            serial = Some(s);
+            output = copy_link(&mut reader, output, s, 0, None)?;
+            if !reader.next_link()? {
+                break;
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use header::OpusTags;
+    use oggopus::tests::{decode_all, drop_page, head, stream};
+
+    fn decode(data: &[u8]) -> Vec<f32> {
+        decode_all(&mut OggOpusReader::new(data).unwrap())
+    }
+
+    #[test]
+    fn cut_decode() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 100, 500);
+        let complete = decode(&data);
+        assert_eq!(complete.len(), 2 * (100 * 960 - 312 - 500));
+
+        let output = cut(&data[..], vec![], 10000, Some(30000)).unwrap();
+        let mut reader = OggOpusReader::new(&output[..]).unwrap();
+        // The copy starts with the packet at least the pre-roll before the start.
+        assert_eq!(reader.head.pre_skip, 10000 + 312 - 6 * 960);
+        let pcm = decode_all(&mut reader);
+        assert_eq!(pcm.len(), 2 * 20000);
+        // The decoder converges, from small differences after the pre-roll.
+        let error = |range: std::ops::Range<usize>| {
+            pcm[2 * range.start..2 * range.end]
+                .iter()
+                .zip(&complete[2 * (10000 + range.start)..2 * (10000 + range.end)])
+                .map(|(x, y)| (x - y).abs())
+                .fold(0.0, f32::max)
+        };
+        assert!(error(0..20000) < 0.02);
+        assert!(error(10000..20000) < 1e-4);
+
+        // Up to the end of the stream, and from its start.
+        assert_eq!(decode(&cut(&data[..], vec![], 90000, None).unwrap()).len(),
+                   2 * (100 * 960 - 312 - 500 - 90000));
+        assert_eq!(decode(&cut(&data[..], vec![], 0, Some(1000)).unwrap()),
+                   complete[..2 * 1000]);
+    }
+
+    #[test]
+    fn bad_ranges() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 10, 0);
+        // The stream has 10 * 960 - 312 samples.
+        for &(start, end) in &[(100, Some(100)), (200, Some(100)), (9288, None)] {
+            let err = cut(&data[..], vec![], start, end).err().map(|err| err.kind());
+            assert_eq!(err, Some(io::ErrorKind::InvalidInput));
+        }
+        assert!(cut(&data[..], vec![], 5000, Some(20000)).is_ok());
+    }
+
+    #[test]
+    fn gap() {
+        let data = drop_page(&stream(&head(312, 0), &OpusTags::default(), 100, 0), 3);
+        let err = cut(&data[..], vec![], 0, None).err().map(|err| err.kind());
+        assert_eq!(err, Some(io::ErrorKind::InvalidInput));
+        // Before the lost page, the cut is fine.
+        assert!(cut(&data[..], vec![], 0, Some(10000)).is_ok());
+    }
+
+    #[test]
+    fn concat_cuts() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 100, 500);
+        let a = cut(&data[..], vec![], 1000, Some(25000)).unwrap();
+        let b = cut(&data[..], vec![], 40000, None).unwrap();
+        let output = concat(vec![&a[..], &b[..], &data[..]], vec![]).unwrap();
+        let mut reader = OggOpusReader::new(&output[..]).unwrap();
+        let pcm = decode_all(&mut reader);
+        assert_eq!(reader.link(), 2);
+        let mut expected = decode(&a);
+        expected.extend(decode(&b));
+        expected.extend(decode(&data));
+        assert_eq!(pcm, expected);
+
+        let mut mono = head(312, 0);
+        mono.channels = 1;
+        mono.coupled_streams = 0;
+        mono.mapping = vec![0];
+        let c = stream(&mono, &OpusTags::default(), 10, 0);
+        let err = concat(vec![&a[..], &c[..]], vec![]).err().map(|err| err.kind());
+        assert_eq!(err, Some(io::ErrorKind::InvalidInput));
+    }
+}
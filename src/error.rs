@@ -0,0 +1,46 @@
+use std;
+
+/// Errors reported by the decoder, mirroring the libopus error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// One or more invalid/out of range arguments.
+    BadArg,
+    /// Not enough bytes allocated in the buffer.
+    BufferTooSmall,
+    /// The compressed data passed is corrupted.
+    InvalidPacket,
+    /// Invalid/unsupported request number.
+    Unimplemented,
+}
+
+impl Error {
+    /// The libopus error code returned through the C API.
+    pub fn code(&self) -> i32 {
+        match *self {
+            Error::BadArg => -1,
+            Error::BufferTooSmall => -2,
+            Error::InvalidPacket => -4,
+            Error::Unimplemented => -5,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match *self {
+            Error::BadArg => "invalid argument",
+            Error::BufferTooSmall => "buffer too small",
+            Error::InvalidPacket => "corrupted stream",
+            Error::Unimplemented => "request not implemented",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
@@ -0,0 +1,424 @@
+use error::Error;
+use multistream::OpusMSDecoder;
+use projection::OpusProjectionDecoder;
+use std;
+
+/// 5.1. Identification Header (RFC 7845)
+///
+///      0                   1                   2                   3
+///      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |      'O'      |      'p'      |      'u'      |      's'      |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |      'H'      |      'e'      |      'a'      |      'd'      |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |  Version = 1  | Channel Count |           Pre-skip            |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                     Input Sample Rate (Hz)                    |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |   Output Gain (Q7.8 in dB)    | Mapping Family|               |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+               :
+///     |                                                               |
+///     :               Optional Channel Mapping Table...               :
+///     |                                                               |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: usize,
+    /// Number of samples at 48 kHz to discard from the decoder output when starting playback.
+    pub pre_skip: usize,
+    /// Sample rate of the original input, for information only.
+    pub input_sample_rate: u32,
+    /// Gain to apply to the output, in Q7.8 dB.
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    pub streams: usize,
+    pub coupled_streams: usize,
+    /// Decoded channel of each output channel, for all the families but 3.
+    pub mapping: Vec<u8>,
+    /// Demixing matrix, for family 3.
+    pub demixing_matrix: Vec<u8>,
+}
+
+impl OpusHead {
+    pub fn parse(data: &[u8]) -> Result<OpusHead, Error> {
+        if data.len() < 19 || &data[..8] != b"OpusHead" {
+            return Err(Error::InvalidPacket);
+        }
+        // The major version is in the upper four bits; only version 0 is understood.
+        let version = data[8];
+        if version >> 4 != 0 {
+            return Err(Error::Unimplemented);
+        }
+        let channels = data[9] as usize;
+        if channels == 0 {
+            return Err(Error::InvalidPacket);
+        }
+        let mut head = OpusHead {
+            version,
+            channels,
+            pre_skip: u16::from_le_bytes([data[10], data[11]]) as usize,
+            input_sample_rate: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            output_gain: i16::from_le_bytes([data[16], data[17]]),
+            mapping_family: data[18],
+            streams: 1,
+            coupled_streams: channels - 1,
+            mapping: vec![],
+            demixing_matrix: vec![],
+        };
+        if head.mapping_family == 0 {
+            if channels > 2 {
+                return Err(Error::InvalidPacket);
+            }
+            head.mapping = [0, 1][..channels].to_vec();
+            return Ok(head);
+        }
+
+        if data.len() < 21 {
+            return Err(Error::InvalidPacket);
+        }
+        head.streams = data[19] as usize;
+        head.coupled_streams = data[20] as usize;
+        if head.streams == 0 || head.coupled_streams > head.streams {
+            return Err(Error::InvalidPacket);
+        }
+        let table = &data[21..];
+        if head.mapping_family == 3 {
+            let size = 2 * channels * (head.streams + head.coupled_streams);
+            if table.len() < size {
+                return Err(Error::InvalidPacket);
+            }
+            head.demixing_matrix = table[..size].to_vec();
+        } else {
+            if table.len() < channels {
+                return Err(Error::InvalidPacket);
+            }
+            head.mapping = table[..channels].to_vec();
+            let decoded_channels = head.streams + head.coupled_streams;
+            if head.mapping.iter().any(|&m| m != 255 && m as usize >= decoded_channels) {
+                return Err(Error::InvalidPacket);
+            }
+        }
+        Ok(head)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = b"OpusHead".to_vec();
+        data.push(self.version);
+        data.push(self.channels as u8);
+        data.extend_from_slice(&(self.pre_skip as u16).to_le_bytes());
+        data.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        data.extend_from_slice(&self.output_gain.to_le_bytes());
+        data.push(self.mapping_family);
+        if self.mapping_family != 0 {
+            data.push(self.streams as u8);
+            data.push(self.coupled_streams as u8);
+            if self.mapping_family == 3 {
+                data.extend_from_slice(&self.demixing_matrix);
+            } else {
+                data.extend_from_slice(&self.mapping);
+            }
+        }
+        data
+    }
+
+    /// Output gain as a linear factor.
+    pub fn gain(&self) -> f32 {
+        10f32.powf(self.output_gain as f32 / (20.0 * 256.0))
+    }
+
+    /// Decoder for the streams described by the header.
+    pub fn decoder(&self) -> Result<Decoder, Error> {
+        match self.mapping_family {
+            0 | 1 | 255 => {
+                OpusMSDecoder::new(self.channels, self.streams, self.coupled_streams, &self.mapping)
+                    .map(Decoder::Multistream)
+            }
+            2 => {
+                OpusProjectionDecoder::new(self.channels,
+                                           self.streams,
+                                           self.coupled_streams,
+                                           &self.mapping)
+                    .map(Decoder::Projection)
+            }
+            3 => {
+                OpusProjectionDecoder::with_demixing_matrix(self.channels,
+                                                            self.streams,
+                                                            self.coupled_streams,
+                                                            &self.demixing_matrix)
+                    .map(Decoder::Projection)
+            }
+            _ => Err(Error::Unimplemented),
+        }
+    }
+}
+
+/// Decoder built from an identification header.
+pub enum Decoder {
+    Multistream(OpusMSDecoder),
+    Projection(OpusProjectionDecoder),
+}
+
+impl Decoder {
+    /// Number of interleaved channels in the output of `decode`.
+    pub fn channels(&self) -> usize {
+        match *self {
+            Decoder::Multistream(ref st) => st.channels,
+            Decoder::Projection(ref st) => st.output_channels(),
+        }
+    }
+
+    /// Decode a packet into `pcm`, or conceal a lost packet when `data` is `None`. Returns the
+    /// number of samples per channel.
+    pub fn decode(&mut self, data: Option<&[u8]>, pcm: &mut [f32]) -> Result<usize, Error> {
+        match *self {
+            Decoder::Multistream(ref mut st) => st.decode(data, pcm),
+            Decoder::Projection(ref mut st) => st.decode(data, pcm),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match *self {
+            Decoder::Multistream(ref mut st) => st.reset(),
+            Decoder::Projection(ref mut st) => st.reset(),
+        }
+    }
+
+    /// Gain applied to the output, in Q7.8 dB.
+    pub fn set_gain(&mut self, gain: i16) {
+        match *self {
+            Decoder::Multistream(ref mut st) => st.set_gain(gain),
+            Decoder::Projection(ref mut st) => st.set_gain(gain),
+        }
+    }
+}
+
+/// 5.2. Comment Header (RFC 7845)
+///
+/// A vendor string and a list of user comments of the form "NAME=value", with the field names
+/// compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpusTags {
+    pub vendor: String,
+    pub comments: Vec<String>,
+}
+
+impl OpusTags {
+    pub fn parse(data: &[u8]) -> Result<OpusTags, Error> {
+        if data.len() < 8 || &data[..8] != b"OpusTags" {
+            return Err(Error::InvalidPacket);
+        }
+        let mut pos = 8;
+        let vendor = read_string(data, &mut pos)?;
+        let count = read_u32(data, &mut pos)? as usize;
+        // Each comment takes at least its 4-byte length.
+        if count > (data.len() - pos) / 4 {
+            return Err(Error::InvalidPacket);
+        }
+        let mut comments = Vec::with_capacity(count);
+        for _ in 0..count {
+            comments.push(read_string(data, &mut pos)?);
+        }
+        Ok(OpusTags { vendor, comments })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = b"OpusTags".to_vec();
+        write_string(&mut data, &self.vendor);
+        data.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            write_string(&mut data, comment);
+        }
+        data
+    }
+
+    /// Values of the comments named `name`.
+    pub fn get<'b>(&'b self, name: &'b str) -> impl Iterator<Item = &'b str> + 'b {
+        self.comments.iter().filter_map(move |comment| {
+            let mut split = comment.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(key), Some(value)) if key.eq_ignore_ascii_case(name) => Some(value),
+                _ => None,
+            }
+        })
+    }
+
+    /// Replace the comments named `name` by one with `value`.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.comments.retain(|comment| {
+            !comment.split('=').next().is_some_and(|key| key.eq_ignore_ascii_case(name))
+        });
+        self.comments.push(format!("{}={}", name, value));
+    }
+
+    /// R128_TRACK_GAIN, in Q7.8 dB, to apply on top of the output gain to normalise the track to
+    /// a loudness of -23 LUFS.
+    pub fn track_gain(&self) -> Option<i16> {
+        self.gain("R128_TRACK_GAIN")
+    }
+
+    pub fn set_track_gain(&mut self, gain: i16) {
+        self.set("R128_TRACK_GAIN", &gain.to_string());
+    }
+
+    /// R128_ALBUM_GAIN, in Q7.8 dB, to apply on top of the output gain to normalise the album to
+    /// a loudness of -23 LUFS.
+    pub fn album_gain(&self) -> Option<i16> {
+        self.gain("R128_ALBUM_GAIN")
+    }
+
+    pub fn set_album_gain(&mut self, gain: i16) {
+        self.set("R128_ALBUM_GAIN", &gain.to_string());
+    }
+
+    /// The gains are a signed decimal integer, with no leading "+" nor leading zeros.
+    fn gain(&self, name: &str) -> Option<i16> {
+        self.get(name).next().and_then(|value| {
+            let digits = value.strip_prefix('-').unwrap_or(value);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            value.parse().ok()
+        })
+    }
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    if data.len() < *pos + 4 {
+        return Err(Error::InvalidPacket);
+    }
+    let p = *pos;
+    *pos += 4;
+    Ok(u32::from_le_bytes([data[p], data[p + 1], data[p + 2], data[p + 3]]))
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String, Error> {
+    let len = read_u32(data, pos)? as usize;
+    if data.len() - *pos < len {
+        return Err(Error::InvalidPacket);
+    }
+    let string = String::from_utf8_lossy(&data[*pos..*pos + len]).into_owned();
+    *pos += len;
+    Ok(string)
+}
+
+fn write_string(data: &mut Vec<u8>, string: &str) {
+    data.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    data.extend_from_slice(string.as_bytes());
+}
+
+/// Whether an Ogg packet is the identification header beginning an Opus stream.
+pub fn is_opus_head(data: &[u8]) -> bool {
+    data.starts_with(b"OpusHead")
+}
+
+impl std::fmt::Display for OpusTags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Encoded with {}", self.vendor)?;
+        for comment in &self.comments {
+            writeln!(f, "{}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_round_trip() {
+        // Stereo, 312 samples of pre-skip, 44.1 kHz input, -1 dB of output gain.
+        let data = b"OpusHead\x01\x02\x38\x01\x44\xac\x00\x00\x00\xff\x00";
+        let head = OpusHead::parse(data).unwrap();
+        assert_eq!((head.version, head.channels, head.pre_skip), (1, 2, 312));
+        assert_eq!((head.input_sample_rate, head.output_gain), (44100, -256));
+        assert_eq!((head.streams, head.coupled_streams, &head.mapping[..]), (1, 1, &[0, 1][..]));
+        assert_eq!(head.to_bytes(), &data[..]);
+        assert!((head.gain() - 10f32.powf(-1.0 / 20.0)).abs() < 1e-6);
+        assert!(is_opus_head(data));
+
+        // 5.1 surround.
+        let mut data = b"OpusHead\x01\x06\x00\x00\x80\xbb\x00\x00\x00\x00\x01\x04\x02".to_vec();
+        data.extend_from_slice(&[0, 4, 1, 2, 3, 5]);
+        let head = OpusHead::parse(&data).unwrap();
+        assert_eq!((head.streams, head.coupled_streams), (4, 2));
+        assert_eq!(head.mapping, [0, 4, 1, 2, 3, 5]);
+        assert_eq!(head.to_bytes(), data);
+        assert_eq!(head.decoder().unwrap().channels(), 6);
+
+        // First-order ambisonics with a demixing matrix.
+        let mut data = b"OpusHead\x01\x04\x00\x00\x80\xbb\x00\x00\x00\x00\x03\x02\x02".to_vec();
+        data.extend((0..32).map(|i| i as u8));
+        let head = OpusHead::parse(&data).unwrap();
+        assert_eq!(head.demixing_matrix.len(), 32);
+        assert_eq!(head.to_bytes(), data);
+        assert_eq!(head.decoder().unwrap().channels(), 4);
+    }
+
+    #[test]
+    fn invalid_heads() {
+        let head = |data: &[u8]| OpusHead::parse(data).err();
+        assert_eq!(head(b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00"),
+                   Some(Error::InvalidPacket));
+        assert_eq!(head(b"OpusHeat\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x00"),
+                   Some(Error::InvalidPacket));
+        // A new major version.
+        assert_eq!(head(b"OpusHead\x10\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x00"),
+                   Some(Error::Unimplemented));
+        // No channels, or 3 in family 0.
+        assert_eq!(head(b"OpusHead\x01\x00\x00\x00\x80\xbb\x00\x00\x00\x00\x00"),
+                   Some(Error::InvalidPacket));
+        assert_eq!(head(b"OpusHead\x01\x03\x00\x00\x80\xbb\x00\x00\x00\x00\x00"),
+                   Some(Error::InvalidPacket));
+        // A mapping to a missing decoded channel, and a short mapping table.
+        assert_eq!(head(b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x01\x01\x01\x00\x02"),
+                   Some(Error::InvalidPacket));
+        assert_eq!(head(b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x01\x01\x01\x00"),
+                   Some(Error::InvalidPacket));
+        // More coupled streams than streams.
+        assert_eq!(head(b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x01\x01\x02\x00\x01"),
+                   Some(Error::InvalidPacket));
+        // An unknown family parses, but has no decoder.
+        let data = b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x04\x01\x01\x00\x01";
+        let head = OpusHead::parse(data).unwrap();
+        assert_eq!(head.decoder().err(), Some(Error::Unimplemented));
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let mut tags = OpusTags {
+            vendor: "libopus 1.3".to_string(),
+            comments: vec!["TITLE=Song".to_string(), "artist=Someone".to_string()],
+        };
+        let data = tags.to_bytes();
+        assert_eq!(&data[..8], b"OpusTags");
+        assert_eq!(OpusTags::parse(&data), Ok(tags.clone()));
+        assert_eq!(tags.get("ARTIST").collect::<Vec<_>>(), ["Someone"]);
+        assert_eq!(tags.to_string(), "Encoded with libopus 1.3\nTITLE=Song\nartist=Someone\n");
+
+        tags.set("Title", "Other");
+        assert_eq!(tags.comments, ["artist=Someone", "Title=Other"]);
+        assert_eq!(OpusTags::parse(&tags.to_bytes()), Ok(tags.clone()));
+
+        // Truncated, or with more comments than bytes.
+        assert!(OpusTags::parse(&data[..data.len() - 1]).is_err());
+        let mut data = b"OpusTags\x00\x00\x00\x00".to_vec();
+        data.extend_from_slice(&1000u32.to_le_bytes());
+        assert!(OpusTags::parse(&data).is_err());
+    }
+
+    #[test]
+    fn gains() {
+        let mut tags = OpusTags::default();
+        assert_eq!((tags.track_gain(), tags.album_gain()), (None, None));
+        tags.set_track_gain(-1234);
+        tags.set_album_gain(56);
+        assert_eq!((tags.track_gain(), tags.album_gain()), (Some(-1234), Some(56)));
+        for value in &["+5", "-", "", "1.5", "40000"] {
+            tags.set("R128_TRACK_GAIN", value);
+            assert_eq!(tags.track_gain(), None);
+        }
+    }
+}
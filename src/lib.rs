@@ -1,18 +1,38 @@
 mod anti_collapse;
 mod bands;
 mod bands_utils;
+pub mod compare;
 mod consts;
 mod cwrs;
 mod denormalise_bands;
+pub mod dither;
+pub mod edit;
 mod entdec;
+pub mod error;
+pub mod header;
 mod init;
 mod kiss_fft;
+pub mod loudness;
 mod mdct;
 mod mode;
+pub mod mp4;
+pub mod multistream;
+pub mod ogg;
+pub mod oggopus;
 mod opus_decoder;
+pub mod opus_demo;
+pub mod opusfile;
+pub mod packet;
+mod playback;
+mod plc;
+pub mod projection;
 mod quant_bands;
 mod rate;
+pub mod resample;
+pub mod rtp;
 mod utils;
 mod vq;
+pub mod wav;
+pub mod webm;
 pub use init::*;
 pub use opus_decoder::*;
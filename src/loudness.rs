@@ -0,0 +1,401 @@
+use error::Error;
+use header::OpusTags;
+use std;
+use std::collections::VecDeque;
+
+/// Target loudness of the R128 gains of OpusTags, in LUFS.
+pub const REFERENCE_LOUDNESS: f64 = -23.0;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Relative gates of the integrated loudness and of the loudness range, in LU.
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_GATE: f64 = -20.0;
+
+/// Taps per phase of the 4x oversampling filter of the true peak.
+const PEAK_TAPS: usize = 12;
+
+/// Second order IIR filter, in direct form I.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] -
+                self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The two stages of the K-weighting filter (ITU-R BS.1770): a high shelf modelling the
+/// acoustic effect of the head, and a high-pass filter (RLB weighting), at `rate`.
+fn k_weighting(rate: u32) -> (Biquad, Biquad) {
+    let pi = std::f64::consts::PI;
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (pi * f0 / rate as f64).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new([(vh + vb * k / q + k * k) / a0,
+                             2.0 * (k * k - vh) / a0,
+                             (vh - vb * k / q + k * k) / a0],
+                            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (pi * f0 / rate as f64).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new([1.0, -2.0, 1.0],
+                               [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    (shelf, highpass)
+}
+
+/// Weights of the channels, in the Vorbis channel order of Opus (RFC 7845, section 5.1.1.2):
+/// 1.41 (+1.5 dB) for the surround channels, and 0 for the LFE channel.
+fn channel_weights(channels: usize) -> Vec<f64> {
+    let s = 1.41;
+    match channels {
+        4 => vec![1.0, 1.0, s, s],
+        5 => vec![1.0, 1.0, 1.0, s, s],
+        6 => vec![1.0, 1.0, 1.0, s, s, 0.0],
+        7 => vec![1.0, 1.0, 1.0, s, s, s, 0.0],
+        8 => vec![1.0, 1.0, 1.0, s, s, s, s, 0.0],
+        _ => vec![1.0; channels],
+    }
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+/// Mean energy of the blocks above `threshold`.
+fn mean_energy<'a, I>(blocks: I, threshold: f64) -> Option<f64>
+    where I: Iterator<Item = &'a f64>
+{
+    let (sum, count) = blocks.filter(|&&e| e > threshold)
+        .fold((0.0, 0), |(sum, count), &e| (sum + e, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f64)
+    }
+}
+
+/// Loudness of the blocks above both the absolute gate and the relative gate, 10 LU below the
+/// loudness of the blocks above the absolute gate.
+fn gated_loudness<'a, I>(blocks: I) -> Option<f64>
+    where I: Iterator<Item = &'a f64> + Clone
+{
+    let absolute = mean_energy(blocks.clone(), energy(ABSOLUTE_GATE))?;
+    let relative = loudness(absolute) + RELATIVE_GATE;
+    mean_energy(blocks, energy(relative.max(ABSOLUTE_GATE))).map(loudness)
+}
+
+/// Gain in Q7.8 dB from `loudness` to the reference.
+fn gain_to_reference(loudness: f64) -> i16 {
+    let gain = ((REFERENCE_LOUDNESS - loudness) * 256.0).round();
+    gain.clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+/// 4x oversampling true-peak meter of a channel (ITU-R BS.1770, annex 2).
+#[derive(Clone)]
+struct TruePeak {
+    history: [f32; PEAK_TAPS],
+    peak: f32,
+}
+
+/// Interpolation filters of the phases 1/4, 2/4 and 3/4 between input samples: a sinc with a
+/// Hann window, over `PEAK_TAPS` samples.
+fn interpolation_filters() -> [[f32; PEAK_TAPS]; 3] {
+    let pi = std::f64::consts::PI;
+    let half = (PEAK_TAPS / 2) as f64;
+    let mut filters = [[0.0; PEAK_TAPS]; 3];
+    for (p, filter) in filters.iter_mut().enumerate() {
+        for (j, h) in filter.iter_mut().enumerate() {
+            let t = j as f64 - half + (p + 1) as f64 / 4.0;
+            let window = 0.5 + 0.5 * (pi * t / half).cos();
+            *h = ((pi * t).sin() / (pi * t) * window) as f32;
+        }
+    }
+    filters
+}
+
+/// Loudness meter of EBU R128 (EBU Tech 3341 and 3342) of interleaved samples.
+///
+/// The K-weighted mean square of each channel is accumulated over blocks of 100 ms. The
+/// momentary loudness is over the last 400 ms, and the short-term loudness over the last 3 s.
+/// The integrated loudness is over the 400 ms blocks, every 100 ms, with an absolute gate at
+/// -70 LUFS and a relative gate 10 LU below the loudness of the blocks above it. The loudness
+/// range is the spread between the 10th and 95th percentiles of the short-term loudness, every
+/// 100 ms, above -70 LUFS and 20 LU below their loudness.
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<(Biquad, Biquad)>,
+    /// Samples per channel of a 100 ms block.
+    block_size: usize,
+    /// Weighted sum of squares of the current block, and number of its samples per channel.
+    energy: f64,
+    samples: usize,
+    /// Mean energies of the last 30 blocks of 100 ms.
+    recent: VecDeque<f64>,
+    /// Energies of the 400 ms blocks.
+    momentary: Vec<f64>,
+    /// Energies of the 3 s blocks.
+    short_term: Vec<f64>,
+    interpolation: [[f32; PEAK_TAPS]; 3],
+    peaks: Vec<TruePeak>,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, rate: u32) -> Result<LoudnessMeter, Error> {
+        if channels == 0 || rate < 10 {
+            return Err(Error::BadArg);
+        }
+        Ok(LoudnessMeter {
+            channels,
+            weights: channel_weights(channels),
+            filters: vec![k_weighting(rate); channels],
+            block_size: (rate as usize + 5) / 10,
+            energy: 0.0,
+            samples: 0,
+            recent: VecDeque::with_capacity(30),
+            momentary: vec![],
+            short_term: vec![],
+            interpolation: interpolation_filters(),
+            peaks: vec![TruePeak {
+                            history: [0.0; PEAK_TAPS],
+                            peak: 0.0,
+                        };
+                        channels],
+        })
+    }
+
+    /// Measure interleaved `pcm`, such as the output of the decoder.
+    pub fn process(&mut self, pcm: &[f32]) {
+        for frame in pcm.chunks(self.channels) {
+            for (c, &x) in frame.iter().enumerate() {
+                let (ref mut shelf, ref mut highpass) = self.filters[c];
+                let y = highpass.process(shelf.process(x as f64));
+                self.energy += self.weights[c] * y * y;
+
+                let peak = &mut self.peaks[c];
+                peak.history.rotate_right(1);
+                peak.history[0] = x;
+                peak.peak = peak.peak.max(x.abs());
+                for filter in &self.interpolation {
+                    let y: f32 = filter.iter().zip(&peak.history).map(|(h, x)| h * x).sum();
+                    peak.peak = peak.peak.max(y.abs());
+                }
+            }
+            self.samples += 1;
+            if self.samples == self.block_size {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        if self.recent.len() == 30 {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(self.energy / self.samples as f64);
+        self.energy = 0.0;
+        self.samples = 0;
+        if self.recent.len() >= 4 {
+            self.momentary.push(self.recent.iter().rev().take(4).sum::<f64>() / 4.0);
+        }
+        if self.recent.len() == 30 {
+            self.short_term.push(self.recent.iter().sum::<f64>() / 30.0);
+        }
+    }
+
+    /// Loudness of the last `blocks` blocks of 100 ms, if there are as many.
+    fn recent_loudness(&self, blocks: usize) -> Option<f64> {
+        if self.recent.len() < blocks {
+            return None;
+        }
+        Some(loudness(self.recent.iter().rev().take(blocks).sum::<f64>() / blocks as f64))
+    }
+
+    /// Momentary loudness, over the last 400 ms, in LUFS.
+    pub fn momentary(&self) -> Option<f64> {
+        self.recent_loudness(4)
+    }
+
+    /// Short-term loudness, over the last 3 s, in LUFS.
+    pub fn short_term(&self) -> Option<f64> {
+        self.recent_loudness(30)
+    }
+
+    /// Gated integrated loudness of the whole input, in LUFS. `None` when all is below the
+    /// absolute gate.
+    pub fn integrated(&self) -> Option<f64> {
+        gated_loudness(self.momentary.iter())
+    }
+
+    /// Loudness range, in LU.
+    pub fn loudness_range(&self) -> Option<f64> {
+        let absolute = mean_energy(self.short_term.iter(), energy(ABSOLUTE_GATE))?;
+        let gate = loudness(absolute) + RANGE_GATE;
+        let mut gated: Vec<f64> = self.short_term
+            .iter()
+            .map(|&e| loudness(e))
+            .filter(|&l| l > ABSOLUTE_GATE && l > gate)
+            .collect();
+        gated.sort_by(f64::total_cmp);
+        let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+        Some(percentile(0.95) - percentile(0.10))
+    }
+
+    /// Maximum true peak of the channels, in dBTP.
+    pub fn true_peak(&self) -> f64 {
+        let peak = self.peaks.iter().fold(0.0f32, |max, p| max.max(p.peak));
+        20.0 * (peak as f64).log10()
+    }
+
+    /// R128_TRACK_GAIN for the input, when it is the output of the decoder with the output gain
+    /// applied.
+    pub fn track_gain(&self) -> Option<i16> {
+        self.integrated().map(gain_to_reference)
+    }
+
+    /// Set R128_TRACK_GAIN in `tags` to `track_gain`, which is returned. The tags are left
+    /// unchanged when there is no integrated loudness.
+    pub fn write_track_gain(&self, tags: &mut OpusTags) -> Option<i16> {
+        let gain = self.track_gain()?;
+        tags.set_track_gain(gain);
+        Some(gain)
+    }
+}
+
+/// R128_ALBUM_GAIN for the tracks measured by `meters`, from their integrated loudness
+/// together.
+pub fn album_gain(meters: &[LoudnessMeter]) -> Option<i16> {
+    gated_loudness(meters.iter().flat_map(|m| m.momentary.iter()))
+        .map(gain_to_reference)
+}
+
+/// Set R128_ALBUM_GAIN in the `tags` of a track to `album_gain`, as `write_track_gain`.
+pub fn write_album_gain(meters: &[LoudnessMeter], tags: &mut OpusTags) -> Option<i16> {
+    let gain = album_gain(meters)?;
+    tags.set_album_gain(gain);
+    Some(gain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Meter of a 1 kHz sine in every channel, at `levels` in dBFS for `seconds` each.
+    fn sine(channels: usize, rate: u32, levels: &[(f64, usize)]) -> LoudnessMeter {
+        let mut meter = LoudnessMeter::new(channels, rate).unwrap();
+        let mut t = 0;
+        for &(level, seconds) in levels {
+            let amplitude = 10f64.powf(level / 20.0);
+            let mut pcm = Vec::with_capacity(channels * rate as usize);
+            for _ in 0..seconds {
+                pcm.clear();
+                for _ in 0..rate {
+                    let phase = 2.0 * std::f64::consts::PI * 1000.0 * t as f64 / rate as f64;
+                    let x = (amplitude * phase.sin()) as f32;
+                    pcm.extend(std::iter::repeat_n(x, channels));
+                    t += 1;
+                }
+                meter.process(&pcm);
+            }
+        }
+        meter
+    }
+
+    #[test]
+    fn stereo_sine() {
+        // EBU Tech 3341, test 1: a stereo sine at -23 dBFS measures -23 LUFS.
+        let meter = sine(2, 48000, &[(-23.0, 4)]);
+        assert!((meter.integrated().unwrap() + 23.0).abs() < 0.1);
+        assert!((meter.momentary().unwrap() + 23.0).abs() < 0.1);
+        assert!((meter.short_term().unwrap() + 23.0).abs() < 0.1);
+        assert!(meter.track_gain().unwrap().abs() < 26);
+        assert!((meter.true_peak() + 23.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn silence() {
+        let mut meter = LoudnessMeter::new(1, 16000).unwrap();
+        meter.process(&[0.0; 16000 * 4]);
+        assert_eq!(meter.integrated(), None);
+        assert_eq!(meter.loudness_range(), None);
+        assert_eq!(meter.track_gain(), None);
+        assert!(meter.momentary().is_some());
+        assert!(meter.short_term().is_some());
+    }
+
+    #[test]
+    fn gates() {
+        // The relative gate falls below the absolute gate, which still excludes the second half.
+        let meter = sine(2, 16000, &[(-62.0, 4), (-71.0, 4)]);
+        assert!((meter.integrated().unwrap() + 62.0).abs() < 0.2);
+        // The second half passes the relative gate of -25.6 LUFS.
+        let meter = sine(2, 16000, &[(-23.0, 4), (-33.0, 4)]);
+        let expected = -23.0 + 10.0 * 0.55f64.log10();
+        assert!((meter.integrated().unwrap() - expected).abs() < 0.2);
+    }
+
+    #[test]
+    fn loudness_range() {
+        // EBU Tech 3342, test 1: -20 then -30 LUFS measures 10 LU.
+        let meter = sine(2, 16000, &[(-20.0, 10), (-30.0, 10)]);
+        assert!((meter.loudness_range().unwrap() - 10.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn album() {
+        let meters = [sine(2, 16000, &[(-23.0, 4)]), sine(2, 16000, &[(-33.0, 4)])];
+        let expected = (-10.0 * 0.55f64.log10() * 256.0) as i16;
+        assert!((album_gain(&meters).unwrap() - expected).abs() < 40);
+        assert_eq!(album_gain(&[]), None);
+    }
+
+    #[test]
+    fn write_gains() {
+        let meters = [sine(2, 16000, &[(-33.0, 4)]), sine(2, 16000, &[(-13.0, 4)])];
+        let mut tags = OpusTags {
+            vendor: "rumpus".to_string(),
+            comments: vec!["TITLE=sine".to_string(), "R128_TRACK_GAIN=0".to_string()],
+        };
+        let track = meters[0].write_track_gain(&mut tags).unwrap();
+        let album = write_album_gain(&meters, &mut tags).unwrap();
+        assert!((track - 10 * 256).abs() < 26);
+        let tags = OpusTags::parse(&tags.to_bytes()).unwrap();
+        assert_eq!((tags.track_gain(), tags.album_gain()), (Some(track), Some(album)));
+        assert_eq!(tags.comments.len(), 3);
+
+        // Silence leaves the tags alone.
+        let mut meter = LoudnessMeter::new(1, 16000).unwrap();
+        meter.process(&[0.0; 16000 * 4]);
+        let mut unchanged = tags.clone();
+        assert_eq!(meter.write_track_gain(&mut unchanged), None);
+        assert_eq!(unchanged, tags);
+    }
+}
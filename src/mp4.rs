@@ -0,0 +1,470 @@
+use header::OpusHead;
+use std::io::{self, Read, Seek, SeekFrom};
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn u16_be(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn u32_be(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn u64_be(data: &[u8]) -> u64 {
+    (u32_be(data) as u64) << 32 | u32_be(&data[4..]) as u64
+}
+
+/// Iterator over the boxes of a buffer, as (type, body).
+struct Boxes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<([u8; 4], &'a [u8])> {
+        if self.data.len() < 8 {
+            return None;
+        }
+        let kind = [self.data[4], self.data[5], self.data[6], self.data[7]];
+        let (header, size) = match u32_be(self.data) {
+            0 => (8, self.data.len() as u64),
+            1 if self.data.len() >= 16 => (16, u64_be(&self.data[8..])),
+            size => (8, size as u64),
+        };
+        if size < header as u64 || size > self.data.len() as u64 {
+            return None;
+        }
+        let body = &self.data[header..size as usize];
+        self.data = &self.data[size as usize..];
+        Some((kind, body))
+    }
+}
+
+fn boxes(data: &[u8]) -> Boxes<'_> {
+    Boxes { data }
+}
+
+/// First child box of type `kind`.
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|&(k, _)| &k == kind).map(|(_, body)| body)
+}
+
+/// Box at `path` under `data`.
+fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| child(data, kind))
+}
+
+/// Opus Specific Box (Encapsulation of Opus in ISO Base Media File Format, 4.3.2)
+///
+///     class OpusSpecificBox extends Box('dOps') {
+///         unsigned int(8) Version = 0;
+///         unsigned int(8) OutputChannelCount;
+///         unsigned int(16) PreSkip;
+///         unsigned int(32) InputSampleRate;
+///         signed int(16) OutputGain;
+///         unsigned int(8) ChannelMappingFamily;
+///         if (ChannelMappingFamily != 0) {
+///             ChannelMappingTable(OutputChannelCount);
+///         }
+///     }
+///
+/// The fields are those of the OpusHead header, but big-endian.
+pub fn parse_dops(data: &[u8]) -> io::Result<OpusHead> {
+    if data.len() < 11 || data[0] != 0 {
+        return Err(invalid_data("invalid dOps box"));
+    }
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(data[1]);
+    head.extend_from_slice(&u16_be(&data[2..]).to_le_bytes());
+    head.extend_from_slice(&u32_be(&data[4..]).to_le_bytes());
+    head.extend_from_slice(&u16_be(&data[8..]).to_le_bytes());
+    head.extend_from_slice(&data[10..]);
+    Ok(OpusHead::parse(&head)?)
+}
+
+/// A packet of the Opus track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mp4Packet {
+    pub data: Vec<u8>,
+    /// Decoding time of the packet, in samples at 48 kHz, including the pre-skip.
+    pub timestamp: u64,
+}
+
+struct Sample {
+    offset: u64,
+    size: u32,
+    timestamp: u64,
+}
+
+/// Reader of the first Opus track of an MP4 file.
+///
+/// The sample table of the track gives the location, size and time of each packet, which are
+/// read in decoding order. The edit list, when present, gives the pre-skip as the media time of
+/// its first edit, and the length of the audio as its duration.
+pub struct Mp4Reader<R> {
+    reader: R,
+    pub head: OpusHead,
+    /// Samples at 48 kHz to discard at the start of the decoded audio.
+    pub pre_skip: u64,
+    /// Number of samples at 48 kHz to play after the pre-skip, if known.
+    pub length: Option<u64>,
+    samples: Vec<Sample>,
+    next: usize,
+}
+
+impl<R: Read + Seek> Mp4Reader<R> {
+    pub fn new(mut reader: R) -> io::Result<Mp4Reader<R>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let moov = read_moov(&mut reader)?;
+        let movie_timescale = find(&moov, &[b"mvhd"]).and_then(|mvhd| match mvhd.first() {
+            Some(&1) if mvhd.len() >= 24 => Some(u32_be(&mvhd[20..])),
+            Some(&0) if mvhd.len() >= 16 => Some(u32_be(&mvhd[12..])),
+            _ => None,
+        });
+
+        for (kind, trak) in boxes(&moov) {
+            if &kind != b"trak" {
+                continue;
+            }
+            let stbl = match find(trak, &[b"mdia", b"minf", b"stbl"]) {
+                Some(stbl) => stbl,
+                None => continue,
+            };
+            let entry = match child(stbl, b"stsd").and_then(|stsd| stsd.get(8..)) {
+                Some(entries) => boxes(entries).next(),
+                None => None,
+            };
+            // AudioSampleEntry: 28 bytes of fields, then the child boxes.
+            let dops = match entry {
+                Some((kind, body)) if &kind == b"Opus" && body.len() >= 28 => {
+                    child(&body[28..], b"dOps")
+                }
+                _ => continue,
+            };
+            let head = parse_dops(dops.ok_or_else(|| invalid_data("missing dOps box"))?)?;
+            let timescale = find(trak, &[b"mdia", b"mdhd"]).and_then(|mdhd| match mdhd.first() {
+                Some(&1) if mdhd.len() >= 24 => Some(u32_be(&mdhd[20..])),
+                Some(&0) if mdhd.len() >= 16 => Some(u32_be(&mdhd[12..])),
+                _ => None,
+            });
+            let timescale = timescale.filter(|&t| t > 0).unwrap_or(48000) as u64;
+            let samples = sample_table(stbl, timescale, len)?;
+
+            let mut pre_skip = head.pre_skip as u64;
+            let mut length = None;
+            if let Some(elst) = find(trak, &[b"edts", b"elst"]) {
+                if let Some((duration, media_time)) = first_edit(elst) {
+                    pre_skip = media_time * 48000 / timescale;
+                    if let Some(movie_timescale) = movie_timescale.filter(|&t| t > 0) {
+                        length = Some(duration * 48000 / movie_timescale as u64);
+                    }
+                }
+            }
+            return Ok(Mp4Reader {
+                reader,
+                head,
+                pre_skip,
+                length,
+                samples,
+                next: 0,
+            });
+        }
+        Err(invalid_data("no Opus track"))
+    }
+
+    /// Read the next packet. Returns `None` at the end of the track.
+    pub fn next_packet(&mut self) -> io::Result<Option<Mp4Packet>> {
+        let sample = match self.samples.get(self.next) {
+            Some(sample) => sample,
+            None => return Ok(None),
+        };
+        self.next += 1;
+        self.reader.seek(SeekFrom::Start(sample.offset))?;
+        let mut data = vec![0; sample.size as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Mp4Packet {
+            data,
+            timestamp: sample.timestamp,
+        }))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Number of packets in the track.
+    pub fn packets(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Continue reading at the last packet starting at or before `timestamp`, in samples at
+    /// 48 kHz including the pre-skip.
+    pub fn seek(&mut self, timestamp: u64) {
+        self.next = match self.samples.binary_search_by_key(&timestamp, |s| s.timestamp) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        };
+    }
+}
+
+/// Read the top-level boxes up to the movie box, and return its body.
+fn read_moov<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header[..8])?;
+        let (header_size, size) = match u32_be(&header) {
+            1 => {
+                reader.read_exact(&mut header[8..])?;
+                (16, u64_be(&header[8..]))
+            }
+            0 => {
+                let end = reader.seek(SeekFrom::End(0))?;
+                reader.seek(SeekFrom::Start(pos + 8))?;
+                (8, end - pos)
+            }
+            size => (8, size as u64),
+        };
+        if size < header_size {
+            return Err(invalid_data("invalid box size"));
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![];
+            reader.take(size - header_size).read_to_end(&mut moov)?;
+            return Ok(moov);
+        }
+        pos = reader.seek(SeekFrom::Start(pos + size))?;
+    }
+}
+
+/// Segment duration, in the movie timescale, and media time of the first edit playing media.
+fn first_edit(elst: &[u8]) -> Option<(u64, u64)> {
+    let version = *elst.first()?;
+    let count = u32_be(elst.get(4..8)?) as usize;
+    let entry_size = if version == 1 { 20 } else { 12 };
+    (0..count).find_map(|i| {
+        let entry = elst.get(8 + i * entry_size..8 + (i + 1) * entry_size)?;
+        let (duration, media_time) = if version == 1 {
+            (u64_be(entry), u64_be(&entry[8..]) as i64)
+        } else {
+            (u32_be(entry) as u64, u32_be(&entry[4..]) as i32 as i64)
+        };
+        // A media time of -1 is an empty edit.
+        if media_time < 0 {
+            None
+        } else {
+            Some((duration, media_time as u64))
+        }
+    })
+}
+
+/// Location, size and time of each sample, from the sample size (stsz), chunk offset
+/// (stco/co64), sample-to-chunk (stsc) and decoding time-to-sample (stts) boxes. The samples
+/// are bounded by the size of the stsz box, or with a constant size, by the file size `len`.
+fn sample_table(stbl: &[u8], timescale: u64, len: u64) -> io::Result<Vec<Sample>> {
+    let truncated = || invalid_data("truncated sample table");
+    let entries = |kind: &[u8; 4], size: usize| -> io::Result<Vec<&[u8]>> {
+        let body = child(stbl, kind).ok_or_else(|| invalid_data("missing sample table box"))?;
+        let count = u32_be(body.get(4..8).ok_or_else(truncated)?) as usize;
+        let data = body.get(8..8 + count * size).ok_or_else(truncated)?;
+        Ok(data.chunks(size).collect())
+    };
+
+    let stsz = child(stbl, b"stsz").ok_or_else(|| invalid_data("missing stsz box"))?;
+    let sample_size = u32_be(stsz.get(4..8).ok_or_else(truncated)?);
+    let count = u32_be(stsz.get(8..12).ok_or_else(truncated)?) as usize;
+    let sizes: Vec<u32> = if sample_size != 0 {
+        if count as u64 * sample_size as u64 > len {
+            return Err(truncated());
+        }
+        vec![sample_size; count]
+    } else {
+        stsz.get(12..12 + 4 * count).ok_or_else(truncated)?.chunks(4).map(u32_be).collect()
+    };
+
+    let chunks: Vec<u64> = if child(stbl, b"co64").is_some() {
+        entries(b"co64", 8)?.into_iter().map(u64_be).collect()
+    } else {
+        entries(b"stco", 4)?.into_iter().map(|e| u32_be(e) as u64).collect()
+    };
+
+    let stsc = entries(b"stsc", 12)?;
+    let mut samples = Vec::with_capacity(count);
+    let mut sizes = sizes.into_iter();
+    for (i, entry) in stsc.iter().enumerate() {
+        let first = u32_be(entry) as usize;
+        let per_chunk = u32_be(&entry[4..]);
+        let last = stsc.get(i + 1).map_or(chunks.len() + 1, |next| u32_be(next) as usize);
+        for chunk in first..last {
+            let mut offset = *chunks.get(chunk.wrapping_sub(1)).ok_or_else(truncated)?;
+            for _ in 0..per_chunk {
+                let size = sizes.next().ok_or_else(truncated)?;
+                samples.push(Sample {
+                    offset,
+                    size,
+                    timestamp: 0,
+                });
+                offset += size as u64;
+            }
+        }
+    }
+
+    let mut timestamp = 0;
+    let mut sample = samples.iter_mut();
+    for entry in entries(b"stts", 8)? {
+        for _ in 0..u32_be(entry) {
+            match sample.next() {
+                Some(sample) => sample.timestamp = timestamp * 48000 / timescale,
+                None => break,
+            }
+            timestamp += u32_be(&entry[4..]) as u64;
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn mp4box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = (8 + body.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// A full box body of version 0: the version and flags, then `u32` fields.
+    fn fields(values: &[u32]) -> Vec<u8> {
+        let mut data = vec![0; 4];
+        for value in values {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data
+    }
+
+    fn dops() -> Vec<u8> {
+        // Stereo, 312 samples of pre-skip, 48 kHz, 1 dB of output gain.
+        vec![0, 2, 0x01, 0x38, 0, 0, 0xbb, 0x80, 0x01, 0x00, 0]
+    }
+
+    /// An MP4 file with the packets `packets` in chunks of 2, and an edit list of
+    /// `(duration in ms, media time)` edits.
+    pub fn mp4(packets: &[Vec<u8>], edits: &[(u32, i32)]) -> Vec<u8> {
+        let ftyp = mp4box(b"ftyp", b"isomiso2");
+        let mdat: Vec<u8> = packets.concat();
+        let mut chunk_offsets = vec![];
+        let mut offset = ftyp.len() + 8;
+        for chunk in packets.chunks(2) {
+            chunk_offsets.push(offset as u32);
+            offset += chunk.iter().map(Vec::len).sum::<usize>();
+        }
+
+        let mut entry = vec![0; 28];
+        entry.extend(mp4box(b"dOps", &dops()));
+        let mut stsd = fields(&[1]);
+        stsd.extend(mp4box(b"Opus", &entry));
+        let mut sizes = vec![0, packets.len() as u32];
+        sizes.extend(packets.iter().map(|p| p.len() as u32));
+        // Chunks of 2 samples, and a last one of 1 for an odd count.
+        let mut stsc = vec![1, 1, 2, 1];
+        if packets.len() % 2 == 1 {
+            stsc.extend_from_slice(&[chunk_offsets.len() as u32, 1, 1]);
+            stsc[0] = 2;
+        }
+        let mut offsets = vec![chunk_offsets.len() as u32];
+        offsets.extend(&chunk_offsets);
+        let stbl = [mp4box(b"stsd", &stsd),
+                    mp4box(b"stts", &fields(&[1, packets.len() as u32, 960])),
+                    mp4box(b"stsc", &fields(&stsc)),
+                    mp4box(b"stsz", &fields(&sizes)),
+                    mp4box(b"stco", &fields(&offsets))]
+            .concat();
+        let mdia = [mp4box(b"mdhd", &fields(&[0, 0, 48000, 0])),
+                    mp4box(b"minf", &mp4box(b"stbl", &stbl))]
+            .concat();
+        let mut elst = vec![edits.len() as u32];
+        for &(duration, media_time) in edits {
+            elst.extend_from_slice(&[duration, media_time as u32, 0x10000]);
+        }
+        let trak = [mp4box(b"edts", &mp4box(b"elst", &fields(&elst))), mp4box(b"mdia", &mdia)]
+            .concat();
+        let moov = [mp4box(b"mvhd", &fields(&[0, 0, 1000])), mp4box(b"trak", &trak)].concat();
+        [ftyp, mp4box(b"mdat", &mdat), mp4box(b"moov", &moov)].concat()
+    }
+
+    fn packets() -> Vec<Vec<u8>> {
+        (0..5).map(|i| vec![0xfc; 10 + i]).collect()
+    }
+
+    #[test]
+    fn opus_specific_box() {
+        let head = parse_dops(&dops()).unwrap();
+        assert_eq!((head.channels, head.pre_skip, head.output_gain), (2, 312, 256));
+        assert_eq!(head.input_sample_rate, 48000);
+        let mut data = dops();
+        data[0] = 1;
+        assert!(parse_dops(&data).is_err());
+        assert!(parse_dops(&data[..10]).is_err());
+    }
+
+    #[test]
+    fn sample_tables() {
+        let data = mp4(&packets(), &[(90, 312)]);
+        let mut reader = Mp4Reader::new(io::Cursor::new(&data[..])).unwrap();
+        assert_eq!(reader.head, parse_dops(&dops()).unwrap());
+        assert_eq!((reader.pre_skip, reader.length, reader.packets()), (312, Some(4320), 5));
+        for (i, p) in packets().iter().enumerate() {
+            assert_eq!(reader.next_packet().unwrap(),
+                       Some(Mp4Packet {
+                           data: p.clone(),
+                           timestamp: 960 * i as u64,
+                       }));
+        }
+        assert_eq!(reader.next_packet().unwrap(), None);
+
+        reader.seek(2000);
+        assert_eq!(reader.next_packet().unwrap().unwrap().timestamp, 1920);
+        reader.seek(0);
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, packets()[0]);
+    }
+
+    #[test]
+    fn edit_lists() {
+        // An empty edit first.
+        let data = mp4(&packets(), &[(500, -1), (90, 1000)]);
+        let reader = Mp4Reader::new(io::Cursor::new(&data[..])).unwrap();
+        assert_eq!((reader.pre_skip, reader.length), (1000, Some(4320)));
+        // Without an edit list, the pre-skip of the header, and an unknown length.
+        let data = mp4(&packets(), &[]);
+        let reader = Mp4Reader::new(io::Cursor::new(&data[..])).unwrap();
+        assert_eq!((reader.pre_skip, reader.length), (312, None));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let data = mp4(&packets(), &[]);
+        // Without the movie box, or with a truncated sample table.
+        assert!(Mp4Reader::new(io::Cursor::new(&data[..40])).is_err());
+        let mut other = data.clone();
+        let pos = other.windows(4).position(|w| w == b"Opus").unwrap();
+        other[pos..pos + 4].copy_from_slice(b"mp4a");
+        let err = Mp4Reader::new(io::Cursor::new(&other[..])).err().unwrap();
+        assert_eq!(err.to_string(), "no Opus track");
+        let stsz = data.windows(4).position(|w| w == b"stsz").unwrap();
+        let mut truncated = data.clone();
+        truncated[stsz + 15] = 50;
+        assert!(Mp4Reader::new(io::Cursor::new(&truncated[..])).is_err());
+        // A constant sample size, for more samples than the file holds.
+        let mut constant = data.clone();
+        constant[stsz + 11] = 10;
+        constant[stsz + 12..stsz + 16].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = Mp4Reader::new(io::Cursor::new(&constant[..])).err().unwrap();
+        assert_eq!(err.to_string(), "truncated sample table");
+    }
+}
@@ -0,0 +1,335 @@
+use consts;
+use error::Error;
+use opus_decoder::OpusDecoder;
+use packet;
+use std;
+
+/// Streams, coupled streams and channel mapping of the Vorbis channel orders, for 1 to 8
+/// channels (RFC 7845, section 5.1.1.2).
+///
+///     1: mono
+///     2: stereo (left, right)
+///     3: linear surround (left, center, right)
+///     4: quadraphonic (front left, front right, rear left, rear right)
+///     5: 5.0 (front left, center, front right, rear left, rear right)
+///     6: 5.1 (front left, center, front right, rear left, rear right, LFE)
+///     7: 6.1 (front left, center, front right, side left, side right, rear center, LFE)
+///     8: 7.1 (front left, center, front right, side left, side right, rear left, rear right, LFE)
+pub const VORBIS_MAPPINGS: [(usize, usize, &[u8]); 8] = [
+    (1, 0, &[0]),
+    (1, 1, &[0, 1]),
+    (2, 1, &[0, 2, 1]),
+    (2, 2, &[0, 1, 2, 3]),
+    (3, 2, &[0, 4, 1, 2, 3]),
+    (4, 2, &[0, 4, 1, 2, 3, 5]),
+    (4, 3, &[0, 4, 1, 2, 3, 5, 6]),
+    (5, 3, &[0, 6, 1, 2, 3, 4, 5, 7]),
+];
+
+/// Multistream decoder.
+///
+/// A multistream packet is the concatenation of one self-delimited packet per stream, except for
+/// the last one which is a regular packet (RFC 6716, Appendix B). The first `coupled_streams`
+/// streams are stereo, and the others mono. Output channel `i` takes decoded channel
+/// `mapping[i]`, numbering the left and right channels of the coupled streams first, then the
+/// uncoupled streams. A mapping of 255 outputs silence.
+pub struct OpusMSDecoder {
+    pub channels: usize,
+    pub streams: usize,
+    pub coupled_streams: usize,
+    pub mapping: Vec<u8>,
+    pub decoders: Vec<OpusDecoder>,
+    buffer: Vec<f32>,
+}
+
+impl OpusMSDecoder {
+    pub fn new(channels: usize,
+               streams: usize,
+               coupled_streams: usize,
+               mapping: &[u8])
+               -> Result<OpusMSDecoder, Error> {
+        if channels == 0 || channels > 255 || mapping.len() != channels || streams == 0 ||
+           coupled_streams > streams || streams + coupled_streams > 255 {
+            return Err(Error::BadArg);
+        }
+        if mapping.iter().any(|&m| m != 255 && m as usize >= streams + coupled_streams) {
+            return Err(Error::BadArg);
+        }
+        let decoders = (0..streams)
+            .map(|s| OpusDecoder::with_channels(if s < coupled_streams { 2 } else { 1 }))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(OpusMSDecoder {
+            channels,
+            streams,
+            coupled_streams,
+            mapping: mapping.to_vec(),
+            decoders,
+            buffer: vec![0.0; 2 * packet::MAX_PACKET_DURATION],
+        })
+    }
+
+    /// Decoder for one of the channel mapping families without a coded mapping table.
+    ///
+    /// Family 0 is mono or stereo in a single stream. Family 1 is 1 to 8 channels in the Vorbis
+    /// channel order, see `VORBIS_MAPPINGS`. The other families code their mapping in the
+    /// identification header, and their decoders are built with `new`.
+    pub fn with_family(family: u8, channels: usize) -> Result<OpusMSDecoder, Error> {
+        match family {
+            0 if channels == 1 || channels == 2 => {
+                OpusMSDecoder::new(channels, 1, channels - 1, &[0, 1][..channels])
+            }
+            1 if (1..=8).contains(&channels) => {
+                let (streams, coupled_streams, mapping) = VORBIS_MAPPINGS[channels - 1];
+                OpusMSDecoder::new(channels, streams, coupled_streams, mapping)
+            }
+            _ => Err(Error::BadArg),
+        }
+    }
+
+    /// Split a multistream packet into the packets of its streams, and check that they have the
+    /// same duration and can all be decoded.
+    pub fn parse<'a>(&self, data: &'a [u8]) -> Result<Vec<packet::Packet<'a>>, Error> {
+        let mut packets: Vec<packet::Packet> = Vec::with_capacity(self.streams);
+        let mut offset = 0;
+        for (s, decoder) in self.decoders.iter().enumerate() {
+            if offset >= data.len() {
+                return Err(Error::InvalidPacket);
+            }
+            let packet = packet::parse(&data[offset..], s != self.streams - 1)?;
+            offset += packet.len;
+            if packets.first().is_some_and(|first| first.duration() != packet.duration()) {
+                return Err(Error::InvalidPacket);
+            }
+            decoder.check_packet(&packet)?;
+            packets.push(packet);
+        }
+        Ok(packets)
+    }
+
+    /// Decode a multistream packet into `pcm`, with `channels` interleaved channels, or conceal
+    /// a lost packet when `data` is `None`. Returns the number of samples per channel.
+    ///
+    /// The whole packet is checked before any stream is decoded, so that the streams stay in
+    /// step after an error.
+    pub fn decode(&mut self, data: Option<&[u8]>, pcm: &mut [f32]) -> Result<usize, Error> {
+        let packets = match data {
+            Some(data) if !data.is_empty() => Some(self.parse(data)?),
+            _ => None,
+        };
+        let duration = packets.as_ref().map_or(consts::FRAME_SIZE, |packets| packets[0].duration());
+        if pcm.len() < self.channels * duration {
+            return Err(Error::BufferTooSmall);
+        }
+        for s in 0..self.streams {
+            let n = match packets {
+                Some(ref packets) => self.decoders[s].decode_packet(&packets[s], &mut self.buffer)?,
+                None => self.decoders[s].decode(None, &mut self.buffer)?,
+            };
+            self.route(s, n, pcm);
+        }
+        for (i, &m) in self.mapping.iter().enumerate() {
+            if m == 255 {
+                for j in 0..duration {
+                    pcm[j * self.channels + i] = 0.0;
+                }
+            }
+        }
+        Ok(duration)
+    }
+
+    /// Copy the output of stream `s`, held in `buffer`, to the channels mapped to it.
+    fn route(&self, s: usize, n: usize, pcm: &mut [f32]) {
+        let stride = self.decoders[s].channels;
+        for (i, &m) in self.mapping.iter().enumerate() {
+            let m = m as usize;
+            let c = if m < 2 * self.coupled_streams && m / 2 == s {
+                m % 2
+            } else if m != 255 && m >= 2 * self.coupled_streams &&
+                      m - self.coupled_streams == s {
+                0
+            } else {
+                continue;
+            };
+            for j in 0..n {
+                pcm[j * self.channels + i] = self.buffer[j * stride + c];
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for decoder in self.decoders.iter_mut() {
+            decoder.reset();
+        }
+    }
+
+    /// Gain applied to the decoded samples of every stream, in Q7.8 dB.
+    pub fn set_gain(&mut self, gain: i16) {
+        for decoder in self.decoders.iter_mut() {
+            decoder.set_gain(gain);
+        }
+    }
+}
+
+/// # Safety
+///
+/// `mapping` points to `channels` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn opus_multistream_decoder_create(channels: i32,
+                                                             streams: i32,
+                                                             coupled_streams: i32,
+                                                             mapping: *const u8)
+                                                             -> *mut OpusMSDecoder {
+    if channels <= 0 || streams < 0 || coupled_streams < 0 || mapping.is_null() {
+        return std::ptr::null_mut();
+    }
+    let mapping = std::slice::from_raw_parts(mapping, channels as usize);
+    match OpusMSDecoder::new(channels as usize, streams as usize, coupled_streams as usize, mapping) {
+        Ok(st) => Box::into_raw(Box::new(st)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `data_ptr` points to `length` bytes, or is NULL for a lost packet. `pcm_ptr` has room for the
+/// decoded samples of all the interleaved channels: the duration of the packet, or 20 ms for a
+/// lost packet.
+#[no_mangle]
+pub unsafe extern "C" fn opus_multistream_decoder_decode(st: &mut OpusMSDecoder,
+                                                         data_ptr: *const u8,
+                                                         length: usize,
+                                                         pcm_ptr: *mut f32)
+                                                         -> i32 {
+    let data = if data_ptr.is_null() || length == 0 {
+        None
+    } else {
+        Some(std::slice::from_raw_parts(data_ptr, length))
+    };
+    let duration = match data {
+        Some(data) => packet::parse(data, st.streams > 1).map(|packet| packet.duration()),
+        None => Ok(consts::FRAME_SIZE),
+    };
+    let result = duration.and_then(|duration| {
+        let pcm = std::slice::from_raw_parts_mut(pcm_ptr, st.channels * duration);
+        st.decode(data, pcm)
+    });
+    match result {
+        Ok(n) => n as i32,
+        Err(err) => err.code(),
+    }
+}
+
+/// # Safety
+///
+/// `st` was returned by `opus_multistream_decoder_create`, and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn opus_multistream_decoder_destroy(st: *mut OpusMSDecoder) {
+    if !st.is_null() {
+        drop(Box::from_raw(st));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    /// The CELT packet `i` with the TOC byte `toc`, self-delimited or not.
+    fn stream_packet(toc: u8, i: usize, self_delimited: bool) -> Vec<u8> {
+        let mut data = celt_packet(i);
+        data[0] = toc;
+        if self_delimited {
+            data.insert(1, 160);
+        }
+        data
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(OpusMSDecoder::new(2, 1, 1, &[0]).is_err());
+        assert!(OpusMSDecoder::new(2, 1, 1, &[0, 2]).is_err());
+        assert!(OpusMSDecoder::new(1, 1, 2, &[0]).is_err());
+        assert!(OpusMSDecoder::with_family(0, 3).is_err());
+        assert!(OpusMSDecoder::with_family(1, 9).is_err());
+        assert!(OpusMSDecoder::with_family(2, 4).is_err());
+        // Family 255 has a coded mapping table.
+        assert!(OpusMSDecoder::with_family(255, 2).is_err());
+        let d = OpusMSDecoder::with_family(1, 6).unwrap();
+        assert_eq!((d.streams, d.coupled_streams), (4, 2));
+        assert_eq!(d.decoders.iter().map(|d| d.channels).collect::<Vec<_>>(), [2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn routing() {
+        // Linear surround: a stereo stream for left and right, and a mono one for the center.
+        let mut data = stream_packet(0xfc, 0, true);
+        data.extend(stream_packet(0xf8, 1, false));
+        let mut decoder = OpusMSDecoder::with_family(1, 3).unwrap();
+        let mut pcm = vec![0.0; 3 * 960];
+        assert_eq!(decoder.decode(Some(&data), &mut pcm), Ok(960));
+
+        let mut stereo = vec![0.0; 2 * 960];
+        OpusDecoder::new().decode(Some(&celt_packet(0)), &mut stereo).unwrap();
+        let mut mono = vec![0.0; 960];
+        OpusDecoder::with_channels(1)
+            .unwrap()
+            .decode(Some(&stream_packet(0xf8, 1, false)), &mut mono)
+            .unwrap();
+        for i in 0..960 {
+            assert_eq!(&pcm[3 * i..3 * i + 3], &[stereo[2 * i], mono[i], stereo[2 * i + 1]]);
+        }
+
+        // A lost packet is concealed in every stream.
+        assert_eq!(decoder.decode(None, &mut pcm), Ok(960));
+    }
+
+    #[test]
+    fn silent_channels() {
+        let mut decoder = OpusMSDecoder::new(3, 1, 1, &[1, 255, 0]).unwrap();
+        let mut pcm = vec![1.0; 3 * 960];
+        let data = celt_packet(2);
+        assert_eq!(decoder.decode(Some(&data), &mut pcm), Ok(960));
+        let mut stereo = vec![0.0; 2 * 960];
+        OpusDecoder::new().decode(Some(&data), &mut stereo).unwrap();
+        for i in 0..960 {
+            assert_eq!(&pcm[3 * i..3 * i + 3], &[stereo[2 * i + 1], 0.0, stereo[2 * i]]);
+        }
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let mut decoder = OpusMSDecoder::new(2, 2, 0, &[0, 1]).unwrap();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        // Streams of different durations.
+        let mut data = stream_packet(0xf8, 0, true);
+        data.extend(stream_packet(0xf9, 0, false));
+        assert_eq!(decoder.decode(Some(&data), &mut pcm), Err(Error::InvalidPacket));
+        // A missing stream.
+        let data = stream_packet(0xf8, 0, true);
+        assert_eq!(decoder.decode(Some(&data), &mut pcm), Err(Error::InvalidPacket));
+        // Too small an output.
+        let mut data = stream_packet(0xf8, 0, true);
+        data.extend(stream_packet(0xf8, 0, false));
+        assert_eq!(decoder.decode(Some(&data), &mut pcm[..960]), Err(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn streams_in_step() {
+        let mut decoder = OpusMSDecoder::new(2, 2, 0, &[0, 1]).unwrap();
+        let mut pcm = vec![0.0; 2 * 960];
+        let mut good = stream_packet(0xf8, 0, true);
+        good.extend(stream_packet(0xf8, 1, false));
+        decoder.decode(Some(&good), &mut pcm).unwrap();
+        // The second stream cannot be decoded: neither is the first.
+        let mut bad = stream_packet(0xf8, 2, true);
+        bad.extend(stream_packet(0x08, 2, false));
+        assert_eq!(decoder.decode(Some(&bad), &mut pcm), Err(Error::Unimplemented));
+
+        let mut expected = OpusMSDecoder::new(2, 2, 0, &[0, 1]).unwrap();
+        let mut expected_pcm = vec![0.0; 2 * 960];
+        expected.decode(Some(&good), &mut expected_pcm).unwrap();
+        decoder.decode(Some(&good), &mut pcm).unwrap();
+        expected.decode(Some(&good), &mut expected_pcm).unwrap();
+        assert_eq!(pcm, expected_pcm);
+    }
+}
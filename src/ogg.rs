@@ -0,0 +1,654 @@
+use std;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of a page header without its segment table.
+const HEADER_SIZE: usize = 27;
+
+/// Size of the body beyond which the writer ends a page.
+const TARGET_PAGE_SIZE: usize = 4096;
+
+/// Maximum size of a page: the header, 255 lacing values and 255 segments of 255 bytes.
+pub const MAX_PAGE_SIZE: usize = HEADER_SIZE + 255 + 255 * 255;
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+
+/// CRC-32 with polynomial 0x04c11db7, no reflection and a zero initial value, over the page
+/// with its checksum field zeroed (RFC 3533, section 6).
+pub fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| crc32_update(crc, byte))
+}
+
+/// An Ogg page (RFC 3533, section 6).
+///
+///      0                   1                   2                   3
+///      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1| Byte
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     | capture_pattern: Magic number for page start "OggS"           | 0-3
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     | version       | header_type   | granule_position              | 4-7
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                                                               | 8-11
+///     +                               +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                               | bitstream_serial_number       | 12-15
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                               | page_sequence_number          | 16-19
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                               | CRC_checksum                  | 20-23
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                               |page_segments  | segment_table | 24-27
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     | ...                                                           | 28-
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    /// The first packet on the page continues a packet of the previous page.
+    pub continued: bool,
+    /// Beginning of stream.
+    pub bos: bool,
+    /// End of stream.
+    pub eos: bool,
+    /// Position after the last packet completed on the page, or -1 if none is.
+    pub granule_position: i64,
+    pub serial: u32,
+    pub sequence: u32,
+    /// Lacing values: a segment shorter than 255 bytes ends a packet.
+    pub segments: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+impl Page {
+    /// Parse a page at the start of `data`. Returns `None` if `data` does not start with a
+    /// complete page with a valid checksum, otherwise the page and its size.
+    pub fn parse(data: &[u8]) -> Option<(Page, usize)> {
+        if data.len() < HEADER_SIZE || &data[..4] != CAPTURE_PATTERN || data[4] != 0 {
+            return None;
+        }
+        let header_size = HEADER_SIZE + data[26] as usize;
+        if data.len() < header_size {
+            return None;
+        }
+        let segments = &data[HEADER_SIZE..header_size];
+        let size = header_size + segments.iter().map(|&s| s as usize).sum::<usize>();
+        if data.len() < size {
+            return None;
+        }
+        let mut header = data[..header_size].to_vec();
+        header[22..26].copy_from_slice(&[0; 4]);
+        let crc = data[header_size..size]
+            .iter()
+            .fold(crc32(&header), |crc, &byte| crc32_update(crc, byte));
+        if crc != u32_le(&data[22..]) {
+            return None;
+        }
+        let page = Page {
+            continued: data[5] & 0x1 != 0,
+            bos: data[5] & 0x2 != 0,
+            eos: data[5] & 0x4 != 0,
+            granule_position: u32_le(&data[6..]) as i64 | (u32_le(&data[10..]) as i64) << 32,
+            serial: u32_le(&data[14..]),
+            sequence: u32_le(&data[18..]),
+            segments: segments.to_vec(),
+            body: data[header_size..size].to_vec(),
+        };
+        Some((page, size))
+    }
+
+    /// Serialise the page, computing its checksum.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.segments.len() + self.body.len());
+        data.extend_from_slice(CAPTURE_PATTERN);
+        data.push(0);
+        data.push(self.continued as u8 | (self.bos as u8) << 1 | (self.eos as u8) << 2);
+        data.extend_from_slice(&(self.granule_position as u64).to_le_bytes());
+        data.extend_from_slice(&self.serial.to_le_bytes());
+        data.extend_from_slice(&self.sequence.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.push(self.segments.len() as u8);
+        data.extend_from_slice(&self.segments);
+        data.extend_from_slice(&self.body);
+        let crc = crc32(&data);
+        data[22..26].copy_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    /// Number of packets completed on the page.
+    pub fn packets(&self) -> usize {
+        self.segments.iter().filter(|&&s| s < 255).count()
+    }
+}
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc ^ (byte as u32) << 24;
+    for _ in 0..8 {
+        crc = if crc & 0x80000000 != 0 {
+            crc << 1 ^ 0x04c11db7
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+fn u32_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// Reader of the pages of an Ogg bitstream.
+///
+/// Garbage between pages and pages with a bad checksum are skipped, by searching for the next
+/// capture pattern.
+pub struct PageReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Offset in `reader` of the start of `buffer`.
+    offset: u64,
+    eof: bool,
+}
+
+impl<R: Read> PageReader<R> {
+    pub fn new(reader: R) -> PageReader<R> {
+        PageReader::with_offset(reader, 0)
+    }
+
+    /// Reader whose input starts at byte `offset` of the underlying source.
+    pub fn with_offset(reader: R, offset: u64) -> PageReader<R> {
+        PageReader {
+            reader,
+            buffer: vec![],
+            offset,
+            eof: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Offset of the next page that would be returned, or of the data not yet synced.
+    pub fn position(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read more data into the buffer. Returns false at the end of the input.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let len = self.buffer.len();
+        self.buffer.resize(len + 4096, 0);
+        let n = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(n) => break n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.buffer.truncate(len);
+                    return Err(err);
+                }
+            }
+        };
+        self.buffer.truncate(len + n);
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buffer.drain(..n);
+        self.offset += n as u64;
+    }
+
+    /// Read the next valid page, with its offset in the source. Returns `None` at the end of
+    /// the input.
+    pub fn next_page(&mut self) -> io::Result<Option<(Page, u64)>> {
+        loop {
+            match self.buffer.windows(4).position(|w| w == CAPTURE_PATTERN) {
+                Some(start) => {
+                    self.consume(start);
+                    if let Some((page, size)) = Page::parse(&self.buffer) {
+                        let offset = self.offset;
+                        self.consume(size);
+                        return Ok(Some((page, offset)));
+                    }
+                    // Either the page is incomplete, or it is corrupted and the search resumes
+                    // after its capture pattern.
+                    if self.buffer.len() >= MAX_PAGE_SIZE || !self.fill()? {
+                        if self.buffer.len() < 4 {
+                            return Ok(None);
+                        }
+                        self.consume(1);
+                    }
+                }
+                None => {
+                    // Keep a partial capture pattern at the end of the buffer.
+                    let keep = std::cmp::min(self.buffer.len(), 3);
+                    let skip = self.buffer.len() - keep;
+                    self.consume(skip);
+                    if !self.fill()? {
+                        let len = self.buffer.len();
+                        self.consume(len);
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> PageReader<R> {
+    /// Continue reading at byte `offset` of the source.
+    pub fn seek(&mut self, offset: u64) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.buffer.clear();
+        self.offset = offset;
+        self.eof = false;
+        Ok(())
+    }
+
+    /// Size of the source. Reading continues where it was.
+    pub fn stream_len(&mut self) -> io::Result<u64> {
+        let position = self.reader.stream_position()?;
+        let len = self.reader.seek(SeekFrom::End(0))?;
+        self.reader.seek(SeekFrom::Start(position))?;
+        Ok(len)
+    }
+}
+
+/// A packet of a logical bitstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub data: Vec<u8>,
+    pub serial: u32,
+    /// Granule position of the page, if the packet is the last one completed on it.
+    pub granule_position: Option<i64>,
+    /// First packet of its logical bitstream.
+    pub bos: bool,
+    /// Last packet of its logical bitstream.
+    pub eos: bool,
+    /// Index of the packet among the packets received of its logical bitstream, counting from 0.
+    /// A packet dropped for a lost page is counted if its end was received, but the packets
+    /// wholly on lost pages are not, so after a gap this may not be the index in the bitstream.
+    pub number: u64,
+}
+
+#[derive(Default)]
+struct Stream {
+    partial: Vec<u8>,
+    /// Sequence number of the last page.
+    sequence: Option<u32>,
+    /// The partial packet lost a page and is dropped when completed.
+    hole: bool,
+    packets: u64,
+}
+
+/// Reader of the packets of the logical bitstreams multiplexed in an Ogg bitstream.
+///
+/// Packets spanning several pages are reassembled. Packets are returned in the order they
+/// complete, with the serial number of their stream. A packet missing a page, as detected from
+/// the page sequence numbers, is dropped.
+pub struct PacketReader<R> {
+    pages: PageReader<R>,
+    streams: HashMap<u32, Stream>,
+    queue: VecDeque<Packet>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R) -> PacketReader<R> {
+        PacketReader::from_pages(PageReader::new(reader))
+    }
+
+    pub fn from_pages(pages: PageReader<R>) -> PacketReader<R> {
+        PacketReader {
+            pages,
+            streams: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn pages(&mut self) -> &mut PageReader<R> {
+        &mut self.pages
+    }
+
+    pub fn into_inner(self) -> R {
+        self.pages.into_inner()
+    }
+
+    /// Forget the partial packets and the queued packets, e.g. after seeking the source. The
+    /// next packet is the first one starting on a new page.
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        for stream in self.streams.values_mut() {
+            stream.partial.clear();
+            stream.sequence = None;
+            stream.hole = true;
+        }
+    }
+
+    /// Drop the packets completed by the pages pushed so far, keeping the partial ones.
+    pub fn drop_completed(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Split a page into packets, completing the partial packet of its stream.
+    pub fn push_page(&mut self, page: &Page) {
+        let stream = self.streams.entry(page.serial).or_default();
+        if page.bos {
+            *stream = Default::default();
+        }
+        match stream.sequence {
+            Some(sequence) if sequence.wrapping_add(1) == page.sequence => {}
+            Some(_) => {
+                stream.partial.clear();
+                stream.hole = true;
+            }
+            None => {}
+        }
+        stream.sequence = Some(page.sequence);
+        if !page.continued && !stream.partial.is_empty() {
+            stream.partial.clear();
+        }
+        // A continued packet whose start was never seen is dropped.
+        let mut skip = page.continued && (stream.partial.is_empty() || stream.hole);
+        if !page.continued {
+            stream.hole = false;
+        }
+
+        let last = page.segments.iter().rposition(|&s| s < 255);
+        let mut pos = 0;
+        for (i, &segment) in page.segments.iter().enumerate() {
+            let segment = segment as usize;
+            if !skip {
+                stream.partial.extend_from_slice(&page.body[pos..pos + segment]);
+            }
+            pos += segment;
+            if segment < 255 {
+                if !skip {
+                    let data = std::mem::take(&mut stream.partial);
+                    self.queue.push_back(Packet {
+                        data,
+                        serial: page.serial,
+                        granule_position: if Some(i) == last && page.granule_position != -1 {
+                            Some(page.granule_position)
+                        } else {
+                            None
+                        },
+                        bos: page.bos && stream.packets == 0,
+                        eos: page.eos && Some(i) == last,
+                        number: stream.packets,
+                    });
+                }
+                stream.packets += 1;
+                skip = false;
+                stream.hole = false;
+            }
+        }
+        if skip {
+            stream.partial.clear();
+        }
+    }
+
+    /// Read the next complete packet of any logical bitstream. Returns `None` at the end of the
+    /// input.
+    pub fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.queue.pop_front() {
+                return Ok(Some(packet));
+            }
+            match self.pages.next_page()? {
+                Some((page, _)) => self.push_page(&page),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = io::Result<Packet>;
+
+    fn next(&mut self) -> Option<io::Result<Packet>> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Writer of the packets of a logical bitstream into pages.
+///
+/// A page ends once it holds 255 lacing values or `TARGET_PAGE_SIZE` bytes, or when flushed.
+/// Its granule position is that of the last packet completed on it.
+pub struct PacketWriter<W> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+    granule_position: i64,
+    /// The page being filled continues a packet of the previous page.
+    continued: bool,
+}
+
+impl<W: Write> PacketWriter<W> {
+    pub fn new(writer: W, serial: u32) -> PacketWriter<W> {
+        PacketWriter {
+            writer,
+            serial,
+            sequence: 0,
+            segments: vec![],
+            body: vec![],
+            granule_position: -1,
+            continued: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write a packet ending at `granule_position`. The last packet of the stream has `eos`
+    /// set, which ends its page.
+    pub fn write_packet(&mut self,
+                        data: &[u8],
+                        granule_position: i64,
+                        eos: bool)
+                        -> io::Result<()> {
+        // A packet takes a lacing value of 255 per 255 bytes, and a last one below 255.
+        let mut pos = 0;
+        loop {
+            if self.segments.len() == 255 {
+                self.write_page(false, pos > 0)?;
+            }
+            let segment = std::cmp::min(data.len() - pos, 255);
+            self.segments.push(segment as u8);
+            self.body.extend_from_slice(&data[pos..pos + segment]);
+            pos += segment;
+            if segment < 255 {
+                break;
+            }
+        }
+        self.granule_position = granule_position;
+        if eos {
+            self.write_page(true, false)
+        } else if self.body.len() >= TARGET_PAGE_SIZE {
+            self.write_page(false, false)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// End the current page, if not empty, e.g. after the headers of a stream.
+    pub fn flush_page(&mut self) -> io::Result<()> {
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+        self.write_page(false, false)
+    }
+
+    /// Write the current page, even if empty, as the last one of the stream.
+    pub fn end_stream(&mut self) -> io::Result<()> {
+        self.write_page(true, false)
+    }
+
+    /// Write the current page. `continues` tells whether the next page continues its last
+    /// packet.
+    fn write_page(&mut self, eos: bool, continues: bool) -> io::Result<()> {
+        let page = Page {
+            continued: self.continued,
+            bos: self.sequence == 0,
+            eos,
+            granule_position: self.granule_position,
+            serial: self.serial,
+            sequence: self.sequence,
+            segments: std::mem::take(&mut self.segments),
+            body: std::mem::take(&mut self.body),
+        };
+        self.writer.write_all(&page.to_bytes())?;
+        self.sequence += 1;
+        self.granule_position = -1;
+        self.continued = continues;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packets of various sizes, a large one spanning pages.
+    fn packets() -> Vec<Vec<u8>> {
+        [0, 1, 254, 255, 256, 510, 5000, 70000, 3]
+            .iter()
+            .map(|&len| (0..len).map(|i| (i * 7 + len) as u8).collect())
+            .collect()
+    }
+
+    fn write_stream(serial: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut writer = PacketWriter::new(vec![], serial);
+        for (i, p) in packets.iter().enumerate() {
+            writer.write_packet(p, 100 * i as i64, i == packets.len() - 1).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0x89a1897f);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn page_round_trip() {
+        let page = Page {
+            continued: true,
+            bos: false,
+            eos: true,
+            granule_position: 0x123456789,
+            serial: 0xdeadbeef,
+            sequence: 7,
+            segments: vec![255, 10],
+            body: (0..265).map(|i| i as u8).collect(),
+        };
+        let data = page.to_bytes();
+        assert_eq!(data.len(), HEADER_SIZE + 2 + 265);
+        assert_eq!(Page::parse(&data), Some((page.clone(), data.len())));
+        assert_eq!(page.packets(), 1);
+        // Trailing data is left alone, but a truncated or corrupted page is rejected.
+        let mut longer = data.clone();
+        longer.extend_from_slice(b"OggS");
+        assert_eq!(Page::parse(&longer), Some((page, data.len())));
+        assert_eq!(Page::parse(&data[..data.len() - 1]), None);
+        let mut corrupted = data;
+        corrupted[100] ^= 1;
+        assert_eq!(Page::parse(&corrupted), None);
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packets = packets();
+        let data = write_stream(42, &packets);
+        let mut pages = PageReader::new(&data[..]);
+        let mut count = 0;
+        let mut offset = 0;
+        while let Some((page, page_offset)) = pages.next_page().unwrap() {
+            assert_eq!((page.sequence, page.bos), (count, count == 0));
+            assert_eq!(page_offset, offset);
+            offset += page.to_bytes().len() as u64;
+            count += 1;
+        }
+        assert_eq!(offset, data.len() as u64);
+        assert!(count > 3);
+
+        let read: Vec<Packet> = PacketReader::new(&data[..]).map(|p| p.unwrap()).collect();
+        assert_eq!(read.len(), packets.len());
+        for (i, (p, data)) in read.iter().zip(&packets).enumerate() {
+            assert_eq!((&p.data, p.serial, p.number), (data, 42, i as u64));
+            assert_eq!((p.bos, p.eos), (i == 0, i == packets.len() - 1));
+        }
+        // Only the last packet of a page has its granule position.
+        assert_eq!(read[8].granule_position, Some(800));
+        assert_eq!(read[7].granule_position, Some(700));
+        assert_eq!(read[0].granule_position, None);
+    }
+
+    #[test]
+    fn multiplexed() {
+        let a = write_stream(1, &packets()[..3]);
+        let b = write_stream(2, &packets()[5..7]);
+        // Garbage, with a capture pattern, before the pages.
+        let mut data = b"garbage OggS".to_vec();
+        data.extend_from_slice(&b);
+        data.extend_from_slice(&a);
+        let read: Vec<Packet> = PacketReader::new(&data[..]).map(|p| p.unwrap()).collect();
+        let ids: Vec<(u32, u64)> = read.iter().map(|p| (p.serial, p.number)).collect();
+        assert_eq!(ids, [(2, 0), (2, 1), (1, 0), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn lost_page() {
+        let packets = packets();
+        let data = write_stream(3, &packets);
+        let mut pages = vec![];
+        let mut pos = 0;
+        while let Some((page, size)) = Page::parse(&data[pos..]) {
+            pages.push(page);
+            pos += size;
+        }
+        // The page starting the 70000-byte packet is lost, and its end on the next page is
+        // dropped.
+        let lost = pages.iter().position(|page| page.segments.len() == 255).unwrap();
+        assert!(pages[lost + 1].continued);
+        let mut reader = PacketReader::new(&[][..]);
+        for (i, page) in pages.iter().enumerate() {
+            if i != lost {
+                reader.push_page(page);
+            }
+        }
+        let read: Vec<Packet> = reader.map(|p| p.unwrap()).collect();
+        let numbers: Vec<u64> = read.iter().map(|p| p.number).collect();
+        assert_eq!(numbers, [0, 1, 2, 3, 4, 5, 6, 8]);
+        assert_eq!(read[7].data, packets[8]);
+    }
+
+    #[test]
+    fn seek() {
+        let data = write_stream(4, &packets());
+        let mut pages = PageReader::new(io::Cursor::new(&data[..]));
+        pages.next_page().unwrap();
+        let (_, second) = pages.next_page().unwrap().unwrap();
+        assert_eq!(pages.stream_len().unwrap(), data.len() as u64);
+        // Reading continues after the second page.
+        assert_eq!(pages.next_page().unwrap().unwrap().0.sequence, 2);
+        // From the middle of the first page, the next one is found.
+        pages.seek(10).unwrap();
+        let (page, offset) = pages.next_page().unwrap().unwrap();
+        assert_eq!((page.sequence, offset), (1, second));
+    }
+}
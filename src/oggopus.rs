@@ -0,0 +1,732 @@
+use header::{self, OpusHead, OpusTags};
+use ogg::{self, PacketReader, PacketWriter};
+use packet;
+use playback::Playback;
+use std;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, Write};
+
+/// Number of samples to decode before a seek target, for the decoder to converge (RFC 7845,
+/// section 4.6).
+pub const PRE_ROLL: i64 = 3840;
+
+/// Below this range, bisection gives way to a linear scan of the pages.
+const BISECTION_THRESHOLD: u64 = ogg::MAX_PAGE_SIZE as u64;
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Gain applied to the decoded output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackGain {
+    /// No gain, not even the output gain of the header, which players must apply.
+    None,
+    /// The output gain of the header.
+    Header,
+    /// The output gain plus R128_TRACK_GAIN, or the output gain alone without the tag.
+    Track,
+    /// The output gain plus R128_ALBUM_GAIN, or the output gain alone without the tag.
+    Album,
+}
+
+impl PlaybackGain {
+    /// The sum of the output gain and the R128 gain, in Q7.8 dB, clamped to the range of a
+    /// Q7.8 value, as the gains can add up to more.
+    pub fn gain(self, head: &OpusHead, tags: &OpusTags) -> i16 {
+        let output_gain = head.output_gain as i32;
+        let gain = match self {
+            PlaybackGain::None => 0,
+            PlaybackGain::Header => output_gain,
+            PlaybackGain::Track => output_gain + tags.track_gain().unwrap_or(0) as i32,
+            PlaybackGain::Album => output_gain + tags.album_gain().unwrap_or(0) as i32,
+        };
+        gain.clamp(-32767, 32767) as i16
+    }
+}
+
+/// Decoder of the Opus streams of an Ogg file (RFC 7845).
+///
+/// Packets of the other logical bitstreams are ignored. The decoded samples are counted in
+/// granule positions, at 48 kHz, from which the first `pre_skip` samples of the stream are
+/// discarded: sample position 0 of the output is granule position `pre_skip`. The last page of
+/// the stream may end before its last packet, which is then trimmed to the granule position.
+///
+/// Pages lost in the middle of the stream show as a jump in the granule positions, and the
+/// missing samples are concealed.
+///
+/// A chained file is a sequence of links, each with its own stream and headers. The links are
+/// decoded one after the other, each trimmed as above, for gapless playback.
+///
+/// The output gain of the header is applied to the decoded samples, or another gain chosen by
+/// `set_playback_gain`.
+pub struct OggOpusReader<R> {
+    packets: PacketReader<R>,
+    serial: u32,
+    pub head: OpusHead,
+    pub tags: OpusTags,
+    link: usize,
+    /// Packets read ahead of decoding.
+    pending: VecDeque<ogg::Packet>,
+    /// Offset of the first audio page.
+    data_start: u64,
+    /// Granule position at the start of the first audio packet.
+    start_granule: i64,
+    eos: bool,
+    playback: Playback,
+    playback_gain: PlaybackGain,
+}
+
+/// Read packets up to the headers of the next Opus stream, returning its serial number and
+/// headers, or `None` at the end of the input.
+fn read_headers<R: Read>(packets: &mut PacketReader<R>)
+                         -> io::Result<Option<(u32, OpusHead, OpusTags)>> {
+    let (serial, head) = loop {
+        match packets.next_packet()? {
+            Some(ref p) if p.bos && header::is_opus_head(&p.data) => {
+                break (p.serial, OpusHead::parse(&p.data)?);
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+    };
+    let tags = loop {
+        match packets.next_packet()? {
+            Some(ref p) if p.serial == serial => break OpusTags::parse(&p.data)?,
+            Some(_) => {}
+            None => return Err(invalid_data("missing OpusTags header")),
+        }
+    };
+    Ok(Some((serial, head, tags)))
+}
+
+impl<R: Read> OggOpusReader<R> {
+    /// Read the headers of the first Opus stream.
+    pub fn new(reader: R) -> io::Result<OggOpusReader<R>> {
+        let mut packets = PacketReader::new(reader);
+        let (serial, head, tags) = match read_headers(&mut packets)? {
+            Some(headers) => headers,
+            None => return Err(invalid_data("no Opus stream")),
+        };
+        let playback = Playback::new(head.decoder()?);
+        let mut reader = OggOpusReader {
+            packets,
+            serial,
+            head,
+            tags,
+            link: 0,
+            pending: VecDeque::new(),
+            data_start: 0,
+            start_granule: 0,
+            eos: false,
+            playback,
+            playback_gain: PlaybackGain::Header,
+        };
+        reader.start_link()?;
+        Ok(reader)
+    }
+
+    /// Set up the decoding of a link whose headers were just read.
+    fn start_link(&mut self) -> io::Result<()> {
+        // The comment header ends its page, so that the audio starts on a new one.
+        self.data_start = self.packets.pages().position();
+        self.pending.clear();
+        self.eos = false;
+        self.start_granule = self.find_start_granule()?;
+        self.playback.granule = self.start_granule;
+        self.playback.skip_to = self.start_granule + self.head.pre_skip as i64;
+        self.update_gain();
+        Ok(())
+    }
+
+    /// Choose the gain applied to the output, from the next call to `read`.
+    pub fn set_playback_gain(&mut self, playback_gain: PlaybackGain) {
+        self.playback_gain = playback_gain;
+        self.update_gain();
+    }
+
+    fn update_gain(&mut self) {
+        self.playback.set_gain(self.playback_gain.gain(&self.head, &self.tags));
+    }
+
+    /// Gain applied to the output of the current link, in dB.
+    pub fn gain(&self) -> f64 {
+        self.playback.gain() as f64 / 256.0
+    }
+
+    /// Move on to the next link of a chained file, with a new decoder. Returns false at the end
+    /// of the input. `read` does so at the end of each link.
+    pub fn next_link(&mut self) -> io::Result<bool> {
+        let (serial, head, tags) = match read_headers(&mut self.packets)? {
+            Some(headers) => headers,
+            None => return Ok(false),
+        };
+        self.playback = Playback::new(head.decoder()?);
+        self.serial = serial;
+        self.head = head;
+        self.tags = tags;
+        self.link += 1;
+        self.start_link()?;
+        Ok(true)
+    }
+
+    /// Serial number of the stream of the current link.
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Index of the current link of a chained file, counting from 0. The headers, and the number
+    /// of channels, may change from one link to the next.
+    pub fn link(&self) -> usize {
+        self.link
+    }
+
+    /// Granule position at the start of the stream: that of the first page completing a packet,
+    /// minus the duration of the packets up to there.
+    fn find_start_granule(&mut self) -> io::Result<i64> {
+        let mut duration = 0;
+        loop {
+            let p = match self.read_packet()? {
+                Some(p) => p,
+                None => return Ok(0),
+            };
+            duration += packet::duration(&p.data).unwrap_or(0) as i64;
+            let granule_position = p.granule_position;
+            let eos = p.eos;
+            self.pending.push_back(p);
+            if let Some(granule_position) = granule_position {
+                // A shorter last page trims the end of the stream rather than its start.
+                return Ok(if eos { 0 } else { std::cmp::max(0, granule_position - duration) });
+            }
+        }
+    }
+
+    /// Next packet of the Opus stream from the source.
+    fn read_packet(&mut self) -> io::Result<Option<ogg::Packet>> {
+        loop {
+            match self.packets.next_packet()? {
+                Some(ref p) if p.serial != self.serial => {}
+                p => return Ok(p),
+            }
+        }
+    }
+
+    fn next_packet(&mut self) -> io::Result<Option<ogg::Packet>> {
+        if self.eos {
+            return Ok(None);
+        }
+        if self.pending.is_empty() {
+            self.read_page_packets()?;
+        }
+        let p = self.pending.pop_front();
+        self.eos = p.as_ref().is_none_or(|p| p.eos);
+        Ok(p)
+    }
+
+    /// Read the packets up to the end of a page. Pages lost before it show as a granule
+    /// position past the end of its packets from the last one, and the difference is left to
+    /// conceal.
+    fn read_page_packets(&mut self) -> io::Result<()> {
+        let mut duration = 0;
+        while let Some(p) = self.read_packet()? {
+            duration += packet::duration(&p.data).unwrap_or(0) as i64;
+            let granule_position = p.granule_position;
+            let eos = p.eos;
+            self.pending.push_back(p);
+            if let Some(granule_position) = granule_position {
+                let gap = granule_position - duration - self.playback.granule;
+                self.playback.gap = std::cmp::max(gap, 0);
+                break;
+            }
+            if eos {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.packets.into_inner()
+    }
+
+    /// Number of interleaved channels in the output.
+    pub fn channels(&self) -> usize {
+        self.playback.channels()
+    }
+
+    /// Sample position of the next output sample.
+    pub fn position(&self) -> u64 {
+        let granule = std::cmp::max(self.playback.granule, self.playback.skip_to);
+        std::cmp::max(0, granule - self.head.pre_skip as i64) as u64
+    }
+
+    /// Read the next packet of the current link without decoding it, for copying, with the
+    /// granule position at its start. Returns `None` at the end of the link. The granule
+    /// position of the packet, if any, is the end of the samples to play, and lost packets show
+    /// as a gap before the start. Not to be mixed with `read`.
+    pub fn next_raw_packet(&mut self) -> io::Result<Option<(ogg::Packet, i64)>> {
+        let p = match self.next_packet()? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        self.playback.granule += self.playback.gap;
+        self.playback.gap = 0;
+        let start = self.playback.granule;
+        self.playback.granule += packet::duration(&p.data).unwrap_or(0) as i64;
+        if let Some(granule_position) = p.granule_position {
+            self.playback.granule = granule_position;
+        }
+        Ok(Some((p, start)))
+    }
+
+    /// Decode the next packet into `pcm`, which has room for 120 ms of samples. Returns the
+    /// number of samples per channel, with `channels()` interleaved channels, or 0 at the end of
+    /// the input. Lost packets are concealed, up to 120 ms at a time.
+    pub fn read(&mut self, pcm: &mut [f32]) -> io::Result<usize> {
+        loop {
+            if self.pending.is_empty() && !self.eos {
+                self.read_page_packets()?;
+            }
+            if self.playback.gap > 0 {
+                let n = self.playback.conceal(pcm)?;
+                if n > 0 {
+                    return Ok(n);
+                }
+                continue;
+            }
+            let p = match self.next_packet()? {
+                Some(p) => p,
+                None if self.next_link()? => continue,
+                None => return Ok(0),
+            };
+            // The last page may end before its last packet, but not before its start.
+            let trim = match p.granule_position {
+                Some(granule_position) if p.eos => {
+                    let duration = packet::duration(&p.data).unwrap_or(0) as i64;
+                    let trim = self.playback.granule + duration - granule_position;
+                    if trim > duration {
+                        return Err(invalid_data("end trim longer than the last packet"));
+                    }
+                    std::cmp::max(trim, 0) as usize
+                }
+                _ => 0,
+            };
+            let n = self.playback.decode(&p.data, trim, pcm)?;
+            if let Some(granule_position) = p.granule_position {
+                self.playback.granule = granule_position;
+            }
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> OggOpusReader<R> {
+    /// Seek to sample position `sample` of the current link.
+    ///
+    /// The Ogg pages are bisected for the last one completing a packet at least `PRE_ROLL`
+    /// samples before the target, and decoding restarts after it, discarding the output up to
+    /// the target.
+    pub fn seek(&mut self, sample: u64) -> io::Result<()> {
+        let target = std::cmp::max(sample as i64 + self.head.pre_skip as i64,
+                                   self.start_granule + self.head.pre_skip as i64);
+        let pre_roll = target - PRE_ROLL;
+
+        // The start of the audio, or a page known to end before the pre-roll, and its granule
+        // position.
+        let mut best: Option<(u64, i64)> = None;
+        let mut lo = self.data_start;
+        let mut hi = self.packets.pages().stream_len()?;
+        if pre_roll > self.start_granule {
+            while hi - lo > BISECTION_THRESHOLD {
+                let mid = lo + (hi - lo) / 2;
+                match self.find_page(mid, hi)? {
+                    Some((offset, granule)) if granule <= pre_roll => {
+                        best = Some((offset, granule));
+                        lo = offset;
+                    }
+                    _ => hi = mid,
+                }
+            }
+            // Scan the remaining range.
+            let mut offset = lo;
+            while let Some((page_offset, granule)) = self.find_page(offset, hi)? {
+                if granule > pre_roll {
+                    break;
+                }
+                best = Some((page_offset, granule));
+                offset = page_offset + 1;
+            }
+        }
+
+        self.packets.reset();
+        self.pending.clear();
+        self.playback.decoder.reset();
+        self.eos = false;
+        match best {
+            Some((offset, granule)) => {
+                // Read the page again for the packet continued on the next one, dropping the
+                // packets it completes.
+                self.packets.pages().seek(offset)?;
+                let page = self.packets.pages().next_page()?.map(|(page, _)| page);
+                if let Some(page) = page {
+                    self.packets.push_page(&page);
+                    self.packets.drop_completed();
+                    self.eos = page.eos;
+                }
+                self.playback.granule = granule;
+            }
+            None => {
+                self.packets.pages().seek(self.data_start)?;
+                self.playback.granule = self.start_granule;
+            }
+        }
+        self.playback.skip_to = target;
+        self.playback.gap = 0;
+        Ok(())
+    }
+
+    /// First page of the Opus stream completing a packet, starting in `[offset, end)`, with its
+    /// granule position.
+    fn find_page(&mut self, offset: u64, end: u64) -> io::Result<Option<(u64, i64)>> {
+        let pages = self.packets.pages();
+        pages.seek(offset)?;
+        while let Some((page, page_offset)) = pages.next_page()? {
+            if page_offset >= end {
+                break;
+            }
+            if page.serial == self.serial && page.granule_position != -1 {
+                return Ok(Some((page_offset, page.granule_position)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Writer of an Ogg Opus stream.
+///
+/// The headers take a page each. Audio packets follow, each ending at the granule position
+/// given by the durations of the packets so far, counting from 0 at the start of the pre-skip.
+/// The last packet is held back until `finish`, which marks it as the end of the stream.
+pub struct OggOpusWriter<W> {
+    packets: PacketWriter<W>,
+    granule: i64,
+    last: Option<Vec<u8>>,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// Write the headers of a stream with serial number `serial`.
+    pub fn new(writer: W,
+               serial: u32,
+               head: &OpusHead,
+               tags: &OpusTags)
+               -> io::Result<OggOpusWriter<W>> {
+        let mut packets = PacketWriter::new(writer, serial);
+        packets.write_packet(&head.to_bytes(), 0, false)?;
+        packets.flush_page()?;
+        packets.write_packet(&tags.to_bytes(), 0, false)?;
+        packets.flush_page()?;
+        Ok(OggOpusWriter {
+            packets,
+            granule: 0,
+            last: None,
+        })
+    }
+
+    /// Write a packet. Its duration is taken from its TOC byte.
+    pub fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let duration = packet::duration(data)?;
+        if let Some(last) = self.last.take() {
+            self.packets.write_packet(&last, self.granule, false)?;
+        }
+        self.granule += duration as i64;
+        self.last = Some(data.to_vec());
+        Ok(())
+    }
+
+    /// End the stream, trimming `trim` samples from the end of its last packet, and return the
+    /// underlying writer. The trim cannot be longer than the last packet.
+    pub fn finish(mut self, trim: usize) -> io::Result<W> {
+        match self.last.take() {
+            Some(last) => {
+                if trim > packet::duration(&last)? {
+                    return Err(invalid_input("trim longer than the last packet"));
+                }
+                self.packets.write_packet(&last, self.granule - trim as i64, true)?;
+            }
+            None if trim > 0 => return Err(invalid_input("trim without packets")),
+            None => self.packets.end_stream()?,
+        }
+        Ok(self.packets.into_inner())
+    }
+
+    /// Granule position after the packets written so far.
+    pub fn granule_position(&self) -> i64 {
+        self.granule
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    pub fn head(pre_skip: usize, output_gain: i16) -> OpusHead {
+        OpusHead {
+            version: 1,
+            channels: 2,
+            pre_skip,
+            input_sample_rate: 48000,
+            output_gain,
+            mapping_family: 0,
+            streams: 1,
+            coupled_streams: 1,
+            mapping: vec![0, 1],
+            demixing_matrix: vec![],
+        }
+    }
+
+    /// An Ogg Opus stream of `count` packets, with `trim` samples trimmed from the end.
+    pub fn stream(head: &OpusHead, tags: &OpusTags, count: usize, trim: usize) -> Vec<u8> {
+        let mut writer = OggOpusWriter::new(vec![], 0x1234, head, tags).unwrap();
+        for i in 0..count {
+            writer.write_packet(&celt_packet(i)).unwrap();
+        }
+        writer.finish(trim).unwrap()
+    }
+
+    pub fn decode_all<R: Read>(reader: &mut OggOpusReader<R>) -> Vec<f32> {
+        let mut pcm = vec![0.0; reader.channels() * packet::MAX_PACKET_DURATION];
+        let mut output = vec![];
+        loop {
+            let n = reader.read(&mut pcm).unwrap();
+            if n == 0 {
+                return output;
+            }
+            output.extend_from_slice(&pcm[..n * reader.channels()]);
+        }
+    }
+
+    /// The stream `data` without its page `index`, as if lost. The headers take pages 0 and 1.
+    pub fn drop_page(data: &[u8], index: usize) -> Vec<u8> {
+        let mut pages = vec![];
+        let mut pos = 0;
+        while let Some((page, size)) = ogg::Page::parse(&data[pos..]) {
+            pages.push(page);
+            pos += size;
+        }
+        assert_eq!(pos, data.len());
+        pages.remove(index);
+        pages.iter().flat_map(|page| page.to_bytes()).collect()
+    }
+
+    #[test]
+    fn playback_gain() {
+        let mut tags = OpusTags::default();
+        tags.set_track_gain(-1536);
+        let data = stream(&head(312, 1536), &tags, 5, 0);
+        let decode = |playback_gain| {
+            let mut reader = OggOpusReader::new(&data[..]).unwrap();
+            reader.set_playback_gain(playback_gain);
+            (reader.gain(), decode_all(&mut reader))
+        };
+        let (gain, none) = decode(PlaybackGain::None);
+        assert_eq!(gain, 0.0);
+        let (gain, header) = decode(PlaybackGain::Header);
+        assert_eq!(gain, 6.0);
+        let scale = 10f32.powf(6.0 / 20.0);
+        assert_eq!(none.len(), header.len());
+        assert!(none.iter().zip(&header).all(|(&x, &y)| (x * scale - y).abs() < 1e-6));
+        let (gain, track) = decode(PlaybackGain::Track);
+        assert_eq!(gain, 0.0);
+        assert_eq!(track, none);
+        // Without R128_ALBUM_GAIN, the album gain is the output gain alone.
+        assert_eq!(decode(PlaybackGain::Album), (6.0, header));
+    }
+
+    #[test]
+    fn missing_page() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 100, 0);
+        // The pages hold 26 packets each.
+        let damaged = drop_page(&data, 3);
+
+        let complete = decode_all(&mut OggOpusReader::new(&data[..]).unwrap());
+        let mut reader = OggOpusReader::new(&damaged[..]).unwrap();
+        let concealed = decode_all(&mut reader);
+        assert_eq!(concealed.len(), complete.len());
+        assert_eq!(reader.position(), (100 * 960 - 312) as u64);
+        // Up to the lost page, the output is that of the complete stream.
+        let start = 2 * (26 * 960 - 312);
+        let end = start + 2 * 26 * 960;
+        assert_eq!(concealed[..start], complete[..start]);
+        assert_ne!(concealed[start..end], complete[start..end]);
+    }
+
+    #[test]
+    fn pre_skip() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 10, 0);
+        let mut reader = OggOpusReader::new(&data[..]).unwrap();
+        assert_eq!((reader.channels(), reader.position(), reader.link()), (2, 0, 0));
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        assert_eq!(reader.read(&mut pcm).unwrap(), 960 - 312);
+        assert_eq!(reader.position(), 960 - 312);
+        assert_eq!(decode_all(&mut reader).len(), 2 * 9 * 960);
+        assert_eq!(reader.position(), 10 * 960 - 312);
+        assert_eq!(reader.read(&mut pcm).unwrap(), 0);
+        assert_eq!(reader.read(&mut pcm[..100]).unwrap(), 0);
+
+        // A pre-skip longer than a packet.
+        let data = stream(&head(3000, 0), &OpusTags::default(), 10, 0);
+        let mut reader = OggOpusReader::new(&data[..]).unwrap();
+        assert_eq!(reader.read(&mut pcm).unwrap(), 4 * 960 - 3000);
+        let err = reader.read(&mut pcm[..100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn seek() {
+        // Long enough for the bisection, with pages of 26 packets.
+        let data = stream(&head(312, 0), &OpusTags::default(), 500, 0);
+        assert!(data.len() as u64 > BISECTION_THRESHOLD);
+        let mut reader = OggOpusReader::new(io::Cursor::new(&data[..])).unwrap();
+        let mut complete = vec![];
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        while complete.len() < 2 * 300 * 960 {
+            let n = reader.read(&mut pcm).unwrap();
+            complete.extend_from_slice(&pcm[..2 * n]);
+        }
+
+        let mut read_at = |sample: u64, len: usize| {
+            reader.seek(sample).unwrap();
+            assert_eq!(reader.position(), sample);
+            let mut output = vec![];
+            while output.len() < 2 * len {
+                let n = reader.read(&mut pcm).unwrap();
+                output.extend_from_slice(&pcm[..2 * n]);
+            }
+            output.truncate(2 * len);
+            output
+        };
+        // Within the pre-roll of the start, decoding starts over.
+        assert_eq!(read_at(0, 5000), complete[..2 * 5000]);
+        assert_eq!(read_at(3000, 5000), complete[2 * 3000..2 * 8000]);
+        // Further on, the decoder converges over the pre-roll, from a page before.
+        for &sample in &[250000, 100000, 12345] {
+            let output = read_at(sample, 5000);
+            let start = 2 * sample as usize;
+            let error = output.iter()
+                .zip(&complete[start..start + 2 * 5000])
+                .map(|(x, y)| (x - y).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 0.02);
+        }
+        // Past the end, there is nothing left to read.
+        reader.seek(600 * 960).unwrap();
+        assert_eq!(reader.read(&mut pcm).unwrap(), 0);
+    }
+
+    #[test]
+    fn chained() {
+        let a = stream(&head(312, 0), &OpusTags::default(), 10, 500);
+        let mut mono = head(0, 0);
+        mono.channels = 1;
+        mono.coupled_streams = 0;
+        mono.mapping = vec![0];
+        let mut writer = OggOpusWriter::new(vec![], 0x5678, &mono, &OpusTags::default()).unwrap();
+        for i in 0..5 {
+            writer.write_packet(&celt_packet(i)).unwrap();
+        }
+        let b = writer.finish(100).unwrap();
+
+        let first = decode_all(&mut OggOpusReader::new(&a[..]).unwrap());
+        assert_eq!(first.len(), 2 * (10 * 960 - 312 - 500));
+        let second = decode_all(&mut OggOpusReader::new(&b[..]).unwrap());
+        assert_eq!(second.len(), 5 * 960 - 100);
+
+        let mut data = a.clone();
+        data.extend_from_slice(&b);
+        let mut reader = OggOpusReader::new(&data[..]).unwrap();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        let mut output = vec![];
+        while reader.link() == 0 {
+            let n = reader.read(&mut pcm).unwrap();
+            output.extend_from_slice(&pcm[..n * reader.channels()]);
+        }
+        // The read moving on to the second link returns its first packet.
+        assert_eq!(output, [&first[..], &second[..960]].concat());
+        assert_eq!((reader.serial(), reader.channels()), (0x5678, 1));
+        assert_eq!(decode_all(&mut reader)[..], second[960..]);
+        assert_eq!(reader.position(), 5 * 960 - 100);
+    }
+
+    #[test]
+    fn writer() {
+        let head = head(312, 0);
+        let mut tags = OpusTags::default();
+        tags.set("TITLE", "Test");
+        let mut writer = OggOpusWriter::new(vec![], 7, &head, &tags).unwrap();
+        for i in 0..30 {
+            writer.write_packet(&celt_packet(i)).unwrap();
+        }
+        assert_eq!(writer.granule_position(), 30 * 960);
+        assert!(writer.write_packet(&[]).is_err());
+        let data = writer.finish(200).unwrap();
+
+        let mut pages = ogg::PageReader::new(&data[..]);
+        let mut next_page = || pages.next_page().unwrap().map(|(page, _)| page);
+        // The headers take a page each.
+        let page = next_page().unwrap();
+        assert_eq!((page.bos, page.serial, page.body.clone()), (true, 7, head.to_bytes()));
+        assert_eq!(next_page().unwrap().body, tags.to_bytes());
+        // Then full pages of packets, and the last one trimmed.
+        let page = next_page().unwrap();
+        assert_eq!((page.packets(), page.granule_position, page.eos), (26, 26 * 960, false));
+        let page = next_page().unwrap();
+        assert_eq!((page.packets(), page.granule_position, page.eos), (4, 30 * 960 - 200, true));
+        assert_eq!(next_page(), None);
+
+        let reader = OggOpusReader::new(&data[..]).unwrap();
+        assert_eq!((reader.serial(), &reader.head, &reader.tags), (7, &head, &tags));
+
+        // A stream without packets still ends.
+        let data = OggOpusWriter::new(vec![], 7, &head, &tags).unwrap().finish(0).unwrap();
+        let mut pages = ogg::PageReader::new(&data[..]);
+        let last = std::iter::from_fn(|| pages.next_page().unwrap()).last().unwrap().0;
+        assert!(last.eos && last.segments.is_empty());
+        assert_eq!(decode_all(&mut OggOpusReader::new(&data[..]).unwrap()), []);
+
+        // The trim is at most the last packet.
+        let mut writer = OggOpusWriter::new(vec![], 7, &head, &tags).unwrap();
+        writer.write_packet(&celt_packet(0)).unwrap();
+        writer.write_packet(&celt_packet(1)).unwrap();
+        let err = writer.finish(961).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let writer = OggOpusWriter::new(vec![], 7, &head, &tags).unwrap();
+        assert_eq!(writer.finish(1).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn end_trim_past_last_packet() {
+        // The granule position of the last page ends within its first packet.
+        let mut packets = PacketWriter::new(vec![], 7);
+        packets.write_packet(&head(312, 0).to_bytes(), 0, false).unwrap();
+        packets.flush_page().unwrap();
+        packets.write_packet(&OpusTags::default().to_bytes(), 0, false).unwrap();
+        packets.flush_page().unwrap();
+        packets.write_packet(&celt_packet(0), 960, false).unwrap();
+        packets.flush_page().unwrap();
+        packets.write_packet(&celt_packet(1), 0, false).unwrap();
+        packets.write_packet(&celt_packet(2), 1500, true).unwrap();
+        let data = packets.into_inner();
+        let mut reader = OggOpusReader::new(&data[..]).unwrap();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        assert_eq!(reader.read(&mut pcm).unwrap(), 960 - 312);
+        assert_eq!(reader.read(&mut pcm).unwrap(), 960);
+        assert_eq!(reader.read(&mut pcm).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -3,8 +3,11 @@ use bands;
 use consts;
 use denormalise_bands;
 use entdec;
+use error::Error;
 use mdct;
 use mode;
+use packet;
+use plc;
 use quant_bands;
 use rate;
 use std;
@@ -15,16 +18,59 @@ const GAINS: [[f32; 3]; 3] = [[0.3066406250, 0.2170410156, 0.1296386719],
                               [0.4638671875, 0.2680664062, 0.0],
                               [0.7998046875, 0.1000976562, 0.0]];
 
-pub struct OpusDecoder<'a> {
+pub struct OpusDecoder {
     pub mode: mode::CeltMode,
+    /// Number of output channels, 1 or 2. Mono and stereo streams are both decoded to it.
+    pub channels: usize,
+    /// State of the range decoder after the last frame, or 0 after a DTX frame or a lost
+    /// packet, to check against the final range of the encoder.
     pub range: u32,
+    /// Seed of the random generator of the band folding, the anti-collapse and the concealment.
+    pub seed: u32,
     pub pitch: usize,
     pub gain: f32,
     pub tapset: usize,
     pub preemph_mem: [f32; 2],
     pub decode_mem: [Vec<f32>; 2],
     pub bands: [f32; consts::NUM_BANDS * 6],
-    pub ec: entdec::EntropyCoder<'a>,
+    pub loss_count: usize,
+    pub plc_pitch: usize,
+    pub plc_voiced: bool,
+    pub plc_lpc: [[f32; plc::LPC_ORDER]; 2],
+    pub plc_energy: f32,
+    /// Estimate of the background noise energy in each band, the floor of the comfort noise.
+    pub background_bands: [f32; consts::NUM_BANDS * 2],
+    /// Whether the last frame was a DTX frame.
+    pub in_dtx: bool,
+    /// Gain applied to the decoded samples, in Q7.8 dB, kept across a reset.
+    pub decode_gain: i16,
+}
+
+impl OpusDecoder {
+    /// Reset the decoder state, as after a discontinuity in the stream.
+    pub fn reset(&mut self) {
+        self.range = 0;
+        self.seed = 0;
+        self.pitch = 0;
+        self.gain = 0.0;
+        self.tapset = 0;
+        self.preemph_mem = [0.0; 2];
+        for mem in self.decode_mem.iter_mut() {
+            for x in mem.iter_mut() {
+                *x = 0.0;
+            }
+        }
+        for (i, e) in self.bands.iter_mut().enumerate() {
+            *e = if i < 2 * consts::NUM_BANDS { 0.0 } else { -28.0 };
+        }
+        self.loss_count = 0;
+        self.plc_pitch = 0;
+        self.plc_voiced = false;
+        self.plc_lpc = [[0.0; plc::LPC_ORDER]; 2];
+        self.plc_energy = 0.0;
+        self.background_bands = [0.0; consts::NUM_BANDS * 2];
+        self.in_dtx = false;
+    }
 }
 
 pub fn comb_filter_old(window: &[f32], x: &mut [f32], pitch: usize, gain: f32, tapset: usize) {
@@ -56,20 +102,46 @@ pub fn comb_filter_const(x: &mut [f32], n: usize, pitch: usize, gain: f32, tapse
     }
 }
 
-fn deemphasis(x: &[Vec<f32>], pcm: &mut [f32], mem: &mut [f32]) {
-    for c in 0..consts::NUM_CHANNELS as usize {
+/// Inverse of `comb_filter_const`, from `x` into `y`.
+pub fn comb_filter_inverse(x: &[f32], y: &mut [f32], pitch: usize, gain: f32, tapset: usize) {
+    for i in 0..y.len() {
+        y[i] = x[i + pitch + 2] - gain *
+                                  (GAINS[tapset][0] * x[i + 2] +
+                                   GAINS[tapset][1] * (x[i + 3] + x[i + 1]) +
+                                   GAINS[tapset][2] * (x[i + 4] + x[i]));
+    }
+}
+
+/// Deemphasis of the last frame of each channel of `x`, interleaved into `pcm`.
+pub fn deemphasis(x: &[Vec<f32>], pcm: &mut [f32], mem: &mut [f32]) {
+    for c in 0..x.len() {
         for i in 0..consts::FRAME_SIZE {
             mem[c] += x[c][BUFFER_SIZE - consts::FRAME_SIZE + i];
-            pcm[2 * i + c] = mem[c] / 32768.0;
+            pcm[x.len() * i + c] = mem[c] / 32768.0;
             mem[c] *= consts::PRE_EMPHASIS;
         }
     }
 }
 
-unsafe fn celt_synthesis(st: &mut OpusDecoder, x: &mut [f32], is_transient: bool) {
-    for c in 0..2 {
+/// # Safety
+///
+/// `x` holds the normalised spectrum of the `channels` coded channels, 960 bins each, with room
+/// for two channels.
+pub unsafe fn celt_synthesis(st: &mut OpusDecoder, x: &mut [f32], channels: usize, is_transient: bool) {
+    for c in 0..channels {
         denormalise_bands::denormalise_bands(&mut x[960 * c..],
                                              &st.bands[21 * c..21 * (c + 1)]);
+    }
+    if channels == 1 && st.channels == 2 {
+        // A mono stream is played on both channels.
+        x.copy_within(..960, 960);
+    } else if channels == 2 && st.channels == 1 {
+        // A stereo stream is downmixed.
+        for i in 0..960 {
+            x[i] = 0.5 * (x[i] + x[960 + i]);
+        }
+    }
+    for c in 0..st.channels {
         let shift = if is_transient { 3 } else { 0 };
         for b in 0..1 << shift {
             mdct::mdct_backward(&st.mode,
@@ -90,21 +162,28 @@ pub fn tf_decode(is_transient: bool, tf_res: &mut [i32], ec: &mut entdec::Entrop
 
     let mut tf_changed = 0;
     let mut logp = if is_transient { 2 } else { 4 };
+    let mut budget = ec.buffer.len() * 8;
+    // A bit is reserved for tf_select, if there is room for it.
+    let tf_select_rsv = ec.tell() + logp < budget;
+    if tf_select_rsv {
+        budget -= 1;
+    }
     tf_res[0] = 0;
-    if ec.tell() + logp <= ec.buffer.len() * 8 {
+    if ec.tell() + logp <= budget {
         tf_res[0] = ec.decode_bit_logp(logp as u32) as i32;
         tf_changed = tf_res[0];
     }
     logp = if is_transient { 4 } else { 5 };
     for i in 1..tf_res.len() {
         tf_res[i] = tf_res[i - 1];
-        if ec.tell() + logp <= ec.buffer.len() * 8 {
+        if ec.tell() + logp <= budget {
             tf_res[i] ^= ec.decode_bit_logp(logp as u32) as i32;
             tf_changed |= tf_res[i];
         }
     }
     let mut tf_select = 0;
-    if TF_SELECT_TABLE[if is_transient { 1 } else { 0 }][tf_changed as usize] !=
+    if tf_select_rsv &&
+       TF_SELECT_TABLE[if is_transient { 1 } else { 0 }][tf_changed as usize] !=
        TF_SELECT_TABLE[if is_transient { 1 } else { 0 }][2 + tf_changed as usize] {
         tf_select = ec.decode_bit_logp(1) as usize;
     }
@@ -124,31 +203,40 @@ pub fn decode_post_filter_params(total_bits: usize,
         *pitch = ((16 << octave) + pitch_in_octave) as usize - 1;
         *gain = 3.0 * (ec.decode_bits(3) + 1) as f32 / 32.0;
         const TAPSET_ICDF: [u8; 3] = [2, 1, 0];
-        *tapset = ec.decode_icdf(&TAPSET_ICDF, 2) as usize;
+        if ec.tell() + 2 <= total_bits {
+            *tapset = ec.decode_icdf(&TAPSET_ICDF, 2) as usize;
+        }
     }
 }
 
 /// Decode the band boosts
 ///
 /// First, set 'dynalloc_logp' to 6, the initial amount of storage required to signal a boost in bits, 'total_bits' to the size of the frame in 8th bits, 'total_boost' to zero, and 'tell' to the total number of 8th bits decoded so far. For each band from the coding start (0 normally, but 17 in Hybrid mode) to the coding end (which changes depending on the signaled bandwidth), the boost quanta in units of 1/8 bit is calculated as quanta = min(8 * N, max(48, N)). This represents a boost step size of six bits, subject to a lower limit of 1/8th bit/sample and an upper limit of 1 bit/sample. Set 'boost' to zero and 'dynalloc_loop_logp' to dynalloc_logp. While dynalloc_loop_log (the current worst case symbol cost) in 8th bits plus tell is less than total_bits plus total_boost and boost is less than cap[] for this band: Decode a bit from the bitstream with dynalloc_loop_logp as the cost of a one and update tell to reflect the current used capacity. If the decoded value is zero break the loop. Otherwise, add quanta to boost and total_boost, subtract quanta from total_bits, and set dynalloc_loop_log to 1. When the loop finishes 'boost' contains the bit allocation boost for this band. If boost is non-zero and dynalloc_logp is greater than 2, decrease dynalloc_logp. Once this process has been executed on all bands, the band boosts have been decoded.
+///
+/// The boost of a band is also bounded by its cap.
 fn decode_band_boosts(length: usize,
+                      channels: usize,
                       ec: &mut entdec::EntropyCoder,
                       boosts: &mut [i32]) -> usize {
+    let caps = rate::init_caps(channels);
     let mut dynalloc_logp = 6;
+    let mut total_bits = length * 8 * 8;
     let mut total_boost = 0;
     let mut tell = ec.tell_frac();
     for i in 0..boosts.len() {
-        let width = 2 * consts::BAND_WIDTHS[i] * 8;
+        let width = channels * consts::BAND_WIDTHS[i] * 8;
         let quanta = std::cmp::min(8 * width, std::cmp::max(48, width));
         let mut boost = 0;
         let mut dynalloc_loop_logp = dynalloc_logp;
-        while dynalloc_loop_logp * 8 + tell < length * 8 * 8 {
-            if ec.decode_bit_logp(dynalloc_loop_logp as u32) == 0 {
+        while dynalloc_loop_logp * 8 + tell < total_bits && (boost as i32) < caps[i] {
+            let flag = ec.decode_bit_logp(dynalloc_loop_logp as u32);
+            tell = ec.tell_frac();
+            if flag == 0 {
                 break;
             }
-            tell = ec.tell_frac();
             boost += quanta;
             total_boost += quanta;
+            total_bits -= quanta;
             dynalloc_loop_logp = 1;
         }
         boosts[i] = boost as i32;
@@ -159,66 +247,87 @@ fn decode_band_boosts(length: usize,
     return total_boost;
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn opus_decoder_decode(st: &mut OpusDecoder,
-                                             data_ptr: *const u8,
-                                             mut length: usize,
-                                             pcm_ptr: *mut f32)
-                                             -> usize {
-    length -= 1;
-    st.ec.init(std::slice::from_raw_parts(data_ptr.offset(1), length));
+/// Decode a CELT frame of a stream with `channels` coded channels.
+unsafe fn celt_decode_frame(st: &mut OpusDecoder, data: &[u8], channels: usize, pcm: &mut [f32]) {
+    let length = data.len();
+    let mut ec: entdec::EntropyCoder = Default::default();
+    ec.init(data);
+
+    let is_silence = ec.decode_bit_logp(15) == 1;
+    if is_silence {
+        // Pretend all the remaining bits were read.
+        ec.nbits_total += length * 8 - ec.tell();
+    }
 
-    let is_silence = st.ec.decode_bit_logp(15) == 1;
 
     let mut pitch: usize = 0;
     let mut tapset: usize = 0;
     let mut gain = 0.0;
-    decode_post_filter_params(length * 8, &mut pitch, &mut tapset, &mut gain, &mut st.ec);
+    decode_post_filter_params(length * 8, &mut pitch, &mut tapset, &mut gain, &mut ec);
+
+
+    let is_transient = ec.tell() + 3 <= length * 8 && ec.decode_bit_logp(3) == 1;
 
-    let is_transient = st.ec.tell() + 3 <= length * 8 && st.ec.decode_bit_logp(3) == 1;
+    let intra = ec.tell() + 3 <= length * 8 && ec.decode_bit_logp(3) == 1;
 
-    let intra = st.ec.tell() + 3 <= length * 8 && st.ec.decode_bit_logp(3) == 1;
 
-    quant_bands::unquant_coarse_energy(&mut st.bands[..2 * 21], intra, &mut st.ec);
+    if channels == 1 {
+        // The prediction of a mono frame after a stereo one starts from the louder channel.
+        for i in 0..21 {
+            st.bands[i] = st.bands[i].max(st.bands[21 + i]);
+        }
+    }
+    quant_bands::unquant_coarse_energy(&mut st.bands[..2 * 21], channels, intra, &mut ec);
+
 
     let mut tf_res = vec![0 as i32; 21];
-    tf_decode(is_transient, &mut tf_res, &mut st.ec);
+    tf_decode(is_transient, &mut tf_res, &mut ec);
+
 
     const SPREAD_ICDF: [u8; 4] = [25, 23, 2, 0];
     let mut spread: i32 = bands::SPREAD_NORMAL;
-    if st.ec.tell() + 4 <= length * 8 {
-        spread = st.ec.decode_icdf(&SPREAD_ICDF, 5) as i32;
+    if ec.tell() + 4 <= length * 8 {
+        spread = ec.decode_icdf(&SPREAD_ICDF, 5) as i32;
     }
 
+
     let mut boosts = vec![0; 21];
-    let total_boost = decode_band_boosts(length, &mut st.ec, &mut boosts);
+    let total_boost = decode_band_boosts(length, channels, &mut ec, &mut boosts);
 
     // The allocation trim is an integer value from 0-10. The default value of 5 indicates no trim. The trim parameter is entropy coded in order to lower the coding cost of less extreme adjustments. Values lower than 5 bias the allocation towards lower frequencies and values above 5 bias it towards higher frequencies. Like other signaled parameters, signaling of the trim is gated so that it is not included if there is insufficient space available in the bitstream. To decode the trim, first set the trim value to 5, then if and only if the count of decoded 8th bits so far (ec.tell_frac) plus 48 (6 bits) is less than or equal to the total frame size in 8th bits minus total_boost (a product of the above band boost procedure), decode the trim value using the PDF in Table 58.
     const TRIM_ICDF: [u8; 11] = [126, 124, 119, 109, 87, 41, 19, 9, 4, 2, 0];
     let mut allocation_trim = 5;
-    if st.ec.tell_frac() + 6 * 8 <= length * 8 * 8 - total_boost {
-        allocation_trim = st.ec.decode_icdf(&TRIM_ICDF, 7) as i32;
+    if ec.tell_frac() + 6 * 8 <= length * 8 * 8 - total_boost {
+        allocation_trim = ec.decode_icdf(&TRIM_ICDF, 7) as i32;
     }
 
+
+    // An 8th bit is reserved for the anti-collapse flag of a transient frame, if there is room.
+    let mut bits = (length * 8 * 8) as i32 - ec.tell_frac() as i32 - 1;
+    let anti_collapse_rsv = if is_transient && bits >= (3 + 2) * 8 { 8 } else { 0 };
+    bits -= anti_collapse_rsv;
+
     let mut intensity = 0;
     let mut is_dual_stereo = false;
     let mut pulses = vec![0; 21];
     let mut fine_quant = vec![0; 21];
     let mut fine_priority = vec![0; 21];
-    let coded_bands = rate::compute_allocation(&mut boosts,
-                                               allocation_trim,
-                                               &mut intensity,
-                                               &mut is_dual_stereo,
-                                               length,
-                                               is_transient,
-                                               &mut pulses,
-                                               &mut fine_quant,
-                                               &mut fine_priority,
-                                               &mut st.ec);
-
-    quant_bands::unquant_fine_energy(&mut st.bands[..2 * 21], &fine_quant, &mut st.ec);
-
-    for c in 0..2 {
+    let (coded_bands, balance) = rate::compute_allocation(channels,
+                                                          &boosts,
+                                                          allocation_trim,
+                                                          &mut intensity,
+                                                          &mut is_dual_stereo,
+                                                          bits,
+                                                          &mut pulses,
+                                                          &mut fine_quant,
+                                                          &mut fine_priority,
+                                                          &mut ec);
+
+
+    quant_bands::unquant_fine_energy(&mut st.bands[..2 * 21], channels, &fine_quant, &mut ec);
+
+
+    for c in 0..st.channels {
         for i in 0..BUFFER_SIZE - 960 + 120 / 2 {
             st.decode_mem[c][i] = st.decode_mem[c][i + 960];
         }
@@ -229,8 +338,9 @@ pub unsafe extern "C" fn opus_decoder_decode(st: &mut OpusDecoder,
     {
         let (x, y) = x_y.split_at_mut(960);
         bands::quant_all_bands(st,
+                               &mut ec,
                                x,
-                               y,
+                               if channels == 2 { y } else { &mut [] },
                                &mut collapse_masks,
                                &pulses,
                                is_transient,
@@ -238,36 +348,40 @@ pub unsafe extern "C" fn opus_decoder_decode(st: &mut OpusDecoder,
                                is_dual_stereo,
                                intensity,
                                &tf_res,
-                               length * 8 * 8 - if is_transient { 8 } else { 0 },
+                               length * 8 * 8 - anti_collapse_rsv as usize,
+                               balance,
                                coded_bands);
     }
 
-    let is_anti_collapse = is_transient && st.ec.decode_bits(1) == 1;
+
+    let is_anti_collapse = anti_collapse_rsv > 0 && ec.decode_bits(1) == 1;
     quant_bands::unquant_energy_finalise(&mut st.bands[..2 * 21],
+                                         channels,
                                          &fine_quant,
                                          &fine_priority,
-                                         length as i32 * 8 - st.ec.tell() as i32,
-                                         &mut st.ec);
+                                         length as i32 * 8 - ec.tell() as i32,
+                                         &mut ec);
+
 
     if is_anti_collapse {
-        anti_collapse::anti_collapse(&mut x_y,
+        anti_collapse::anti_collapse(&mut x_y[..960 * channels],
                                      &collapse_masks,
                                      &st.bands[..2 * 21],
                                      &st.bands[2 * 21..4 * 21],
                                      &st.bands[4 * 21..6 * 21],
                                      &pulses,
-                                     st.range);
+                                     st.seed);
     }
 
     if is_silence {
-        for i in 0..2 * 21 {
+        for i in 0..channels * 21 {
             st.bands[i] = -28.0;
         }
     }
 
-    celt_synthesis(st, &mut x_y, is_transient);
+    celt_synthesis(st, &mut x_y, channels, is_transient);
 
-    for c in 0..2 {
+    for c in 0..st.channels {
         comb_filter_const(&mut st.decode_mem[c][BUFFER_SIZE - 960 - st.pitch - 2..], 120,
                           st.pitch, st.gain, st.tapset);
         comb_filter_old(&st.mode.window,
@@ -283,16 +397,204 @@ pub unsafe extern "C" fn opus_decoder_decode(st: &mut OpusDecoder,
     st.gain = gain;
     st.tapset = tapset;
 
+    if channels == 1 {
+        for i in 0..21 {
+            st.bands[21 + i] = st.bands[i];
+        }
+    }
+
     if !is_transient {
         for i in 0..2 * 21 {
             st.bands[4 * 21 + i] = st.bands[2 * 21 + i];
             st.bands[2 * 21 + i] = st.bands[i];
         }
     }
-    st.range = st.ec.range;
 
-    let pcm = std::slice::from_raw_parts_mut(pcm_ptr, 960 * 2);
-    deemphasis(&st.decode_mem, pcm, &mut st.preemph_mem);
+    // The noise floor may only increase by up to 2.4 dB/second, but after a long loss or DTX
+    // period the whole gap counts towards the update.
+    let max_background_increase = if st.loss_count < 10 { 0.008 } else { 1.0 };
+    for i in 0..2 * 21 {
+        st.background_bands[i] = st.bands[i].min(st.background_bands[i] + max_background_increase);
+    }
+    st.range = ec.range;
+    st.seed = ec.range;
+    st.in_dtx = false;
+
+    deemphasis(&st.decode_mem[..st.channels], pcm, &mut st.preemph_mem);
+    if st.loss_count > 0 {
+        plc::glue_frames(st, pcm);
+        st.loss_count = 0;
+    }
+}
+
+impl OpusDecoder {
+    /// Decode a packet into `pcm`, with `channels` interleaved channels, or conceal a lost packet when `data`
+    /// is `None`. Returns the number of samples per channel.
+    ///
+    /// There is no in-band FEC: it is coded in the SILK layer, which this decoder lacks. A lost
+    /// packet is always concealed.
+    pub fn decode(&mut self, data: Option<&[u8]>, pcm: &mut [f32]) -> Result<usize, Error> {
+        match data {
+            Some(data) if !data.is_empty() => self.decode_packet(&packet::parse(data, false)?, pcm),
+            _ => self.decode_lost(consts::FRAME_SIZE, pcm),
+        }
+    }
+
+    /// Decode a packet already split into frames.
+    ///
+    /// Only CELT-only frames of 20 ms can be decoded. SILK and Hybrid frames need the SILK layer,
+    /// and so do the CELT redundancy frames they carry at mode switches, which are 5 ms long.
+    /// Neither is implemented, and such packets return `Error::Unimplemented`, unless all their
+    /// frames are DTX frames. Frames shorter than 20 ms are not implemented either.
+    pub fn decode_packet(&mut self, packet: &packet::Packet, pcm: &mut [f32]) -> Result<usize, Error> {
+        self.check_packet(packet)?;
+        let frame_size = packet.toc.frame_size();
+        let n = self.channels * consts::FRAME_SIZE;
+        if pcm.len() < self.channels * packet.duration() {
+            return Err(Error::BufferTooSmall);
+        }
+        let chunks = pcm.chunks_mut(self.channels * frame_size);
+        for (frame, pcm) in packet.frames.iter().zip(chunks) {
+            if frame.len() <= 1 {
+                // A frame of at most one byte is a DTX frame, sent instead of the silent frames
+                // of a discontinuous transmission. Comfort noise is played until normal frames
+                // resume.
+                self.in_dtx = true;
+                for pcm in pcm.chunks_mut(n) {
+                    unsafe { plc::celt_decode_lost(self, pcm) };
+                }
+                self.range = 0;
+                continue;
+            }
+            unsafe { celt_decode_frame(self, frame, packet.toc.channels(), pcm) };
+        }
+        self.apply_gain(&mut pcm[..self.channels * packet.duration()]);
+        Ok(packet.duration())
+    }
+
+    /// Check that `decode_packet` can decode `packet`, without changing the state. Returns the
+    /// error it would return otherwise, apart from a buffer too small.
+    pub fn check_packet(&self, packet: &packet::Packet) -> Result<(), Error> {
+        let celt = packet.toc.mode() == packet::Mode::CeltOnly;
+        let dtx = packet.frames.iter().all(|frame| frame.len() <= 1);
+        if packet.toc.frame_size() < consts::FRAME_SIZE || !(celt || dtx) {
+            return Err(Error::Unimplemented);
+        }
+        Ok(())
+    }
+
+    /// Conceal `duration` samples per channel, in whole 20 ms frames.
+    pub fn decode_lost(&mut self, duration: usize, pcm: &mut [f32]) -> Result<usize, Error> {
+        let n = self.channels * consts::FRAME_SIZE;
+        let count = std::cmp::max(1, duration.div_ceil(consts::FRAME_SIZE));
+        if pcm.len() < n * count {
+            return Err(Error::BufferTooSmall);
+        }
+        for pcm in pcm.chunks_mut(n).take(count) {
+            unsafe { plc::celt_decode_lost(self, pcm) };
+        }
+        self.range = 0;
+        self.apply_gain(&mut pcm[..n * count]);
+        Ok(consts::FRAME_SIZE * count)
+    }
+
+    /// Gain applied to the decoded samples, in Q7.8 dB, as `OPUS_SET_GAIN` of libopus.
+    pub fn set_gain(&mut self, gain: i16) {
+        self.decode_gain = gain;
+    }
+
+    fn apply_gain(&self, pcm: &mut [f32]) {
+        if self.decode_gain != 0 {
+            let scale = 10f32.powf(self.decode_gain as f32 / (20.0 * 256.0));
+            for s in pcm {
+                *s *= scale;
+            }
+        }
+    }
+}
+
+/// The interleaved output buffer passed through the C API, sized for `duration` samples of
+/// `channels` channels.
+unsafe fn output<'b>(pcm_ptr: *mut f32, channels: usize, duration: usize) -> &'b mut [f32] {
+    std::slice::from_raw_parts_mut(pcm_ptr, channels * duration)
+}
 
-    return 960;
+/// # Safety
+///
+/// `data_ptr` points to `length` bytes, or is NULL for a lost packet. `pcm_ptr` has room for the
+/// decoded samples, up to 120 ms of interleaved stereo.
+#[no_mangle]
+pub unsafe extern "C" fn opus_decoder_decode(st: &mut OpusDecoder,
+                                             data_ptr: *const u8,
+                                             length: usize,
+                                             pcm_ptr: *mut f32)
+                                             -> i32 {
+    let result = if data_ptr.is_null() || length == 0 {
+        st.decode(None, output(pcm_ptr, st.channels, consts::FRAME_SIZE))
+    } else {
+        let data = std::slice::from_raw_parts(data_ptr, length);
+        packet::duration(data).and_then(|duration| st.decode(Some(data), output(pcm_ptr, st.channels, duration)))
+    };
+    match result {
+        Ok(n) => n as i32,
+        Err(err) => err.code(),
+    }
+}
+
+/// Whether the last decoded frame was a DTX frame, or a gap during DTX.
+///
+/// # Safety
+///
+/// `st` was returned by `opus_decoder_create`.
+#[no_mangle]
+pub unsafe extern "C" fn opus_decoder_get_in_dtx(st: &OpusDecoder) -> i32 {
+    st.in_dtx as i32
+}
+
+/// Reset the decoder state.
+///
+/// # Safety
+///
+/// `st` was returned by `opus_decoder_create`.
+#[no_mangle]
+pub unsafe extern "C" fn opus_decoder_reset(st: &mut OpusDecoder) {
+    st.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    #[test]
+    fn unimplemented_frames() {
+        let mut st = OpusDecoder::new();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        // SILK, Hybrid, and CELT frames of 10 ms.
+        for &toc in &[0x0c, 0x6c, 0xf4] {
+            let mut data = celt_packet(0);
+            data[0] = toc;
+            assert_eq!(st.decode(Some(&data), &mut pcm), Err(Error::Unimplemented));
+        }
+        // DTX frames of any mode are played as comfort noise.
+        assert_eq!(st.decode(Some(&[0x0c]), &mut pcm), Ok(960));
+        assert!(st.in_dtx);
+        assert_eq!(st.decode(Some(&celt_packet(0)), &mut pcm), Ok(960));
+    }
+
+    #[test]
+    fn gain() {
+        let mut expected = vec![0.0; 2 * 960];
+        OpusDecoder::new().decode(Some(&celt_packet(0)), &mut expected).unwrap();
+        let mut st = OpusDecoder::new();
+        st.set_gain(-6 * 256);
+        // The gain is kept across a reset, as in libopus.
+        st.reset();
+        let mut pcm = vec![0.0; 2 * 960];
+        st.decode(Some(&celt_packet(0)), &mut pcm).unwrap();
+        let scale = 10f32.powf(-6.0 / 20.0);
+        for (x, y) in pcm.iter().zip(expected) {
+            assert!((x - y * scale).abs() < 1e-6);
+        }
+    }
 }
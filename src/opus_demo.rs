@@ -0,0 +1,225 @@
+use opus_decoder::OpusDecoder;
+use packet;
+use std;
+use std::io::{self, Read};
+
+/// A packet of an `opus_demo` bitstream, as written by `opus_demo -e` and found in the
+/// conformance test vectors.
+///
+///      0                   1                   2                   3
+///      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                   payload length (big-endian)                 |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |              encoder final range (big-endian)                 |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     :                           payload ...                         :
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///
+/// An empty payload is a lost packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemoPacket {
+    pub data: Vec<u8>,
+    /// State of the range coder of the encoder after the packet.
+    pub final_range: u32,
+}
+
+/// Reader of an `opus_demo` bitstream.
+pub struct DemoReader<R> {
+    reader: R,
+}
+
+impl<R: Read> DemoReader<R> {
+    pub fn new(reader: R) -> DemoReader<R> {
+        DemoReader { reader }
+    }
+
+    /// Read the next packet. Returns `None` at the end of the bitstream.
+    pub fn next_packet(&mut self) -> io::Result<Option<DemoPacket>> {
+        let mut header = [0; 8];
+        let mut n = 0;
+        while n < header.len() {
+            match self.reader.read(&mut header[n..]) {
+                Ok(0) if n == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => n += len,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > packet::MAX_FRAME_BYTES * 48 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid payload length"));
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(DemoPacket {
+            data,
+            final_range: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for DemoReader<R> {
+    type Item = io::Result<DemoPacket>;
+
+    fn next(&mut self) -> Option<io::Result<DemoPacket>> {
+        self.next_packet().transpose()
+    }
+}
+
+/// First packet whose final range differs between the encoder and the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index of the packet, counting from 0.
+    pub packet: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "final range mismatch in packet {}: encoder 0x{:08x}, decoder 0x{:08x}",
+               self.packet,
+               self.expected,
+               self.actual)
+    }
+}
+
+/// Decode an `opus_demo` bitstream, checking the final range of the decoder against that of
+/// the encoder after every packet but the lost ones, as `opus_demo -d` does. The decoded
+/// interleaved samples of each packet are passed to `output`.
+///
+/// DTX packets are checked like the others: both the encoder and the decoder leave a final range
+/// of 0 after them.
+///
+/// Returns the number of packets, or the first mismatch. The decoding stops at a packet which
+/// cannot be decoded, which is reported as a mismatch with a decoder range of 0.
+pub fn verify<R, F>(reader: R,
+                    decoder: &mut OpusDecoder,
+                    mut output: F)
+                    -> io::Result<Result<usize, Mismatch>>
+    where R: Read,
+          F: FnMut(&[f32])
+{
+    let mut pcm = vec![0.0; decoder.channels * packet::MAX_PACKET_DURATION];
+    let mut count = 0;
+    for packet in DemoReader::new(reader) {
+        let packet = packet?;
+        let lost = packet.data.is_empty();
+        let decoded = if lost {
+            decoder.decode(None, &mut pcm)
+        } else {
+            decoder.decode(Some(&packet.data), &mut pcm)
+        };
+        let n = match decoded {
+            Ok(n) => n,
+            Err(_) => {
+                return Ok(Err(Mismatch {
+                    packet: count,
+                    expected: packet.final_range,
+                    actual: 0,
+                }))
+            }
+        };
+        if !lost && decoder.range != packet.final_range {
+            return Ok(Err(Mismatch {
+                packet: count,
+                expected: packet.final_range,
+                actual: decoder.range,
+            }));
+        }
+        output(&pcm[..decoder.channels * n]);
+        count += 1;
+    }
+    Ok(Ok(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    /// Bitstream of `(payload, final range)` pairs.
+    fn bitstream(packets: &[(Vec<u8>, u32)]) -> Vec<u8> {
+        let mut data = vec![];
+        for &(ref payload, final_range) in packets {
+            data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            data.extend_from_slice(&final_range.to_be_bytes());
+            data.extend_from_slice(payload);
+        }
+        data
+    }
+
+    /// CELT packets around a DTX packet and a lost packet, with the final ranges of the encoder.
+    fn dtx_vector() -> Vec<(Vec<u8>, u32)> {
+        vec![(celt_packet(0), 0x00a3c6c8),
+             (vec![0xfc], 0),
+             (celt_packet(1), 0x090f1b00),
+             (vec![], 0),
+             (celt_packet(2), 0x064c5f00)]
+    }
+
+    #[test]
+    fn read_packets() {
+        let data = bitstream(&dtx_vector());
+        let packets: Vec<_> = DemoReader::new(&data[..]).map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets[1],
+                   DemoPacket {
+                       data: vec![0xfc],
+                       final_range: 0,
+                   });
+        assert!(packets[3].data.is_empty());
+        assert_eq!(packets[4].final_range, 0x064c5f00);
+
+        let mut reader = DemoReader::new(&data[..data.len() - 1]);
+        for _ in 0..4 {
+            reader.next_packet().unwrap().unwrap();
+        }
+        assert!(reader.next_packet().is_err());
+    }
+
+    #[test]
+    fn verify_dtx() {
+        let data = bitstream(&dtx_vector());
+        let mut decoder = OpusDecoder::new();
+        let mut samples = 0;
+        let result = verify(&data[..], &mut decoder, |pcm| samples += pcm.len()).unwrap();
+        assert_eq!(result, Ok(5));
+        assert_eq!(samples, 5 * 2 * 960);
+    }
+
+    /// Streams encoded by libopus 1.3 with `opus_demo -e restricted-lowdelay 48000 <channels>
+    /// <bitrate> -framesize 20`: 200 ms of tones over noise at 64 kb/s in stereo and 32 kb/s in
+    /// mono, and 500 ms of a chirp with bursts of noise at 24 kb/s in mono, for the transients.
+    #[test]
+    fn verify_libopus() {
+        let vectors: [(&[u8], usize, usize); 3] =
+            [(include_bytes!("../testdata/celt_stereo.bit"), 2, 11),
+             (include_bytes!("../testdata/celt_mono.bit"), 1, 11),
+             (include_bytes!("../testdata/celt_transient.bit"), 1, 26)];
+        for &(data, channels, packets) in &vectors {
+            let mut decoder = OpusDecoder::with_channels(channels).unwrap();
+            let mut samples = 0;
+            let result = verify(data, &mut decoder, |pcm| samples += pcm.len()).unwrap();
+            assert_eq!(result, Ok(packets));
+            assert_eq!(samples, packets * channels * 960);
+        }
+    }
+
+    #[test]
+    fn verify_mismatch() {
+        let mut packets = dtx_vector();
+        packets[2].1 ^= 1;
+        let data = bitstream(&packets);
+        let result = verify(&data[..], &mut OpusDecoder::new(), |_| {}).unwrap();
+        assert_eq!(result,
+                   Err(Mismatch {
+                       packet: 2,
+                       expected: 0x090f1b01,
+                       actual: 0x090f1b00,
+                   }));
+    }
+}
@@ -0,0 +1,429 @@
+use header::{OpusHead, OpusTags};
+use mp4::{Mp4Packet, Mp4Reader};
+use oggopus::{OggOpusReader, PlaybackGain};
+use packet;
+use playback::Playback;
+use std;
+use std::io::{self, Read};
+use wav::SampleFormat;
+use webm::{self, WebmPacket, WebmReader};
+
+/// The source, after the bytes read to identify the container.
+type Source<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Timestamps are usually rounded to the millisecond. A packet later than the end of the
+/// previous one by more than this many samples follows lost packets.
+const MAX_TIMESTAMP_ERROR: i64 = 120;
+
+/// Decoder of the Opus track of a WebM file, with the bookkeeping `OggOpusReader` does for Ogg:
+/// the codec delay is discarded, the discard padding of the last packet trims the end, and the
+/// gaps in the timestamps are concealed.
+struct WebmDecoder<R> {
+    reader: WebmReader<R>,
+    playback: Playback,
+    /// Whether the first packet was read, setting the start of the output.
+    started: bool,
+    /// Packet read ahead, after a gap.
+    pending: Option<WebmPacket>,
+}
+
+impl<R: Read> WebmDecoder<R> {
+    fn new(reader: WebmReader<R>) -> io::Result<WebmDecoder<R>> {
+        let playback = Playback::new(reader.track().head.decoder()?);
+        Ok(WebmDecoder {
+            reader,
+            playback,
+            started: false,
+            pending: None,
+        })
+    }
+
+    fn read(&mut self, pcm: &mut [f32]) -> io::Result<usize> {
+        loop {
+            let n = if self.playback.gap > 0 {
+                self.playback.conceal(pcm)?
+            } else {
+                let p = match self.pending.take() {
+                    Some(p) => p,
+                    None => {
+                        match self.reader.next_packet()? {
+                            Some(p) => p,
+                            None => return Ok(0),
+                        }
+                    }
+                };
+                let timestamp = webm::ns_to_samples(p.timestamp);
+                if !self.started {
+                    let codec_delay = self.reader.track().codec_delay as i64;
+                    self.playback.skip_to = timestamp + webm::ns_to_samples(codec_delay);
+                    self.playback.granule = timestamp;
+                    self.started = true;
+                } else if timestamp - self.playback.granule > MAX_TIMESTAMP_ERROR {
+                    self.playback.gap = timestamp - self.playback.granule;
+                    self.pending = Some(p);
+                    continue;
+                }
+                let discard = std::cmp::max(webm::ns_to_samples(p.discard_padding), 0);
+                self.playback.decode(&p.data, discard as usize, pcm)?
+            };
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// Decoder of the Opus track of an MP4 file, read into memory: the pre-skip and the length of
+/// the edit list are applied, and the gaps in the timestamps concealed.
+struct Mp4Decoder {
+    reader: Mp4Reader<io::Cursor<Vec<u8>>>,
+    playback: Playback,
+    /// End of the audio, in samples including the pre-skip, if known.
+    end: Option<i64>,
+    /// Packet read ahead, after a gap.
+    pending: Option<Mp4Packet>,
+}
+
+impl Mp4Decoder {
+    fn new(reader: Mp4Reader<io::Cursor<Vec<u8>>>) -> io::Result<Mp4Decoder> {
+        let mut playback = Playback::new(reader.head.decoder()?);
+        playback.skip_to = reader.pre_skip as i64;
+        let end = reader.length.map(|length| (reader.pre_skip + length) as i64);
+        Ok(Mp4Decoder {
+            reader,
+            playback,
+            end,
+            pending: None,
+        })
+    }
+
+    fn read(&mut self, pcm: &mut [f32]) -> io::Result<usize> {
+        loop {
+            let n = if self.playback.gap > 0 {
+                self.playback.conceal(pcm)?
+            } else {
+                let p = match self.pending.take() {
+                    Some(p) => p,
+                    None => {
+                        match self.reader.next_packet()? {
+                            Some(p) => p,
+                            None => return Ok(0),
+                        }
+                    }
+                };
+                let timestamp = p.timestamp as i64;
+                if self.end.is_some_and(|end| timestamp >= end) {
+                    return Ok(0);
+                }
+                if timestamp - self.playback.granule > MAX_TIMESTAMP_ERROR {
+                    self.playback.gap = timestamp - self.playback.granule;
+                    self.pending = Some(p);
+                    continue;
+                }
+                let duration = packet::duration(&p.data).unwrap_or(0) as i64;
+                let trim = self.end.map_or(0, |end| std::cmp::max(timestamp + duration - end, 0));
+                self.playback.decode(&p.data, trim as usize, pcm)?
+            };
+            if n > 0 {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+enum Container<R> {
+    Ogg(OggOpusReader<Source<R>>),
+    Webm(WebmDecoder<Source<R>>, OpusTags),
+    Mp4(Mp4Decoder, OpusTags),
+}
+
+/// Decoder of an Opus file, from any source: an Ogg Opus file, chained or not, or the Opus
+/// track of a WebM or MP4 file, told apart by their first bytes. An MP4 file is read into
+/// memory first, as its movie box may come last.
+///
+/// The decoded samples are at 48 kHz, with `channels()` interleaved channels, which may change
+/// between the links of a chained Ogg file. The pre-skip is discarded, the end trimmed, the
+/// lost packets concealed, and the playback gain applied, as by `OggOpusReader`. They are read
+/// a packet at a time with `read`, as frames of the `Iterator`, or as bytes through a
+/// `PcmReader`.
+pub struct OpusFile<R> {
+    container: Container<R>,
+    buffer: Vec<f32>,
+}
+
+impl<R: Read> OpusFile<R> {
+    pub fn new(mut reader: R) -> io::Result<OpusFile<R>> {
+        let mut magic = vec![];
+        reader.by_ref().take(8).read_to_end(&mut magic)?;
+        let ogg = magic.starts_with(b"OggS");
+        let ebml = magic.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]);
+        // An MP4 file starts with its file type box.
+        let mp4 = magic.get(4..8) == Some(b"ftyp");
+        let container = if ogg {
+            Container::Ogg(OggOpusReader::new(io::Cursor::new(magic).chain(reader))?)
+        } else if ebml {
+            let source = io::Cursor::new(magic).chain(reader);
+            let decoder = WebmDecoder::new(WebmReader::new(source)?)?;
+            Container::Webm(decoder, OpusTags::default())
+        } else if mp4 {
+            let mut data = magic;
+            reader.read_to_end(&mut data)?;
+            let decoder = Mp4Decoder::new(Mp4Reader::new(io::Cursor::new(data))?)?;
+            Container::Mp4(decoder, OpusTags::default())
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown container"));
+        };
+        let mut file = OpusFile {
+            container,
+            buffer: vec![],
+        };
+        file.set_playback_gain(PlaybackGain::Header);
+        Ok(file)
+    }
+
+    /// The identification header of the current link.
+    pub fn head(&self) -> &OpusHead {
+        match self.container {
+            Container::Ogg(ref reader) => &reader.head,
+            Container::Webm(ref decoder, _) => &decoder.reader.track().head,
+            Container::Mp4(ref decoder, _) => &decoder.reader.head,
+        }
+    }
+
+    /// The comment header of the current link, empty for WebM and MP4.
+    pub fn tags(&self) -> &OpusTags {
+        match self.container {
+            Container::Ogg(ref reader) => &reader.tags,
+            Container::Webm(_, ref tags) | Container::Mp4(_, ref tags) => tags,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self.container {
+            Container::Ogg(ref reader) => reader.channels(),
+            Container::Webm(ref decoder, _) => decoder.playback.channels(),
+            Container::Mp4(ref decoder, _) => decoder.playback.channels(),
+        }
+    }
+
+    /// Choose the gain applied to the output, the output gain of the header by default.
+    pub fn set_playback_gain(&mut self, playback_gain: PlaybackGain) {
+        match self.container {
+            Container::Ogg(ref mut reader) => reader.set_playback_gain(playback_gain),
+            Container::Webm(ref mut decoder, ref tags) => {
+                let gain = playback_gain.gain(&decoder.reader.track().head, tags);
+                decoder.playback.set_gain(gain);
+            }
+            Container::Mp4(ref mut decoder, ref tags) => {
+                let gain = playback_gain.gain(&decoder.reader.head, tags);
+                decoder.playback.set_gain(gain);
+            }
+        }
+    }
+
+    /// Gain applied to the output of the current link, in dB.
+    pub fn gain(&self) -> f64 {
+        match self.container {
+            Container::Ogg(ref reader) => reader.gain(),
+            Container::Webm(ref decoder, _) => decoder.playback.gain() as f64 / 256.0,
+            Container::Mp4(ref decoder, _) => decoder.playback.gain() as f64 / 256.0,
+        }
+    }
+
+    /// Decode the next packet into `pcm`, which has room for 120 ms of samples. Returns the
+    /// number of samples per channel, or 0 at the end of the input.
+    pub fn read(&mut self, pcm: &mut [f32]) -> io::Result<usize> {
+        match self.container {
+            Container::Ogg(ref mut reader) => reader.read(pcm),
+            Container::Webm(ref mut decoder, _) => decoder.read(pcm),
+            Container::Mp4(ref mut decoder, _) => decoder.read(pcm),
+        }
+    }
+
+    /// Read the decoded samples as bytes of `format`.
+    pub fn into_reader(self, format: SampleFormat) -> PcmReader<R> {
+        PcmReader {
+            channels: self.channels(),
+            file: self,
+            format,
+            data: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for OpusFile<R> {
+    type Item = io::Result<Vec<f32>>;
+
+    /// The interleaved samples of the next packet.
+    fn next(&mut self) -> Option<io::Result<Vec<f32>>> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.resize(self.channels() * packet::MAX_PACKET_DURATION, 0.0);
+        let result = match self.read(&mut buffer) {
+            Ok(0) => None,
+            Ok(n) => Some(Ok(buffer[..n * self.channels()].to_vec())),
+            Err(err) => Some(Err(err)),
+        };
+        self.buffer = buffer;
+        result
+    }
+}
+
+/// Reader of the decoded samples of an `OpusFile`, as interleaved little-endian bytes of a
+/// sample format. The number of channels must stay that of the first link.
+pub struct PcmReader<R> {
+    file: OpusFile<R>,
+    format: SampleFormat,
+    channels: usize,
+    /// Bytes of the last packet, from `pos`.
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> PcmReader<R> {
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn into_inner(self) -> OpusFile<R> {
+        self.file
+    }
+}
+
+impl<R: Read> Read for PcmReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.data.len() {
+            let pcm = match self.file.next() {
+                Some(pcm) => pcm?,
+                None => return Ok(0),
+            };
+            if self.file.channels() != self.channels {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "channel count changes between links"));
+            }
+            self.data.clear();
+            self.pos = 0;
+            self.format.encode(&pcm, &mut self.data);
+        }
+        let len = std::cmp::min(buf.len(), self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mp4;
+    use oggopus::tests::{decode_all, head, stream};
+    use packet::tests::celt_packet;
+
+    /// A WebM file of 10 packets, with the pre-skip of `head` as codec delay and 240 samples
+    /// of discard padding, without the packets in `lost`.
+    fn webm_file(head: &OpusHead, lost: &[usize]) -> Vec<u8> {
+        let mut blocks = vec![];
+        for i in (0..10).filter(|i| !lost.contains(i)) {
+            let timecode = 20 * i as i16;
+            if i == 9 {
+                blocks.extend(webm::tests::block_group(timecode, &celt_packet(i), 5_000_000));
+            } else {
+                blocks.extend(webm::tests::simple_block(timecode, &celt_packet(i)));
+            }
+        }
+        let codec_delay = webm::samples_to_ns(head.pre_skip as i64) as u64;
+        webm::tests::webm(head, codec_delay, &[(0, blocks)])
+    }
+
+    fn read_all<R: Read>(file: OpusFile<R>) -> Vec<f32> {
+        file.flat_map(|pcm| pcm.unwrap()).collect()
+    }
+
+    /// The output of the same packets in an Ogg Opus file.
+    fn expected(head: &OpusHead) -> Vec<f32> {
+        let data = stream(head, &OpusTags::default(), 10, 240);
+        decode_all(&mut OggOpusReader::new(&data[..]).unwrap())
+    }
+
+    #[test]
+    fn ogg() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 10, 240);
+        let file = OpusFile::new(&data[..]).unwrap();
+        assert_eq!((file.channels(), file.head(), file.gain()), (2, &head(312, 0), 0.0));
+        assert_eq!(read_all(file), expected(&head(312, 0)));
+    }
+
+    #[test]
+    fn webm() {
+        let output = read_all(OpusFile::new(&webm_file(&head(312, 0), &[])[..]).unwrap());
+        assert_eq!(output.len(), 2 * (10 * 960 - 312 - 240));
+        assert_eq!(output, expected(&head(312, 0)));
+    }
+
+    #[test]
+    fn webm_gap() {
+        let data = webm_file(&head(312, 0), &[4, 5]);
+        let file = OpusFile::new(&data[..]).unwrap();
+        let lengths: Vec<usize> = file.map(|pcm| pcm.unwrap().len() / 2).collect();
+        // The two lost packets are concealed, without changing the length of the output.
+        assert_eq!(lengths, [648, 960, 960, 960, 960, 960, 960, 960, 960, 720]);
+        let output = read_all(OpusFile::new(&data[..]).unwrap());
+        let full = expected(&head(312, 0));
+        assert_eq!(output[..2 * (4 * 960 - 312)], full[..2 * (4 * 960 - 312)]);
+    }
+
+    #[test]
+    fn mp4_file() {
+        // 188 ms after the pre-skip: the last 264 samples are trimmed.
+        let packets: Vec<Vec<u8>> = (0..10).map(celt_packet).collect();
+        let data = mp4::tests::mp4(&packets, &[(188, 312)]);
+        let file = OpusFile::new(&data[..]).unwrap();
+        assert_eq!((file.channels(), file.head().pre_skip, file.gain()), (2, 312, 1.0));
+        let output = read_all(file);
+        assert_eq!(output.len(), 2 * 188 * 48);
+        let data = stream(&head(312, 256), &OpusTags::default(), 10, 264);
+        assert_eq!(output, decode_all(&mut OggOpusReader::new(&data[..]).unwrap()));
+
+        // Without an edit list, the pre-skip of the header, and no trim.
+        let data = mp4::tests::mp4(&packets[..4], &[]);
+        assert_eq!(read_all(OpusFile::new(&data[..]).unwrap()).len(), 2 * (4 * 960 - 312));
+    }
+
+    #[test]
+    fn gain() {
+        let data = webm_file(&head(312, 256), &[]);
+        let mut file = OpusFile::new(&data[..]).unwrap();
+        assert_eq!(file.gain(), 1.0);
+        assert_eq!(file.tags(), &OpusTags::default());
+        let scale = 10f32.powf(1.0 / 20.0);
+        let output = read_all(file);
+        for (x, y) in output.iter().zip(expected(&head(312, 0))) {
+            assert!((x - y * scale).abs() < 1e-6);
+        }
+
+        file = OpusFile::new(&data[..]).unwrap();
+        file.set_playback_gain(PlaybackGain::None);
+        assert_eq!(file.gain(), 0.0);
+        assert_eq!(read_all(file), expected(&head(312, 0)));
+    }
+
+    #[test]
+    fn pcm_reader() {
+        let data = stream(&head(312, 0), &OpusTags::default(), 10, 240);
+        let mut reader = OpusFile::new(&data[..]).unwrap().into_reader(SampleFormat::I16);
+        assert_eq!(reader.channels(), 2);
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).unwrap();
+        let mut expected_bytes = vec![];
+        SampleFormat::I16.encode(&expected(&head(312, 0)), &mut expected_bytes);
+        assert_eq!(bytes, expected_bytes);
+    }
+
+    #[test]
+    fn unknown_container() {
+        for data in &[&b"RIFF\0\0\0\0WAVE"[..], b"Ogg", b""] {
+            let err = OpusFile::new(*data).err().map(|err| err.kind());
+            assert_eq!(err, Some(io::ErrorKind::InvalidData));
+        }
+    }
+}
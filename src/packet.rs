@@ -0,0 +1,367 @@
+use error::Error;
+
+/// Maximum duration of a packet, 120 ms at 48 kHz.
+pub const MAX_PACKET_DURATION: usize = 5760;
+
+/// Maximum size of a single compressed frame.
+pub const MAX_FRAME_BYTES: usize = 1275;
+
+/// Table 2: TOC Byte Configuration Parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    SilkOnly,
+    Hybrid,
+    CeltOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+    Narrowband,
+    Mediumband,
+    Wideband,
+    SuperWideband,
+    Fullband,
+}
+
+/// 3.1. The TOC Byte
+///
+///      0
+///      0 1 2 3 4 5 6 7
+///     +-+-+-+-+-+-+-+-+
+///     | config  |s| c |
+///     +-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Toc {
+    pub config: u8,
+    pub stereo: bool,
+    pub code: u8,
+}
+
+impl Toc {
+    pub fn new(toc: u8) -> Toc {
+        Toc {
+            config: toc >> 3,
+            stereo: toc & 0x4 != 0,
+            code: toc & 0x3,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        self.config << 3 | if self.stereo { 0x4 } else { 0 } | self.code
+    }
+
+    pub fn mode(&self) -> Mode {
+        match self.config {
+            0..=11 => Mode::SilkOnly,
+            12..=15 => Mode::Hybrid,
+            _ => Mode::CeltOnly,
+        }
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        match self.config {
+            0..=3 | 16..=19 => Bandwidth::Narrowband,
+            4..=7 => Bandwidth::Mediumband,
+            8..=11 | 20..=23 => Bandwidth::Wideband,
+            12..=13 | 24..=27 => Bandwidth::SuperWideband,
+            _ => Bandwidth::Fullband,
+        }
+    }
+
+    /// Number of samples per channel at 48 kHz in each frame.
+    pub fn frame_size(&self) -> usize {
+        match self.mode() {
+            Mode::SilkOnly => [480, 960, 1920, 2880][self.config as usize & 3],
+            Mode::Hybrid => [480, 960][self.config as usize & 1],
+            Mode::CeltOnly => 120 << (self.config & 3),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        if self.stereo { 2 } else { 1 }
+    }
+}
+
+/// A packet split into its compressed frames.
+#[derive(Debug, Clone)]
+pub struct Packet<'a> {
+    pub toc: Toc,
+    pub frames: Vec<&'a [u8]>,
+    /// Number of padding bytes at the end of the packet.
+    pub padding: usize,
+    /// Number of bytes of the input taken by this packet, which is less than the input length for
+    /// a self-delimited packet followed by more data.
+    pub len: usize,
+}
+
+impl<'a> Packet<'a> {
+    /// Number of samples per channel at 48 kHz in the whole packet.
+    pub fn duration(&self) -> usize {
+        self.frames.len() * self.toc.frame_size()
+    }
+}
+
+/// 3.2.1. Frame Length Coding
+///
+/// Returns the frame length and the number of bytes it took.
+fn parse_size(data: &[u8]) -> Result<(usize, usize), Error> {
+    match data.len() {
+        0 => Err(Error::InvalidPacket),
+        _ if data[0] < 252 => Ok((data[0] as usize, 1)),
+        1 => Err(Error::InvalidPacket),
+        _ => Ok((4 * data[1] as usize + data[0] as usize, 2)),
+    }
+}
+
+/// 3.2. Frame Packing
+///
+/// Splits a packet into frames according to the frame count code in its TOC byte. A
+/// self-delimited packet (Appendix B) additionally codes the length of its last frame, so that
+/// several packets can be concatenated, as done by the multistream format.
+pub fn parse<'a>(data: &'a [u8], self_delimited: bool) -> Result<Packet<'a>, Error> {
+    if data.is_empty() {
+        return Err(Error::InvalidPacket);
+    }
+    let toc = Toc::new(data[0]);
+    let mut pos = 1;
+    let mut len = data.len() - 1;
+    let mut sizes = vec![];
+    let mut last_size = len;
+    let mut padding = 0;
+    let mut cbr = false;
+    let count;
+    match toc.code {
+        0 => count = 1,
+        1 => {
+            count = 2;
+            cbr = true;
+            if !self_delimited {
+                if !len.is_multiple_of(2) {
+                    return Err(Error::InvalidPacket);
+                }
+                last_size = len / 2;
+                sizes.push(last_size);
+            }
+        }
+        2 => {
+            count = 2;
+            let (size, bytes) = parse_size(&data[pos..])?;
+            len -= bytes;
+            if size > len {
+                return Err(Error::InvalidPacket);
+            }
+            pos += bytes;
+            sizes.push(size);
+            last_size = len - size;
+        }
+        _ => {
+            if len < 1 {
+                return Err(Error::InvalidPacket);
+            }
+            let ch = data[pos];
+            pos += 1;
+            len -= 1;
+            count = (ch & 0x3f) as usize;
+            if count == 0 || toc.frame_size() * count > MAX_PACKET_DURATION {
+                return Err(Error::InvalidPacket);
+            }
+            if ch & 0x40 != 0 {
+                loop {
+                    if len == 0 {
+                        return Err(Error::InvalidPacket);
+                    }
+                    let p = data[pos] as usize;
+                    pos += 1;
+                    len -= 1;
+                    let tmp = if p == 255 { 254 } else { p };
+                    if tmp > len {
+                        return Err(Error::InvalidPacket);
+                    }
+                    len -= tmp;
+                    padding += tmp;
+                    if p != 255 {
+                        break;
+                    }
+                }
+            }
+            cbr = ch & 0x80 == 0;
+            if !cbr {
+                last_size = len;
+                for _ in 0..count - 1 {
+                    let (size, bytes) = parse_size(&data[pos..pos + len])?;
+                    len -= bytes;
+                    if size > len || bytes + size > last_size {
+                        return Err(Error::InvalidPacket);
+                    }
+                    pos += bytes;
+                    sizes.push(size);
+                    last_size -= bytes + size;
+                }
+            } else if !self_delimited {
+                last_size = len / count;
+                if last_size * count != len {
+                    return Err(Error::InvalidPacket);
+                }
+                for _ in 0..count - 1 {
+                    sizes.push(last_size);
+                }
+            }
+        }
+    }
+
+    if self_delimited {
+        let (size, bytes) = parse_size(&data[pos..pos + len])?;
+        len -= bytes;
+        if size > len {
+            return Err(Error::InvalidPacket);
+        }
+        pos += bytes;
+        if cbr {
+            if size * count > len {
+                return Err(Error::InvalidPacket);
+            }
+            sizes = vec![size; count];
+        } else {
+            if bytes + size > last_size {
+                return Err(Error::InvalidPacket);
+            }
+            sizes.push(size);
+        }
+    } else {
+        if last_size > MAX_FRAME_BYTES {
+            return Err(Error::InvalidPacket);
+        }
+        sizes.push(last_size);
+    }
+
+    let mut frames = Vec::with_capacity(count);
+    for size in sizes {
+        if size > MAX_FRAME_BYTES {
+            return Err(Error::InvalidPacket);
+        }
+        frames.push(&data[pos..pos + size]);
+        pos += size;
+    }
+    Ok(Packet {
+        toc,
+        frames,
+        padding,
+        len: pos + padding,
+    })
+}
+
+/// Number of samples per channel at 48 kHz in a packet, without fully parsing it.
+pub fn duration(data: &[u8]) -> Result<usize, Error> {
+    if data.is_empty() {
+        return Err(Error::InvalidPacket);
+    }
+    let toc = Toc::new(data[0]);
+    let count = match toc.code {
+        0 => 1,
+        1 | 2 => 2,
+        _ if data.len() < 2 => return Err(Error::InvalidPacket),
+        _ => (data[1] & 0x3f) as usize,
+    };
+    let duration = count * toc.frame_size();
+    if count == 0 || duration > MAX_PACKET_DURATION {
+        return Err(Error::InvalidPacket);
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A CELT-only packet of 20 ms, fullband stereo, of one of three byte patterns.
+    pub fn celt_packet(i: usize) -> Vec<u8> {
+        let mut data = vec![0xfc];
+        data.extend(std::iter::repeat_n([0x55, 0xaa, 0x33][i % 3], 160));
+        data
+    }
+
+    #[test]
+    fn toc() {
+        let toc = Toc::new(0xfc);
+        assert_eq!((toc.config, toc.stereo, toc.code), (31, true, 0));
+        assert_eq!(toc.to_byte(), 0xfc);
+        assert_eq!((toc.mode(), toc.bandwidth()), (Mode::CeltOnly, Bandwidth::Fullband));
+        assert_eq!((toc.frame_size(), toc.channels()), (960, 2));
+
+        let toc = Toc::new(0x1b);
+        assert_eq!((toc.mode(), toc.bandwidth()), (Mode::SilkOnly, Bandwidth::Narrowband));
+        assert_eq!((toc.frame_size(), toc.channels(), toc.code), (2880, 1, 3));
+        let toc = Toc::new(13 << 3);
+        assert_eq!((toc.mode(), toc.bandwidth()), (Mode::Hybrid, Bandwidth::SuperWideband));
+        assert_eq!(toc.frame_size(), 960);
+        assert_eq!(Toc::new(16 << 3).frame_size(), 120);
+        assert_eq!(Toc::new(22 << 3).bandwidth(), Bandwidth::Wideband);
+    }
+
+    #[test]
+    fn frame_packing() {
+        // Code 0: a single frame.
+        let data = [0xf8, 1, 2, 3];
+        let p = parse(&data, false).unwrap();
+        assert_eq!(p.frames, [&[1, 2, 3][..]]);
+        assert_eq!((p.duration(), p.len), (960, 4));
+
+        // Code 1: two frames of the same size.
+        let p = parse(&[0xf9, 1, 2, 3, 4], false).unwrap();
+        assert_eq!(p.frames, [&[1, 2][..], &[3, 4][..]]);
+        assert_eq!(parse(&[0xf9, 1, 2, 3], false).unwrap_err(), Error::InvalidPacket);
+
+        // Code 2: the size of the first frame, then both.
+        let p = parse(&[0xfa, 1, 1, 2, 3], false).unwrap();
+        assert_eq!(p.frames, [&[1][..], &[2, 3][..]]);
+        assert_eq!(parse(&[0xfa, 5, 1], false).unwrap_err(), Error::InvalidPacket);
+
+        // Code 3, VBR with padding: 2 frames, 2 padding bytes.
+        let p = parse(&[0xfb, 0xc2, 2, 1, 1, 2, 3, 0, 0], false).unwrap();
+        assert_eq!(p.frames, [&[1][..], &[2, 3][..]]);
+        assert_eq!((p.padding, p.len, p.duration()), (2, 9, 1920));
+
+        // Code 3, CBR: 3 frames of 2 bytes.
+        let p = parse(&[0xfb, 0x03, 1, 2, 3, 4, 5, 6], false).unwrap();
+        assert_eq!(p.frames, [&[1, 2][..], &[3, 4][..], &[5, 6][..]]);
+        // No frames, or more than 120 ms of them.
+        assert_eq!(parse(&[0xfb, 0x00], false).unwrap_err(), Error::InvalidPacket);
+        assert_eq!(parse(&[0xfb, 0x07], false).unwrap_err(), Error::InvalidPacket);
+        assert_eq!(parse(&[], false).unwrap_err(), Error::InvalidPacket);
+    }
+
+    #[test]
+    fn two_byte_sizes() {
+        let mut data = vec![0xfa, 252, 1];
+        data.extend(std::iter::repeat_n(7, 256 + 10));
+        let p = parse(&data, false).unwrap();
+        assert_eq!((p.frames[0].len(), p.frames[1].len()), (256, 10));
+        // Frames are at most 1275 bytes.
+        let data = vec![0xf8; 1277];
+        assert_eq!(parse(&data, false).unwrap_err(), Error::InvalidPacket);
+    }
+
+    #[test]
+    fn self_delimited() {
+        // Two packets back to back, each coding the size of its last frame.
+        let data = [0xf8, 2, 1, 2, 0xf9, 1, 3, 4, 0xff];
+        let p = parse(&data, true).unwrap();
+        assert_eq!(p.frames, [&[1, 2][..]]);
+        assert_eq!(p.len, 4);
+        let p = parse(&data[p.len..], true).unwrap();
+        assert_eq!(p.frames, [&[3][..], &[4][..]]);
+        assert_eq!(p.len, 4);
+        assert_eq!(parse(&[0xf8, 5, 1], true).unwrap_err(), Error::InvalidPacket);
+    }
+
+    #[test]
+    fn packet_duration() {
+        assert_eq!(duration(&[0xfc]), Ok(960));
+        assert_eq!(duration(&[0xf9]), Ok(1920));
+        assert_eq!(duration(&[0xe3, 0x30]), Ok(48 * 120));
+        assert_eq!(duration(&[0x1b, 0x02]), Ok(2 * 2880));
+        assert_eq!(duration(&[0x1b, 0x03]), Err(Error::InvalidPacket));
+        assert_eq!(duration(&[0xfb]), Err(Error::InvalidPacket));
+        assert_eq!(duration(&[]), Err(Error::InvalidPacket));
+    }
+}
@@ -0,0 +1,153 @@
+use header::Decoder;
+use packet;
+use std;
+use std::io;
+
+/// Output stage of a decoded stream, shared by the readers of each container.
+///
+/// The samples are counted in granule positions, at 48 kHz. Those before `skip_to` are
+/// discarded, the end of a packet may be trimmed, and the samples of lost packets in `gap` are
+/// concealed before the next packet. The gain is applied by the decoder.
+pub struct Playback {
+    pub decoder: Decoder,
+    /// Granule position at the end of the last decoded packet.
+    pub granule: i64,
+    /// Decoded samples before this granule position are discarded.
+    pub skip_to: i64,
+    /// Samples of lost packets to conceal before the next packet.
+    pub gap: i64,
+    /// Gain, in Q7.8 dB.
+    gain: i16,
+    buffer: Vec<f32>,
+}
+
+impl Playback {
+    pub fn new(decoder: Decoder) -> Playback {
+        let buffer = vec![0.0; decoder.channels() * packet::MAX_PACKET_DURATION];
+        Playback {
+            decoder,
+            granule: 0,
+            skip_to: 0,
+            gap: 0,
+            gain: 0,
+            buffer,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.decoder.channels()
+    }
+
+    /// Gain applied to the output, in Q7.8 dB.
+    pub fn gain(&self) -> i16 {
+        self.gain
+    }
+
+    pub fn set_gain(&mut self, gain: i16) {
+        self.gain = gain;
+        self.decoder.set_gain(gain);
+    }
+
+    /// Decode the packet `data`, with `trim` samples cut from its end, into `pcm`, which has
+    /// room for 120 ms of samples. Returns the number of samples per channel, 0 if all of them
+    /// are discarded.
+    pub fn decode(&mut self, data: &[u8], trim: usize, pcm: &mut [f32]) -> io::Result<usize> {
+        let n = self.decoder.decode(Some(data), &mut self.buffer)?;
+        self.output(n - std::cmp::min(trim, n), pcm)
+    }
+
+    /// Conceal up to a packet of the lost samples of `gap` into `pcm`, as `decode`.
+    pub fn conceal(&mut self, pcm: &mut [f32]) -> io::Result<usize> {
+        let n = self.decoder.decode(None, &mut self.buffer)?;
+        let end = std::cmp::min(n as i64, self.gap) as usize;
+        // Without any previous packet, there is nothing to conceal.
+        self.gap = if n == 0 { 0 } else { self.gap - end as i64 };
+        self.output(end, pcm)
+    }
+
+    /// Copy the first `end` samples of the last decoded packet from `skip_to`.
+    fn output(&mut self, end: usize, pcm: &mut [f32]) -> io::Result<usize> {
+        let channels = self.channels();
+        let start = self.granule;
+        self.granule += end as i64;
+        let skip = std::cmp::min(std::cmp::max(self.skip_to - start, 0), end as i64) as usize;
+        if skip == end {
+            return Ok(0);
+        }
+        let len = (end - skip) * channels;
+        if pcm.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer too small"));
+        }
+        pcm[..len].copy_from_slice(&self.buffer[skip * channels..end * channels]);
+        Ok(end - skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oggopus::tests::head;
+    use packet::tests::celt_packet;
+
+    fn playback() -> Playback {
+        Playback::new(head(0, 0).decoder().unwrap())
+    }
+
+    /// The samples of packet `i` decoded on its own after packets `0..i`.
+    fn decoded(i: usize) -> Vec<f32> {
+        let mut decoder = head(0, 0).decoder().unwrap();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        let mut n = 0;
+        for j in 0..i + 1 {
+            n = decoder.decode(Some(&celt_packet(j)), &mut pcm).unwrap();
+        }
+        pcm.truncate(2 * n);
+        pcm
+    }
+
+    #[test]
+    fn skip_and_trim() {
+        let mut playback = playback();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        playback.skip_to = 1000;
+        // The first packet is entirely discarded.
+        assert_eq!(playback.decode(&celt_packet(0), 0, &mut pcm).unwrap(), 0);
+        assert_eq!(playback.decode(&celt_packet(1), 100, &mut pcm).unwrap(), 820);
+        assert_eq!(pcm[..2 * 820], decoded(1)[2 * 40..2 * 860]);
+        assert_eq!(playback.granule, 1820);
+        let err = playback.decode(&celt_packet(2), 0, &mut pcm[..100]).err();
+        assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn gain() {
+        let mut playback = playback();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        playback.set_gain(-6 * 256);
+        assert_eq!(playback.gain(), -1536);
+        assert_eq!(playback.decode(&celt_packet(0), 0, &mut pcm).unwrap(), 960);
+        let scale = 10f32.powf(-6.0 / 20.0);
+        for (x, y) in pcm.iter().zip(decoded(0)) {
+            assert!((x - y * scale).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn conceal() {
+        let mut playback = playback();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        // Silence before the first packet.
+        playback.gap = 1000;
+        assert_eq!(playback.conceal(&mut pcm).unwrap(), 960);
+        assert!(pcm[..2 * 960].iter().all(|&s| s == 0.0));
+        assert_eq!(playback.conceal(&mut pcm).unwrap(), 40);
+        assert_eq!((playback.gap, playback.granule), (0, 1000));
+
+        playback.decode(&celt_packet(0), 0, &mut pcm).unwrap();
+        playback.gap = 1500;
+        assert_eq!(playback.conceal(&mut pcm).unwrap(), 960);
+        assert!(pcm[..2 * 960].iter().any(|&s| s != 0.0));
+        assert_eq!(playback.conceal(&mut pcm).unwrap(), 540);
+        assert_eq!((playback.gap, playback.granule), (0, 3460));
+    }
+}
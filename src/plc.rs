@@ -0,0 +1,408 @@
+use consts;
+use opus_decoder;
+use opus_decoder::{OpusDecoder, BUFFER_SIZE};
+use std;
+use utils;
+
+pub const LPC_ORDER: usize = 24;
+const MAX_PERIOD: usize = 1024;
+const PITCH_LAG_MAX: usize = 720;
+const PITCH_LAG_MIN: usize = 100;
+const COMBFILTER_MINPERIOD: usize = 15;
+/// Losses after which the concealment turns into comfort noise.
+const MAX_PERIODIC_LOSSES: usize = 5;
+/// Lag drift per lost frame, borrowed from the SILK concealment.
+const PITCH_DRIFT: f32 = 0.01;
+/// Normalised pitch correlation under which the signal is considered unvoiced.
+const VOICING_THRESHOLD: f32 = 0.3;
+
+/// Autocorrelation of `x`, with the first and last `window.len()` samples windowed.
+fn autocorr(x: &[f32], ac: &mut [f32], window: &[f32]) {
+    let n = x.len();
+    let mut xx = x.to_vec();
+    for (i, w) in window.iter().enumerate() {
+        xx[i] *= w;
+        xx[n - i - 1] *= w;
+    }
+    for (k, a) in ac.iter_mut().enumerate() {
+        *a = utils::inner_product(&xx[k..], &xx[..n - k]);
+    }
+}
+
+/// Levinson-Durbin recursion.
+///
+/// The prediction error of the resulting filter is e[n] = x[n] + Σ lpc[j] * x[n - j - 1].
+fn lpc(ac: &[f32], lpc: &mut [f32]) {
+    for l in lpc.iter_mut() {
+        *l = 0.0;
+    }
+    let mut error = ac[0];
+    if error <= 0.0 {
+        return;
+    }
+    for i in 0..lpc.len() {
+        let mut rr = ac[i + 1];
+        for j in 0..i {
+            rr += lpc[j] * ac[i - j];
+        }
+        let r = -rr / error;
+        lpc[i] = r;
+        for j in 0..i.div_ceil(2) {
+            let tmp1 = lpc[j];
+            let tmp2 = lpc[i - 1 - j];
+            lpc[j] = tmp1 + r * tmp2;
+            lpc[i - 1 - j] = tmp2 + r * tmp1;
+        }
+        error -= r * r * error;
+        if error < 0.001 * ac[0] {
+            break;
+        }
+    }
+}
+
+/// Analysis filter, `mem` holding the input samples before `x`, most recent first.
+fn fir(x: &mut [f32], lpc: &[f32], mem: &mut [f32]) {
+    for x in x.iter_mut() {
+        let sum = *x + utils::inner_product(lpc, mem);
+        mem.rotate_right(1);
+        mem[0] = *x;
+        *x = sum;
+    }
+}
+
+/// Synthesis filter, `mem` holding the output samples before `x`, most recent first.
+fn iir(x: &mut [f32], lpc: &[f32], mem: &mut [f32]) {
+    for x in x.iter_mut() {
+        let sum = *x - utils::inner_product(lpc, mem);
+        mem.rotate_right(1);
+        mem[0] = sum;
+        *x = sum;
+    }
+}
+
+/// Pitch search on the decoded history.
+///
+/// The channels are mixed, low-passed and decimated by two, and whitened with a fourth-order
+/// LPC filter. The lag maximising the normalised correlation between the last samples and the
+/// lagged history is returned with that correlation.
+fn pitch_search(decode_mem: &[Vec<f32>]) -> (usize, f32) {
+    let n = BUFFER_SIZE / 2;
+    let mut x = vec![0.0; n];
+    for m in decode_mem.iter() {
+        x[0] += 0.25 * (2.0 * m[0] + m[1]);
+        for i in 1..n {
+            x[i] += 0.25 * (m[2 * i - 1] + 2.0 * m[2 * i] + m[2 * i + 1]);
+        }
+    }
+
+    let mut ac = [0.0; 5];
+    autocorr(&x, &mut ac, &[]);
+    ac[0] *= 1.0001;
+    for (i, a) in ac.iter_mut().enumerate().skip(1) {
+        *a -= *a * (0.008 * i as f32) * (0.008 * i as f32);
+    }
+    let mut a = [0.0; 4];
+    lpc(&ac, &mut a);
+    let mut g = 1.0;
+    for a in a.iter_mut() {
+        g *= 0.9;
+        *a *= g;
+    }
+    let a = [a[0] + 0.8, a[1] + 0.8 * a[0], a[2] + 0.8 * a[1], a[3] + 0.8 * a[2], 0.8 * a[3]];
+    fir(&mut x, &a, &mut [0.0; 5]);
+
+    let y = &x[PITCH_LAG_MAX / 2..];
+    let yy = utils::inner_product(y, y);
+    let mut best = (PITCH_LAG_MIN, 0.0);
+    let mut best_score = 0.0;
+    for lag in PITCH_LAG_MIN / 2..PITCH_LAG_MAX / 2 + 1 {
+        let lagged = &x[PITCH_LAG_MAX / 2 - lag..n - lag];
+        let xy = utils::inner_product(y, lagged);
+        let xx = utils::inner_product(lagged, lagged);
+        if xy > 0.0 && xy * xy / xx > best_score {
+            best_score = xy * xy / xx;
+            best = (2 * lag, xy / (xx * yy).sqrt());
+        }
+    }
+    best
+}
+
+/// Periodic extension of the signal of one channel, in the excitation domain of its LPC filter.
+///
+/// The excitation of the last two pitch periods is repeated, attenuated by the rate at which it
+/// was decaying, and by a further fade for repeated losses. Unvoiced signals repeat excitation
+/// picked at random instead. The extension covers the frame and the following MDCT overlap.
+fn extrapolate(st: &mut OpusDecoder, c: usize, pitch: usize, voiced: bool, fade: f32, seed: &mut u32) {
+    let n = consts::FRAME_SIZE;
+    let overlap = consts::WINDOW_SIZE;
+    let buf = &mut st.decode_mem[c];
+    let mut exc = buf[BUFFER_SIZE - MAX_PERIOD..BUFFER_SIZE].to_vec();
+
+    let exc_length = std::cmp::min(2 * pitch, MAX_PERIOD);
+    {
+        let mut mem = [0.0; LPC_ORDER];
+        for (i, m) in mem.iter_mut().enumerate() {
+            *m = buf[BUFFER_SIZE - exc_length - 1 - i];
+        }
+        fir(&mut exc[MAX_PERIOD - exc_length..], &st.plc_lpc[c], &mut mem);
+    }
+
+    // Check if the waveform is decaying, and if so how fast, to avoid adding energy when
+    // concealing a segment with decaying energy.
+    let decay = {
+        let last = &exc[MAX_PERIOD - exc_length / 2..];
+        let before = &exc[MAX_PERIOD - exc_length / 2 * 2..MAX_PERIOD - exc_length / 2];
+        let e1 = 1.0 + utils::inner_product(last, last);
+        let e2 = 1.0 + utils::inner_product(before, before);
+        (e1.min(e2) / e2).sqrt()
+    };
+
+    buf.copy_within(n..BUFFER_SIZE, 0);
+
+    let offset = MAX_PERIOD - pitch;
+    let len = n + overlap;
+    let mut attenuation = fade * decay;
+    let mut s1 = 0.0;
+    let mut j = 0;
+    for i in 0..len {
+        if j >= pitch {
+            j -= pitch;
+            attenuation *= decay;
+        }
+        let e = if voiced {
+            exc[offset + j]
+        } else {
+            *seed = utils::lcg_rand(*seed);
+            exc[MAX_PERIOD - exc_length + (*seed >> 16) as usize % exc_length]
+        };
+        buf[BUFFER_SIZE - n + i] = attenuation * e;
+        // Energy of the decoded signal whose excitation is being copied.
+        let tmp = buf[BUFFER_SIZE - MAX_PERIOD - n + offset + j];
+        s1 += tmp * tmp;
+        j += 1;
+    }
+
+    {
+        let mut mem = [0.0; LPC_ORDER];
+        for (i, m) in mem.iter_mut().enumerate() {
+            *m = buf[BUFFER_SIZE - n - 1 - i];
+        }
+        iir(&mut buf[BUFFER_SIZE - n..BUFFER_SIZE - n + len], &st.plc_lpc[c], &mut mem);
+    }
+
+    // The synthesis may have more energy than expected if the signal changed during the window,
+    // or even explode. Attenuate in that case.
+    let extension = &mut buf[BUFFER_SIZE - n..BUFFER_SIZE - n + len];
+    let s2 = utils::inner_product(extension, extension);
+    if s1 <= 0.2 * s2 || s2.is_nan() {
+        for x in extension.iter_mut() {
+            *x = 0.0;
+        }
+    } else if s1 < s2 {
+        let ratio = ((s1 + 1.0) / (s2 + 1.0)).sqrt();
+        for (i, x) in extension.iter_mut().enumerate() {
+            *x *= if i < overlap {
+                1.0 - st.mode.window[i] * (1.0 - ratio)
+            } else {
+                ratio
+            };
+        }
+    }
+
+    // The postfilter is applied again after the MDCT overlap of the next frame, so the overlap
+    // is prefiltered, then folded as the MDCT would to blend with the next frame.
+    let mut etmp = [0.0; consts::WINDOW_SIZE];
+    let period = std::cmp::max(st.pitch, COMBFILTER_MINPERIOD);
+    opus_decoder::comb_filter_inverse(&buf[BUFFER_SIZE - period - 2..], &mut etmp,
+                                      period, st.gain, st.tapset);
+    let window = &st.mode.window;
+    for i in 0..overlap / 2 {
+        buf[BUFFER_SIZE + i] = window[i] * etmp[overlap - 1 - i] + window[overlap - i - 1] * etmp[i];
+    }
+}
+
+/// Noise concealment, also used as comfort noise in DTX.
+///
+/// The band energies of the last good frame are decayed towards the background noise level, by
+/// 1.5 (in base-2 log units) for the first lost frame and 0.5 for each following one, and the
+/// bands are filled with normalised noise. The result is synthesised as a regular frame, and run
+/// through the postfilter with the last parameters.
+unsafe fn conceal_noise(st: &mut OpusDecoder) {
+    let decay = if st.loss_count == 0 { 1.5 } else { 0.5 };
+    for i in 0..2 * consts::NUM_BANDS {
+        st.bands[i] = st.background_bands[i].max(st.bands[i] - decay);
+    }
+
+    for c in 0..st.channels {
+        for i in 0..BUFFER_SIZE - consts::FRAME_SIZE + consts::WINDOW_SIZE / 2 {
+            st.decode_mem[c][i] = st.decode_mem[c][i + consts::FRAME_SIZE];
+        }
+    }
+
+    let mut x = [0.0; consts::FRAME_SIZE * 2];
+    let mut seed = st.seed;
+    for c in 0..st.channels {
+        for i in 0..consts::NUM_BANDS {
+            let band = &mut x[consts::FRAME_SIZE * c + 8 * consts::BANDS[i]..
+                              consts::FRAME_SIZE * c + 8 * consts::BANDS[i + 1]];
+            for j in band.iter_mut() {
+                seed = utils::lcg_rand(seed);
+                *j = (seed as i32 >> 20) as f32;
+            }
+            utils::renormalise(band, 1.0);
+        }
+    }
+    st.seed = seed;
+
+    let channels = st.channels;
+    opus_decoder::celt_synthesis(st, &mut x, channels, false);
+
+    for c in 0..st.channels {
+        opus_decoder::comb_filter_const(&mut st.decode_mem[c][BUFFER_SIZE - consts::FRAME_SIZE - st.pitch - 2..],
+                                        consts::FRAME_SIZE, st.pitch, st.gain, st.tapset);
+    }
+}
+
+/// Packet loss concealment of the CELT layer, after `celt_decode_lost` in libopus.
+///
+/// For the first few lost frames, the signal is extended periodically from the pitch found in
+/// the decoded history: LPC analysis of the last good frame, repetition of the excitation with a
+/// decaying gain, and a lag drifting by 1% per lost frame. Longer losses, and gaps during DTX,
+/// fade into comfort noise shaped by the last band energies.
+///
+/// This conceals the output of the CELT decoder only. The SILK concealment and comfort noise
+/// generation (`silk_PLC` and `silk_CNG`) belong to the SILK layer, which this decoder lacks.
+///
+/// # Safety
+///
+/// As for `celt_synthesis`.
+pub unsafe fn celt_decode_lost(st: &mut OpusDecoder, pcm: &mut [f32]) {
+    if st.in_dtx || st.loss_count >= MAX_PERIODIC_LOSSES {
+        conceal_noise(st);
+    } else {
+        let fade = if st.loss_count == 0 {
+            let (pitch, correlation) = pitch_search(&st.decode_mem[..st.channels]);
+            st.plc_pitch = pitch;
+            st.plc_voiced = correlation > VOICING_THRESHOLD;
+            for c in 0..st.channels {
+                let mut ac = [0.0; LPC_ORDER + 1];
+                autocorr(&st.decode_mem[c][BUFFER_SIZE - MAX_PERIOD..BUFFER_SIZE], &mut ac,
+                         &st.mode.window);
+                // Add a noise floor of -40 dB, and use lag windowing to stabilise the recursion.
+                ac[0] *= 1.0001;
+                for (i, a) in ac.iter_mut().enumerate().skip(1) {
+                    *a -= *a * (0.008 * 0.008) * (i * i) as f32;
+                }
+                lpc(&ac, &mut st.plc_lpc[c]);
+            }
+            1.0
+        } else {
+            let pitch = st.plc_pitch as f32 * (1.0 + PITCH_DRIFT);
+            st.plc_pitch = std::cmp::min(pitch.round() as usize, PITCH_LAG_MAX);
+            0.8
+        };
+        let mut seed = st.seed;
+        for c in 0..st.channels {
+            let (pitch, voiced) = (st.plc_pitch, st.plc_voiced);
+            extrapolate(st, c, pitch, voiced, fade, &mut seed);
+        }
+        st.seed = seed;
+    }
+
+    opus_decoder::deemphasis(&st.decode_mem[..st.channels], pcm, &mut st.preemph_mem);
+    st.plc_energy = utils::inner_product(pcm, pcm);
+    st.loss_count += 1;
+}
+
+/// Smoothing of the first good frame after a loss.
+///
+/// When the decoded frame is louder than the concealment before it, its gain ramps up from the
+/// level of the concealment, as done by `silk_PLC_glue_frames`.
+pub fn glue_frames(st: &OpusDecoder, pcm: &mut [f32]) {
+    let energy = utils::inner_product(pcm, pcm);
+    if energy <= st.plc_energy {
+        return;
+    }
+    let mut gain = (st.plc_energy / energy).sqrt();
+    let slope = 4.0 * (1.0 - gain) / (pcm.len() / st.channels) as f32;
+    for frame in pcm.chunks_mut(st.channels) {
+        for x in frame.iter_mut() {
+            *x *= gain;
+        }
+        gain += slope;
+        if gain > 1.0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    #[test]
+    fn lpc_ar1() {
+        // The autocorrelation of a first-order autoregressive process.
+        let ac: Vec<f32> = (0..5).map(|k| 0.9f32.powi(k)).collect();
+        let mut a = [0.0; 4];
+        lpc(&ac, &mut a);
+        assert!((a[0] + 0.9).abs() < 1e-6);
+        assert!(a[1..].iter().all(|a| a.abs() < 1e-6));
+        // Nothing to predict in silence.
+        lpc(&[0.0; 5], &mut a);
+        assert_eq!(a, [0.0; 4]);
+    }
+
+    #[test]
+    fn fir_iir() {
+        let x: Vec<f32> = (0..100).map(|i| (i as f32 * 0.3).sin()).collect();
+        let a = [-1.2, 0.5, 0.1];
+        let mut y = x.clone();
+        fir(&mut y, &a, &mut [0.0; 3]);
+        assert_eq!(y[1], x[1] + a[0] * x[0]);
+        iir(&mut y, &a, &mut [0.0; 3]);
+        assert!(x.iter().zip(&y).all(|(x, y)| (x - y).abs() < 1e-5));
+    }
+
+    #[test]
+    fn pitch() {
+        let period = 250;
+        let mem: Vec<f32> = (0..BUFFER_SIZE)
+            .map(|i| {
+                let t = (i % period) as f32 / period as f32;
+                (1..6).map(|h| (2.0 * std::f32::consts::PI * h as f32 * t).sin() / h as f32).sum()
+            })
+            .collect();
+        let (pitch, correlation) = pitch_search(&[mem]);
+        assert_eq!(pitch, period);
+        assert!(correlation > 0.99);
+    }
+
+    #[test]
+    fn conceal() {
+        let mut pcm = vec![0.0; 2 * consts::FRAME_SIZE];
+        let energy = |pcm: &[f32]| utils::inner_product(pcm, pcm);
+        // Nothing to conceal before the first packet.
+        let mut st = OpusDecoder::new();
+        st.decode(None, &mut pcm).unwrap();
+        assert_eq!(energy(&pcm), 0.0);
+
+        let data = celt_packet(0);
+        for _ in 0..5 {
+            st.decode(Some(&data), &mut pcm).unwrap();
+        }
+        let decoded = energy(&pcm);
+        st.decode(None, &mut pcm).unwrap();
+        let concealed = energy(&pcm);
+        assert!(concealed > 0.0 && concealed < 2.0 * decoded);
+        // Repeated losses fade out.
+        for _ in 0..20 {
+            st.decode(None, &mut pcm).unwrap();
+        }
+        assert!(energy(&pcm) < 0.01 * concealed);
+        // The first frame after the losses is glued to the concealment.
+        st.decode(Some(&data), &mut pcm).unwrap();
+        assert!(energy(&pcm[..200]) < energy(&pcm[pcm.len() - 200..]));
+    }
+}
@@ -0,0 +1,259 @@
+use error::Error;
+use multistream::OpusMSDecoder;
+use packet;
+
+/// Renderer of the decoded ambisonics to the output, e.g. to a binaural or speaker signal.
+pub trait Renderer {
+    /// Number of output channels.
+    fn channels(&self) -> usize;
+
+    /// Render `samples` samples of the `input_channels` interleaved ambisonic channels of
+    /// `input` into `output`, with `channels()` interleaved channels.
+    fn render(&mut self, input: &[f32], input_channels: usize, samples: usize, output: &mut [f32]);
+}
+
+/// Renderer applying a fixed matrix, such as a decoder to a speaker layout.
+///
+/// `matrix` holds `channels` rows of one gain per input channel.
+pub struct MatrixRenderer {
+    pub channels: usize,
+    pub matrix: Vec<f32>,
+}
+
+impl Renderer for MatrixRenderer {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn render(&mut self, input: &[f32], input_channels: usize, samples: usize, output: &mut [f32]) {
+        for i in 0..samples {
+            let x = &input[i * input_channels..(i + 1) * input_channels];
+            for (c, row) in self.matrix.chunks(input_channels).enumerate().take(self.channels) {
+                output[i * self.channels + c] = row.iter().zip(x).map(|(g, x)| g * x).sum();
+            }
+        }
+    }
+}
+
+/// Order of the ambisonics in a stream of mapping family 2 or 3, and whether two non-diegetic
+/// stereo channels follow the (order + 1)^2 ambisonic channels.
+pub fn ambisonic_order(channels: usize) -> Option<(usize, bool)> {
+    if channels == 0 || channels > 227 {
+        return None;
+    }
+    let order = (channels as f32).sqrt() as usize - 1;
+    let acn_channels = (order + 1) * (order + 1);
+    match channels - acn_channels {
+        0 => Some((order, false)),
+        2 => Some((order, true)),
+        _ => None,
+    }
+}
+
+/// Ambisonics decoder (RFC 8486).
+///
+/// Mapping family 2 carries ambisonic channels in ACN order with SN3D normalisation, mapped to
+/// the streams as in any multistream packet, optionally followed by a non-diegetic stereo pair.
+/// Mapping family 3 replaces the mapping table with a demixing matrix, which recovers the
+/// ambisonic channels from the decoded streams. The output may be further rendered by a
+/// `Renderer`.
+pub struct OpusProjectionDecoder {
+    /// Number of ambisonic (and non-diegetic) channels.
+    pub channels: usize,
+    pub decoder: OpusMSDecoder,
+    /// `channels` rows of one gain per decoded channel, for family 3.
+    pub demixing_matrix: Option<Vec<f32>>,
+    renderer: Option<Box<dyn Renderer>>,
+    decoded: Vec<f32>,
+    demixed: Vec<f32>,
+}
+
+impl OpusProjectionDecoder {
+    /// Decoder for mapping family 2.
+    pub fn new(channels: usize,
+               streams: usize,
+               coupled_streams: usize,
+               mapping: &[u8])
+               -> Result<OpusProjectionDecoder, Error> {
+        if ambisonic_order(channels).is_none() {
+            return Err(Error::BadArg);
+        }
+        let decoder = OpusMSDecoder::new(channels, streams, coupled_streams, mapping)?;
+        Ok(OpusProjectionDecoder {
+            channels,
+            decoder,
+            demixing_matrix: None,
+            renderer: None,
+            decoded: vec![],
+            demixed: vec![],
+        })
+    }
+
+    /// Decoder for mapping family 3.
+    ///
+    /// The demixing matrix, as found in the identification header, has `channels` rows and
+    /// `streams + coupled_streams` columns of signed 16-bit little-endian Q15 gains, in
+    /// column-major order.
+    pub fn with_demixing_matrix(channels: usize,
+                                streams: usize,
+                                coupled_streams: usize,
+                                demixing_matrix: &[u8])
+                                -> Result<OpusProjectionDecoder, Error> {
+        let decoded_channels = streams + coupled_streams;
+        if ambisonic_order(channels).is_none() ||
+           demixing_matrix.len() != 2 * channels * decoded_channels {
+            return Err(Error::BadArg);
+        }
+        let mapping: Vec<u8> = (0..decoded_channels as u8).collect();
+        let decoder = OpusMSDecoder::new(decoded_channels, streams, coupled_streams, &mapping)?;
+        let mut matrix = vec![0.0; channels * decoded_channels];
+        for (i, gain) in demixing_matrix.chunks(2).enumerate() {
+            let (row, col) = (i % channels, i / channels);
+            matrix[row * decoded_channels + col] = i16::from_le_bytes([gain[0], gain[1]]) as f32 /
+                                                   32768.0;
+        }
+        Ok(OpusProjectionDecoder {
+            channels,
+            decoder,
+            demixing_matrix: Some(matrix),
+            renderer: None,
+            decoded: vec![],
+            demixed: vec![],
+        })
+    }
+
+    /// Render the ambisonics with `renderer`, or output them as they are with `None`.
+    pub fn set_renderer(&mut self, renderer: Option<Box<dyn Renderer>>) {
+        self.renderer = renderer;
+    }
+
+    /// Number of interleaved channels in the output of `decode`.
+    pub fn output_channels(&self) -> usize {
+        match self.renderer {
+            Some(ref renderer) => renderer.channels(),
+            None => self.channels,
+        }
+    }
+
+    /// Decode a packet into `pcm`, or conceal a lost packet when `data` is `None`. Returns the
+    /// number of samples per channel.
+    pub fn decode(&mut self, data: Option<&[u8]>, pcm: &mut [f32]) -> Result<usize, Error> {
+        let decoded_channels = self.decoder.channels;
+        self.decoded.resize(decoded_channels * packet::MAX_PACKET_DURATION, 0.0);
+        let n = self.decoder.decode(data, &mut self.decoded)?;
+        if pcm.len() < self.output_channels() * n {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let ambisonics = match self.demixing_matrix {
+            Some(ref matrix) => {
+                self.demixed.resize(self.channels * n, 0.0);
+                for i in 0..n {
+                    let x = &self.decoded[i * decoded_channels..(i + 1) * decoded_channels];
+                    for (c, row) in matrix.chunks(decoded_channels).enumerate() {
+                        self.demixed[i * self.channels + c] =
+                            row.iter().zip(x).map(|(g, x)| g * x).sum();
+                    }
+                }
+                &self.demixed[..]
+            }
+            None => &self.decoded[..],
+        };
+
+        match self.renderer {
+            Some(ref mut renderer) => renderer.render(ambisonics, self.channels, n, pcm),
+            None => pcm[..self.channels * n].copy_from_slice(&ambisonics[..self.channels * n]),
+        }
+        Ok(n)
+    }
+
+    pub fn reset(&mut self) {
+        self.decoder.reset();
+    }
+
+    /// Gain applied to the decoded channels, before the demixing, in Q7.8 dB.
+    pub fn set_gain(&mut self, gain: i16) {
+        self.decoder.set_gain(gain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::tests::celt_packet;
+
+    /// A packet of two stereo CELT streams of 20 ms.
+    fn packet() -> Vec<u8> {
+        let mut data = celt_packet(0);
+        // Self-delimited, with the size of its frame.
+        data.insert(1, 160);
+        data.extend(celt_packet(1));
+        data
+    }
+
+    #[test]
+    fn order() {
+        assert_eq!(ambisonic_order(1), Some((0, false)));
+        assert_eq!(ambisonic_order(4), Some((1, false)));
+        assert_eq!(ambisonic_order(6), Some((1, true)));
+        assert_eq!(ambisonic_order(9), Some((2, false)));
+        assert_eq!(ambisonic_order(227), Some((14, true)));
+        assert_eq!(ambisonic_order(3), Some((0, true)));
+        for &channels in &[0, 5, 7, 8, 228] {
+            assert_eq!(ambisonic_order(channels), None);
+        }
+    }
+
+    #[test]
+    fn demixing_matrix() {
+        // Channel c is half of decoded channel 3 - c, and the last one also takes channel 3.
+        let mut matrix = vec![0.0; 16];
+        for c in 0..4 {
+            matrix[4 * (3 - c) + c] = 0.5;
+        }
+        matrix[3 * 4 + 3] = -0.25;
+        let mut bytes = vec![];
+        for col in 0..4 {
+            for row in 0..4 {
+                let gain = (matrix[row * 4 + col] * 32768.0) as i16;
+                bytes.extend_from_slice(&gain.to_le_bytes());
+            }
+        }
+        assert!(OpusProjectionDecoder::with_demixing_matrix(4, 2, 2, &bytes[2..]).is_err());
+        let mut decoder = OpusProjectionDecoder::with_demixing_matrix(4, 2, 2, &bytes).unwrap();
+        assert_eq!(decoder.demixing_matrix, Some(matrix));
+        let mut pcm = vec![0.0; 4 * 960];
+        assert_eq!(decoder.decode(Some(&packet()), &mut pcm), Ok(960));
+
+        let mut plain = OpusProjectionDecoder::new(4, 2, 2, &[0, 1, 2, 3]).unwrap();
+        let mut decoded = vec![0.0; 4 * 960];
+        plain.decode(Some(&packet()), &mut decoded).unwrap();
+        for (y, x) in pcm.chunks(4).zip(decoded.chunks(4)) {
+            assert_eq!(y[..3], [0.5 * x[3], 0.5 * x[2], 0.5 * x[1]]);
+            assert!((y[3] - (0.5 * x[0] - 0.25 * x[3])).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn renderer() {
+        assert!(OpusProjectionDecoder::new(5, 3, 2, &[0, 1, 2, 3, 4]).is_err());
+        let mut decoder = OpusProjectionDecoder::new(4, 2, 2, &[0, 1, 2, 3]).unwrap();
+        let mut decoded = vec![0.0; 4 * 960];
+        decoder.decode(Some(&packet()), &mut decoded).unwrap();
+        decoder.reset();
+
+        // The sum and the difference of the first two channels.
+        decoder.set_renderer(Some(Box::new(MatrixRenderer {
+            channels: 2,
+            matrix: vec![1.0, 1.0, 0.0, 0.0, 1.0, -1.0, 0.0, 0.0],
+        })));
+        assert_eq!(decoder.output_channels(), 2);
+        let mut pcm = vec![0.0; 2 * 960];
+        assert_eq!(decoder.decode(Some(&packet()), &mut pcm), Ok(960));
+        for (y, x) in pcm.chunks(2).zip(decoded.chunks(4)) {
+            assert_eq!(y, [x[0] + x[1], x[0] - x[1]]);
+        }
+        assert_eq!(decoder.decode(Some(&packet()), &mut pcm[..960]),
+                   Err(Error::BufferTooSmall));
+    }
+}
@@ -1,6 +1,10 @@
+use consts;
 use entdec;
+use std;
 
 const LAPLACE_NMIN: u32 = 16;
+const MAX_FINE_BITS: i32 = 8;
+const SMALL_ENERGY_ICDF: [u8; 3] = [2, 1, 0];
 
 fn ec_laplace_decode(ec: &mut entdec::EntropyCoder, fs: u32, decay: u32) -> i32 {
     let fm = ec.decode(32768);
@@ -27,7 +31,7 @@ fn ec_laplace_decode(ec: &mut entdec::EntropyCoder, fs: u32, decay: u32) -> i32
     } else {
         fl += fs;
     }
-    ec.update(fl, fl + fs, 32768);
+    ec.update(fl, std::cmp::min(fl + fs, 32768), 32768);
     return val;
 }
 
@@ -47,27 +51,42 @@ const DECAY_INTRA: [u32; 21] = [178, 114, 82, 83, 82, 62, 72, 67, 73, 72, 55, 52
 const DECAY_INTER: [u32; 21] = [121, 66, 43, 40, 44, 32, 36, 33, 33, 34, 21, 23, 20, 25, 26, 21,
                                 16, 13, 10, 13, 15];
 
-pub fn unquant_coarse_energy(bands: &mut [f32], intra: bool, ec: &mut entdec::EntropyCoder) {
+pub fn unquant_coarse_energy(bands: &mut [f32],
+                             channels: usize,
+                             intra: bool,
+                             ec: &mut entdec::EntropyCoder) {
     let (alpha, beta) = if intra {
         (ALPHA_INTRA, BETA_INTRA)
     } else {
         (ALPHA_INTER, BETA_INTER)
     };
 
+    let budget = ec.buffer.len() as i32 * 8;
     let mut prev = [0.0; 2];
-    for i in 0..bands.len() / 2 {
-        for c in 0..2 {
-            let q = ec_laplace_decode(ec,
-                                      if intra {
-                                          PROB_INTRA[i] << 7
-                                      } else {
-                                          PROB_INTER[i] << 7
-                                      },
-                                      if intra {
-                                          DECAY_INTRA[i] << 6
-                                      } else {
-                                          DECAY_INTER[i] << 6
-                                      });
+    for i in 0..consts::NUM_BANDS {
+        for c in 0..channels {
+            // Near the end of the frame, smaller codes are used, down to none.
+            let left = budget - ec.tell() as i32;
+            let q = if left >= 15 {
+                ec_laplace_decode(ec,
+                                  if intra {
+                                      PROB_INTRA[i] << 7
+                                  } else {
+                                      PROB_INTER[i] << 7
+                                  },
+                                  if intra {
+                                      DECAY_INTRA[i] << 6
+                                  } else {
+                                      DECAY_INTER[i] << 6
+                                  })
+            } else if left >= 2 {
+                let q = ec.decode_icdf(&SMALL_ENERGY_ICDF, 2) as i32;
+                (q >> 1) ^ -(q & 1)
+            } else if left >= 1 {
+                -(ec.decode_bit_logp(1) as i32)
+            } else {
+                -1
+            };
             if bands[21 * c + i] < -9.0 {
                 bands[21 * c + i] = -9.0;
             }
@@ -77,9 +96,12 @@ pub fn unquant_coarse_energy(bands: &mut [f32], intra: bool, ec: &mut entdec::En
     }
 }
 
-pub fn unquant_fine_energy(bands: &mut [f32], fine_quant: &[i32], ec: &mut entdec::EntropyCoder) {
-    for i in 0..bands.len() / 2 {
-        for c in 0..2 {
+pub fn unquant_fine_energy(bands: &mut [f32],
+                           channels: usize,
+                           fine_quant: &[i32],
+                           ec: &mut entdec::EntropyCoder) {
+    for i in 0..consts::NUM_BANDS {
+        for c in 0..channels {
             let q = ec.decode_bits(fine_quant[i] as usize);
             bands[21 * c + i] += (q as f32 + 0.5) / (1 << fine_quant[i]) as f32 - 0.5;
         }
@@ -87,19 +109,20 @@ pub fn unquant_fine_energy(bands: &mut [f32], fine_quant: &[i32], ec: &mut entde
 }
 
 pub fn unquant_energy_finalise(bands: &mut [f32],
+                               channels: usize,
                                fine_quant: &[i32],
                                fine_priority: &[u32],
                                mut bits_left: i32,
                                ec: &mut entdec::EntropyCoder) {
     for prio in 0..2 {
-        for i in 0..bands.len() / 2 {
-            if bits_left < 2 {
+        for i in 0..consts::NUM_BANDS {
+            if bits_left < channels as i32 {
                 return;
             }
-            if fine_priority[i] != prio {
+            if fine_quant[i] >= MAX_FINE_BITS || fine_priority[i] != prio {
                 continue;
             }
-            for c in 0..2 {
+            for c in 0..channels {
                 let q = ec.decode_bits(1);
                 bands[21 * c + i] += (q as f32 - 0.5) / (1 << (fine_quant[i] + 1)) as f32;
                 bits_left -= 1;
@@ -0,0 +1,306 @@
+use error::Error;
+use std;
+
+/// Above this number of phases, the filter is interpolated between this many.
+const MAX_PHASES: usize = 1024;
+
+/// Trade-off between the quality of the filter and its cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// 32 taps, Kaiser window with beta = 5 (about 50 dB of stopband attenuation).
+    Low,
+    /// 64 taps, beta = 7 (about 70 dB).
+    Medium,
+    /// 128 taps, beta = 9.5 (about 95 dB).
+    High,
+}
+
+impl Quality {
+    /// Taps per phase when upsampling, Kaiser window beta, and cutoff relative to the Nyquist
+    /// frequency of the lower rate.
+    fn params(self) -> (usize, f64, f64) {
+        match self {
+            Quality::Low => (32, 5.0, 0.85),
+            Quality::Medium => (64, 7.0, 0.91),
+            Quality::High => (128, 9.5, 0.95),
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Windowed sinc of cutoff `fc` at `t` input samples from its centre, with `half` samples on
+/// each side.
+fn sinc(t: f64, fc: f64, half: f64, beta: f64) -> f64 {
+    let x = t / half;
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let window = bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta);
+    let y = std::f64::consts::PI * fc * t;
+    let sinc = if y.abs() < 1e-9 { 1.0 } else { y.sin() / y };
+    fc * sinc * window
+}
+
+/// Polyphase windowed-sinc sample-rate converter of interleaved samples.
+///
+/// The ratio of the rates is reduced to `up / down`: the input is conceptually upsampled by
+/// `up`, low-pass filtered, and decimated by `down`. Output sample `j` is the input at time
+/// `j * down / up`, computed with the phase `(j * down) % up` of the filter over the `taps`
+/// input samples around it. Each phase is a row of the filter table, precomputed for up to
+/// `MAX_PHASES` phases, and linearly interpolated between rows beyond.
+///
+/// The output is aligned with the input: the filter needs `latency()` input samples after the
+/// time of an output sample, which are held back until more input arrives, or until `flush`
+/// pads the input with silence.
+pub struct Resampler {
+    channels: usize,
+    up: usize,
+    down: usize,
+    taps: usize,
+    /// Number of rows of the table: `up`, or `MAX_PHASES + 1` when interpolated.
+    rows: usize,
+    table: Vec<f32>,
+    /// Pending input, starting with the oldest sample of the filter of the next output.
+    buffer: Vec<f32>,
+    /// Phase of the next output, in 0..up.
+    phase: usize,
+    input_frames: u64,
+    output_frames: u64,
+    coefficients: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize,
+               in_rate: u32,
+               out_rate: u32,
+               quality: Quality)
+               -> Result<Resampler, Error> {
+        if channels == 0 || in_rate == 0 || out_rate == 0 {
+            return Err(Error::BadArg);
+        }
+        let g = gcd(in_rate, out_rate);
+        let up = (out_rate / g) as usize;
+        let down = (in_rate / g) as usize;
+        let (taps, beta, cutoff) = quality.params();
+        // When decimating, the filter is stretched to keep its transition band relative to the
+        // output rate.
+        let (taps, fc) = if down > up {
+            let taps = (taps * down).div_ceil(up);
+            (taps + (taps & 1), cutoff * up as f64 / down as f64)
+        } else {
+            (taps, cutoff)
+        };
+        let rows = if up <= MAX_PHASES { up } else { MAX_PHASES + 1 };
+        let phases = if up <= MAX_PHASES { up } else { MAX_PHASES };
+        let half = taps as f64 / 2.0;
+        let delay = taps / 2 - 1;
+        let mut table = Vec::with_capacity(rows * taps);
+        for row in 0..rows {
+            let frac = row as f64 / phases as f64;
+            let start = table.len();
+            table.extend((0..taps).map(|k| {
+                sinc(delay as f64 - k as f64 + frac, fc, half, beta) as f32
+            }));
+            // Unity gain at DC for every phase.
+            let sum: f32 = table[start..].iter().sum();
+            for h in &mut table[start..] {
+                *h /= sum;
+            }
+        }
+        let mut resampler = Resampler {
+            channels,
+            up,
+            down,
+            taps,
+            rows,
+            table,
+            buffer: vec![],
+            phase: 0,
+            input_frames: 0,
+            output_frames: 0,
+            coefficients: vec![0.0; taps],
+        };
+        resampler.reset();
+        Ok(resampler)
+    }
+
+    /// Number of input samples per channel needed after the time of an output sample, before
+    /// it can be computed.
+    pub fn latency(&self) -> usize {
+        self.taps / 2
+    }
+
+    /// Clear the pending input, for a new stream.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.buffer.resize((self.taps / 2 - 1) * self.channels, 0.0);
+        self.phase = 0;
+        self.input_frames = 0;
+        self.output_frames = 0;
+    }
+
+    /// Number of output samples per channel for the input so far, once flushed.
+    fn expected_output(&self) -> u64 {
+        (self.input_frames * self.up as u64).div_ceil(self.down as u64)
+    }
+
+    /// Filter coefficients of the current phase.
+    fn load_coefficients(&mut self) {
+        let taps = self.taps;
+        if self.rows == self.up {
+            let row = &self.table[self.phase * taps..(self.phase + 1) * taps];
+            self.coefficients.copy_from_slice(row);
+        } else {
+            let position = self.phase as f64 * MAX_PHASES as f64 / self.up as f64;
+            let row = position as usize;
+            let a = (position - row as f64) as f32;
+            let (first, second) = self.table[row * taps..(row + 2) * taps].split_at(taps);
+            for (h, (&x, &y)) in self.coefficients.iter_mut().zip(first.iter().zip(second)) {
+                *h = x + a * (y - x);
+            }
+        }
+    }
+
+    /// Compute the outputs for which the pending input is sufficient, up to `limit`.
+    fn run(&mut self, output: &mut Vec<f32>, limit: u64) {
+        let channels = self.channels;
+        let frames = self.buffer.len() / channels;
+        let mut index = 0;
+        while index + self.taps <= frames && self.output_frames < limit {
+            self.load_coefficients();
+            let input = &self.buffer[index * channels..(index + self.taps) * channels];
+            for c in 0..channels {
+                let mut y = 0.0;
+                for (k, &h) in self.coefficients.iter().enumerate() {
+                    y += h * input[k * channels + c];
+                }
+                output.push(y);
+            }
+            self.output_frames += 1;
+            self.phase += self.down;
+            index += self.phase / self.up;
+            self.phase %= self.up;
+        }
+        self.buffer.drain(..index * channels);
+    }
+
+    /// Resample interleaved `input`, appending the output samples to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.input_frames += (input.len() / self.channels) as u64;
+        self.buffer.extend_from_slice(input);
+        self.run(output, u64::MAX);
+    }
+
+    /// Pad the input with silence to compute the remaining output, so that the output has as
+    /// many samples as the input at the output rate, and start a new stream.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let limit = self.expected_output();
+        let len = self.buffer.len() + self.taps * self.channels;
+        self.buffer.resize(len, 0.0);
+        self.run(output, limit);
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * std::f64::consts::PI * frequency * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    fn resample(input: &[f32], in_rate: u32, out_rate: u32, quality: Quality) -> Vec<f32> {
+        let mut resampler = Resampler::new(1, in_rate, out_rate, quality).unwrap();
+        let mut output = vec![];
+        resampler.process(input, &mut output);
+        resampler.flush(&mut output);
+        output
+    }
+
+    /// Largest difference with a sine of `frequency`, away from the ends.
+    fn sine_error(output: &[f32], frequency: f64, rate: u32) -> f32 {
+        let expected = sine(frequency, rate, output.len());
+        let margin = output.len() / 10;
+        output[margin..output.len() - margin]
+            .iter()
+            .zip(&expected[margin..])
+            .map(|(x, y)| (x - y).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(Resampler::new(0, 48000, 44100, Quality::Low).is_err());
+        assert!(Resampler::new(1, 0, 44100, Quality::Low).is_err());
+        assert!(Resampler::new(1, 48000, 0, Quality::Low).is_err());
+    }
+
+    #[test]
+    fn sines() {
+        for &(in_rate, out_rate) in &[(48000, 48000), (48000, 44100), (8000, 48000),
+                                      (48000, 16000), (44100, 48000), (48000, 44101)] {
+            for &quality in &[Quality::Low, Quality::Medium, Quality::High] {
+                let output = resample(&sine(1000.0, in_rate, 9600), in_rate, out_rate, quality);
+                let len = (9600 * out_rate as u64).div_ceil(in_rate as u64) as usize;
+                assert_eq!(output.len(), len);
+                let tolerance = match quality {
+                    Quality::Low => 1e-2,
+                    Quality::Medium => 1e-3,
+                    Quality::High => 1e-4,
+                };
+                let error = sine_error(&output, 1000.0, out_rate);
+                assert!(error < tolerance, "{} -> {}, {:?}: {}", in_rate, out_rate, quality, error);
+            }
+        }
+    }
+
+    #[test]
+    fn stopband() {
+        // A tone above the output Nyquist frequency is removed.
+        let output = resample(&sine(12000.0, 48000, 9600), 48000, 16000, Quality::High);
+        assert!(output[400..2800].iter().all(|x| x.abs() < 1e-4));
+    }
+
+    #[test]
+    fn chunks_and_channels() {
+        let left = sine(1000.0, 48000, 4800);
+        let right = sine(300.0, 48000, 4800);
+        let input: Vec<f32> = left.iter().zip(&right).flat_map(|(&l, &r)| vec![l, r]).collect();
+        let mut resampler = Resampler::new(2, 48000, 44100, Quality::Medium).unwrap();
+        let mut output = vec![];
+        for chunk in input.chunks(2 * 77) {
+            resampler.process(chunk, &mut output);
+        }
+        resampler.flush(&mut output);
+        assert_eq!(output.len(), 2 * 4410);
+        let left: Vec<f32> = output.iter().step_by(2).cloned().collect();
+        let right: Vec<f32> = output.iter().skip(1).step_by(2).cloned().collect();
+        assert_eq!(left, resample(&sine(1000.0, 48000, 4800), 48000, 44100, Quality::Medium));
+        assert_eq!(right, resample(&sine(300.0, 48000, 4800), 48000, 44100, Quality::Medium));
+
+        // After the flush, a new stream starts.
+        let mut second = vec![];
+        resampler.process(&input, &mut second);
+        resampler.flush(&mut second);
+        assert_eq!(second, output);
+    }
+}
@@ -0,0 +1,647 @@
+use error::Error;
+use opus_decoder::OpusDecoder;
+use packet;
+use std;
+use std::collections::BTreeMap;
+
+/// Clock rate of the RTP timestamps of Opus, whatever the coded bandwidth (RFC 7587, 4.1).
+pub const CLOCK_RATE: u32 = 48000;
+
+/// Encoding name and parameters of the a=rtpmap attribute, which are always 48 kHz and 2 channels
+/// (RFC 7587, 7).
+pub const RTPMAP: &str = "opus/48000/2";
+
+/// Sequence numbers further than this from the expected one restart the stream.
+const MAX_MISORDER: u64 = 1000;
+
+/// 5.1. RTP Fixed Header Fields (RFC 3550)
+///
+///      0                   1                   2                   3
+///      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |V=2|P|X|  CC   |M|     PT      |       sequence number         |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |                           timestamp                           |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///     |           synchronization source (SSRC) identifier            |
+///     +=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+=+
+///     |            contributing source (CSRC) identifiers             |
+///     |                             ....                              |
+///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    /// Set on the first packet of a talkspurt, after DTX.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub csrc: Vec<u32>,
+    /// A single Opus packet (RFC 7587, 4.2).
+    pub payload: &'a [u8],
+}
+
+fn u16_be(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn u32_be(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+impl<'a> RtpPacket<'a> {
+    /// Parse an RTP packet, skipping its header extension and padding.
+    pub fn parse(data: &'a [u8]) -> Result<RtpPacket<'a>, Error> {
+        if data.len() < 12 || data[0] >> 6 != 2 {
+            return Err(Error::InvalidPacket);
+        }
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0xf) as usize;
+        let mut pos = 12 + 4 * csrc_count;
+        if data.len() < pos {
+            return Err(Error::InvalidPacket);
+        }
+        let csrc = data[12..pos].chunks(4).map(u32_be).collect();
+        if extension {
+            if data.len() < pos + 4 {
+                return Err(Error::InvalidPacket);
+            }
+            pos += 4 + 4 * u16_be(&data[pos + 2..]) as usize;
+        }
+        let mut end = data.len();
+        if padding {
+            end = end.saturating_sub(data[end - 1] as usize);
+        }
+        if end < pos {
+            return Err(Error::InvalidPacket);
+        }
+        Ok(RtpPacket {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7f,
+            sequence: u16_be(&data[2..]),
+            timestamp: u32_be(&data[4..]),
+            ssrc: u32_be(&data[8..]),
+            csrc,
+            payload: &data[pos..end],
+        })
+    }
+
+    /// Serialise the packet, without header extension nor padding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(12 + 4 * self.csrc.len() + self.payload.len());
+        data.push(0x80 | self.csrc.len() as u8);
+        data.push((self.marker as u8) << 7 | self.payload_type);
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.ssrc.to_be_bytes());
+        for csrc in &self.csrc {
+            data.extend_from_slice(&csrc.to_be_bytes());
+        }
+        data.extend_from_slice(self.payload);
+        data
+    }
+}
+
+/// Writer of the RTP packets of an Opus stream.
+///
+/// The timestamp advances by the duration of each packet, read from its TOC byte. When DTX
+/// stops the transmission, `skip` advances it over the untransmitted audio, and the next packet
+/// starts a talkspurt with the marker bit set (RFC 7587, 4.1).
+pub struct RtpPacketizer {
+    pub payload_type: u8,
+    pub ssrc: u32,
+    /// Sequence number and timestamp of the next packet.
+    pub sequence: u16,
+    pub timestamp: u32,
+    marker: bool,
+}
+
+impl RtpPacketizer {
+    /// Packetizer starting at the given sequence number and timestamp, which should be random.
+    pub fn new(payload_type: u8, ssrc: u32, sequence: u16, timestamp: u32) -> RtpPacketizer {
+        RtpPacketizer {
+            payload_type,
+            ssrc,
+            sequence,
+            timestamp,
+            marker: true,
+        }
+    }
+
+    /// Wrap an Opus packet in an RTP packet.
+    pub fn packetize(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let duration = packet::duration(payload)?;
+        let data = RtpPacket {
+                       marker: self.marker,
+                       payload_type: self.payload_type,
+                       sequence: self.sequence,
+                       timestamp: self.timestamp,
+                       ssrc: self.ssrc,
+                       csrc: vec![],
+                       payload,
+                   }
+                   .to_bytes();
+        self.marker = false;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(duration as u32);
+        Ok(data)
+    }
+
+    /// Advance the timestamp over `duration` samples not transmitted because of DTX. The next
+    /// packet has the marker bit set.
+    pub fn skip(&mut self, duration: usize) {
+        if duration > 0 {
+            self.timestamp = self.timestamp.wrapping_add(duration as u32);
+            self.marker = true;
+        }
+    }
+}
+
+/// 7. Session Description Protocol (SDP) Considerations (RFC 7587)
+///
+/// The a=fmtp parameters of an Opus payload type. The receiver parameters (`maxplaybackrate`,
+/// `stereo`, `useinbandfec`, `usedtx`) tell what the receiver prefers; `sprop-stereo` tells
+/// whether the sender is likely to send stereo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fmtp {
+    /// Maximum output sample rate of the receiver, in Hz.
+    pub maxplaybackrate: u32,
+    /// The receiver prefers stereo.
+    pub stereo: bool,
+    /// The sender is likely to send stereo.
+    pub sprop_stereo: bool,
+    /// The receiver can decode in-band FEC.
+    pub useinbandfec: bool,
+    /// The receiver prefers DTX.
+    pub usedtx: bool,
+}
+
+impl Default for Fmtp {
+    fn default() -> Fmtp {
+        Fmtp {
+            maxplaybackrate: CLOCK_RATE,
+            stereo: false,
+            sprop_stereo: false,
+            useinbandfec: false,
+            usedtx: false,
+        }
+    }
+}
+
+impl Fmtp {
+    /// Parse the parameters of an a=fmtp attribute, after the payload type, e.g.
+    /// "maxplaybackrate=16000; stereo=1". Unknown parameters are ignored, and missing ones take
+    /// their default value.
+    pub fn parse(params: &str) -> Result<Fmtp, Error> {
+        let mut fmtp: Fmtp = Default::default();
+        for param in params.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut split = param.splitn(2, '=');
+            let name = split.next().unwrap_or("").trim();
+            let value = split.next().ok_or(Error::BadArg)?.trim();
+            let flag = || match value {
+                "0" => Ok(false),
+                "1" => Ok(true),
+                _ => Err(Error::BadArg),
+            };
+            match name {
+                "maxplaybackrate" => {
+                    fmtp.maxplaybackrate = value.parse().map_err(|_| Error::BadArg)?
+                }
+                "stereo" => fmtp.stereo = flag()?,
+                "sprop-stereo" => fmtp.sprop_stereo = flag()?,
+                "useinbandfec" => fmtp.useinbandfec = flag()?,
+                "usedtx" => fmtp.usedtx = flag()?,
+                _ => {}
+            }
+        }
+        Ok(fmtp)
+    }
+}
+
+impl std::fmt::Display for Fmtp {
+    /// Format the parameters that differ from their default value.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut params = vec![];
+        if self.maxplaybackrate != CLOCK_RATE {
+            params.push(format!("maxplaybackrate={}", self.maxplaybackrate));
+        }
+        for &(name, value) in &[("stereo", self.stereo),
+                                ("sprop-stereo", self.sprop_stereo),
+                                ("useinbandfec", self.useinbandfec),
+                                ("usedtx", self.usedtx)] {
+            if value {
+                params.push(format!("{}=1", name));
+            }
+        }
+        f.write_str(&params.join("; "))
+    }
+}
+
+struct Entry {
+    timestamp: i64,
+    marker: bool,
+    payload: Vec<u8>,
+}
+
+/// Counters of the jitter buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitterStats {
+    /// Packets decoded on time.
+    pub decoded: u64,
+    /// Packets missing at their playout time, and concealed.
+    pub lost: u64,
+    /// Packets arriving after their playout time, or duplicates, and dropped.
+    pub late: u64,
+}
+
+/// Adaptive jitter buffer for the Opus packets of an RTP stream.
+///
+/// Packets are reordered by sequence number and played out in order, one per call to `get`,
+/// which is driven by the output clock. A missing packet is concealed, without recovery from
+/// the FEC data of the next one, which the decoder does not support. A gap in the timestamps
+/// without a gap in the sequence numbers is DTX, and concealed up to the next packet.
+///
+/// The buffer waits for `delay` samples of audio before starting to play, and again at the
+/// start of each talkspurt, as flagged by the marker bit. The delay follows the interarrival
+/// jitter of RFC 3550, section 6.4.1, within the bounds given at construction.
+pub struct JitterBuffer {
+    packets: BTreeMap<u64, Entry>,
+    /// Extended sequence number of the next packet to play.
+    next: Option<u64>,
+    /// Highest extended sequence number received.
+    highest: Option<u64>,
+    /// Extended timestamp of the highest sequence number.
+    highest_timestamp: i64,
+    /// Timestamp of the next sample to play.
+    playout: i64,
+    /// Relative transit time of the last packet, and interarrival jitter, in samples.
+    transit: Option<i64>,
+    jitter: f64,
+    pub min_delay: usize,
+    pub max_delay: usize,
+    delay: usize,
+    buffering: bool,
+    /// Duration of the last packet played.
+    duration: usize,
+    pub stats: JitterStats,
+}
+
+impl JitterBuffer {
+    /// Buffer with a delay adapting between `min_delay` and `max_delay` samples at 48 kHz.
+    pub fn new(min_delay: usize, max_delay: usize) -> JitterBuffer {
+        JitterBuffer {
+            packets: BTreeMap::new(),
+            next: None,
+            highest: None,
+            highest_timestamp: 0,
+            playout: 0,
+            transit: None,
+            jitter: 0.0,
+            min_delay,
+            max_delay,
+            delay: min_delay,
+            buffering: true,
+            duration: 960,
+            stats: Default::default(),
+        }
+    }
+
+    /// Current target delay, in samples.
+    pub fn delay(&self) -> usize {
+        self.delay
+    }
+
+    /// Interarrival jitter, in samples.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Duration of the audio held, in samples.
+    pub fn buffered(&self) -> usize {
+        match (self.packets.keys().next(), self.highest) {
+            (Some(first), Some(_)) => {
+                let start = std::cmp::max(self.packets[first].timestamp, self.playout);
+                std::cmp::max(0, self.highest_timestamp + self.duration as i64 - start) as usize
+            }
+            _ => 0,
+        }
+    }
+
+    /// Forget all the packets, e.g. when the SSRC changes.
+    pub fn reset(&mut self) {
+        *self = JitterBuffer {
+            stats: self.stats,
+            ..JitterBuffer::new(self.min_delay, self.max_delay)
+        };
+    }
+
+    /// Add a packet received at time `arrival`, in samples at 48 kHz from any origin.
+    pub fn push(&mut self, packet: &RtpPacket, arrival: u32) {
+        let (sequence, timestamp) = match self.highest {
+            None => (1 << 32 | packet.sequence as u64, 1 << 32 | packet.timestamp as i64),
+            Some(highest) => {
+                let delta = packet.sequence.wrapping_sub(highest as u16) as i16 as i64;
+                let timestamp = packet.timestamp.wrapping_sub(self.highest_timestamp as u32);
+                (highest.wrapping_add(delta as u64),
+                 self.highest_timestamp + timestamp as i32 as i64)
+            }
+        };
+        if let Some(next) = self.next {
+            if (sequence as i64 - next as i64).unsigned_abs() > MAX_MISORDER {
+                self.reset();
+                return self.push(packet, arrival);
+            }
+            if sequence < next || self.packets.contains_key(&sequence) {
+                self.stats.late += 1;
+                return;
+            }
+        }
+
+        // J(i) = J(i-1) + (|D(i-1,i)| - J(i-1))/16
+        let transit = arrival.wrapping_sub(packet.timestamp) as i32 as i64;
+        if let Some(last) = self.transit {
+            let d = (transit - last).abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+        let delay = self.duration as f64 + 3.0 * self.jitter;
+        self.delay = std::cmp::min(std::cmp::max(delay.ceil() as usize, self.min_delay),
+                                   self.max_delay);
+
+        if self.highest.is_none_or(|highest| sequence > highest) {
+            self.highest = Some(sequence);
+            self.highest_timestamp = timestamp;
+        }
+        if self.next.is_none() {
+            self.next = Some(sequence);
+            self.playout = timestamp;
+        }
+        self.packets.insert(sequence,
+                            Entry {
+                                timestamp,
+                                marker: packet.marker,
+                                payload: packet.payload.to_vec(),
+                            });
+    }
+
+    /// Decode the audio due next into `pcm`, with the interleaved channels of `decoder`. Returns
+    /// the number of samples per channel, or `None` while buffering, when the output should be
+    /// silence.
+    pub fn get(&mut self,
+               decoder: &mut OpusDecoder,
+               pcm: &mut [f32])
+               -> Result<Option<usize>, Error> {
+        let next = match self.next {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        if self.buffering {
+            if self.buffered() < self.delay {
+                return Ok(None);
+            }
+            self.buffering = false;
+        }
+
+        let first = self.packets.keys().next().cloned();
+        let n = match first {
+            Some(sequence) if sequence == next => {
+                let timestamp = self.packets[&sequence].timestamp;
+                if timestamp > self.playout {
+                    // DTX: conceal up to the next talkspurt, or wait for it to fill the buffer.
+                    if self.packets[&sequence].marker && !self.packets.contains_key(&(next + 1)) {
+                        self.buffering = true;
+                        self.playout = timestamp;
+                        return Ok(None);
+                    }
+                    let gap = (timestamp - self.playout) as usize;
+                    decoder.decode_lost(std::cmp::min(gap, self.duration), pcm)?
+                } else {
+                    let entry = self.packets.remove(&sequence).unwrap();
+                    self.next = Some(next + 1);
+                    self.stats.decoded += 1;
+                    self.playout = entry.timestamp;
+                    let n = decoder.decode(Some(&entry.payload), pcm)?;
+                    self.duration = n;
+                    n
+                }
+            }
+            Some(_) => {
+                self.next = Some(next + 1);
+                self.stats.lost += 1;
+                decoder.decode_lost(self.duration, pcm)?
+            }
+            None => {
+                // Underrun, or DTX: wait for the next packet.
+                decoder.decode_lost(self.duration, pcm)?
+            }
+        };
+        self.playout += n as i64;
+        Ok(Some(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The payload of packet `sequence`.
+    fn payload(sequence: u16) -> Vec<u8> {
+        packet::tests::celt_packet(sequence as usize)
+    }
+
+    /// Play a recorded trace, giving the arrival time in samples at 48 kHz, sequence number,
+    /// timestamp and marker bit of each packet, in order of arrival. The output clock asks for
+    /// 20 ms of audio every 960 samples, from time 0 to `end`. Returns the buffer and what each
+    /// call to `get` returned.
+    fn play(trace: &[(u32, u16, u32, bool)],
+            min_delay: usize,
+            end: u32)
+            -> (JitterBuffer, Vec<Option<usize>>) {
+        let mut buffer = JitterBuffer::new(min_delay, 4800);
+        let mut decoder = OpusDecoder::new();
+        let mut pcm = vec![0.0; 2 * packet::MAX_PACKET_DURATION];
+        let mut output = vec![];
+        let mut packets = trace.iter().peekable();
+        for now in (0..end).step_by(960) {
+            while let Some(&&(arrival, sequence, timestamp, marker)) = packets.peek() {
+                if arrival > now {
+                    break;
+                }
+                let payload = payload(sequence);
+                let data = RtpPacket {
+                               marker,
+                               payload_type: 111,
+                               sequence,
+                               timestamp,
+                               ssrc: 0x1234,
+                               csrc: vec![],
+                               payload: &payload,
+                           }
+                           .to_bytes();
+                buffer.push(&RtpPacket::parse(&data).unwrap(), arrival);
+                packets.next();
+            }
+            output.push(buffer.get(&mut decoder, &mut pcm).unwrap());
+        }
+        (buffer, output)
+    }
+
+    #[test]
+    fn parse_header() {
+        let mut data = vec![0xb1, 0xef, 0x12, 0x34, 0, 0, 0x03, 0xc0, 0xde, 0xad, 0xbe, 0xef];
+        data.extend_from_slice(&[0, 0, 0, 7]);
+        data.extend_from_slice(&[0xbe, 0xde, 0, 1, 1, 2, 3, 4]);
+        data.extend_from_slice(&[0xfc, 0xff, 0xfe]);
+        data.extend_from_slice(&[0, 0, 3]);
+        let packet = RtpPacket::parse(&data).unwrap();
+        assert!(packet.marker);
+        assert_eq!(packet.payload_type, 111);
+        assert_eq!(packet.sequence, 0x1234);
+        assert_eq!(packet.timestamp, 960);
+        assert_eq!(packet.ssrc, 0xdeadbeef);
+        assert_eq!(packet.csrc, vec![7]);
+        assert_eq!(packet.payload, &[0xfc, 0xff, 0xfe]);
+
+        assert_eq!(RtpPacket::parse(&data[..11]), Err(Error::InvalidPacket));
+        data[0] = 0x71;
+        assert_eq!(RtpPacket::parse(&data), Err(Error::InvalidPacket));
+    }
+
+    #[test]
+    fn in_order_with_jitter() {
+        // The sequence numbers wrap around.
+        let trace: Vec<_> = (0..20u16)
+            .map(|i| {
+                let jitter = [0, 250, 40, 600, 120][i as usize % 5];
+                (960 * i as u32 + jitter, 65530u16.wrapping_add(i), 1000 + 960 * i as u32, i == 0)
+            })
+            .collect();
+        let (buffer, output) = play(&trace, 1920, 960 * 24);
+        assert_eq!(buffer.stats,
+                   JitterStats {
+                       decoded: 20,
+                       lost: 0,
+                       late: 0,
+                   });
+        // Playout starts once 40 ms are buffered, then never stops.
+        let start = output.iter().position(Option::is_some).unwrap();
+        assert!(output[start..start + 20].iter().all(|&n| n == Some(960)));
+        assert!(buffer.jitter() > 0.0);
+        assert!(buffer.delay() >= 1920 && buffer.delay() <= 4800);
+    }
+
+    #[test]
+    fn reordered() {
+        let trace = [(0, 100, 0, true),
+                     (960, 101, 960, false),
+                     (1920, 103, 2880, false),
+                     (2100, 102, 1920, false),
+                     (3840, 104, 3840, false),
+                     (4800, 105, 4800, false)];
+        let (buffer, output) = play(&trace, 1920, 960 * 8);
+        assert_eq!(buffer.stats,
+                   JitterStats {
+                       decoded: 6,
+                       lost: 0,
+                       late: 0,
+                   });
+        // Packet 103 waits in the buffer for packet 102.
+        let f = Some(960);
+        assert_eq!(output, [None, f, f, f, f, f, f, f]);
+    }
+
+    #[test]
+    fn missing_and_late() {
+        // Packet 2 never arrives, and packet 4 arrives after its playout time.
+        let trace = [(0, 0, 0, true),
+                     (960, 1, 960, false),
+                     (2880, 3, 2880, false),
+                     (4800, 5, 4800, false),
+                     (5000, 4, 3840, false),
+                     (5760, 6, 5760, false),
+                     (6720, 7, 6720, false)];
+        let (buffer, output) = play(&trace, 1920, 960 * 8);
+        assert_eq!(buffer.stats,
+                   JitterStats {
+                       decoded: 5,
+                       lost: 2,
+                       late: 1,
+                   });
+        // The missing packets are concealed without interrupting the output.
+        let start = output.iter().position(Option::is_some).unwrap();
+        assert!(output[start..].iter().all(|&n| n == Some(960)));
+    }
+
+    #[test]
+    fn dtx() {
+        // Transmission stops after packet 2, and resumes 100 ms later with a talkspurt.
+        let trace = [(0, 0, 0, true),
+                     (960, 1, 960, false),
+                     (1920, 2, 1920, false),
+                     (7680, 3, 7680, true),
+                     (8640, 4, 8640, false),
+                     (9600, 5, 9600, false)];
+        let (buffer, output) = play(&trace, 1920, 960 * 12);
+        assert_eq!(buffer.stats,
+                   JitterStats {
+                       decoded: 6,
+                       lost: 0,
+                       late: 0,
+                   });
+        // The gap is concealed, not counted as lost, until the talkspurt starts. Playout then
+        // waits for the talkspurt to fill the buffer.
+        let f = Some(960);
+        assert_eq!(output, [None, f, f, f, f, f, f, f, None, f, f, f]);
+    }
+
+    #[test]
+    fn packetizer() {
+        let mut packetizer = RtpPacketizer::new(111, 0xdeadbeef, 65535, 0xffffff00);
+        let data = packetizer.packetize(&payload(0)).unwrap();
+        let packet = RtpPacket::parse(&data).unwrap();
+        assert_eq!(packet.to_bytes(), data);
+        assert_eq!((packet.marker, packet.payload_type, packet.ssrc), (true, 111, 0xdeadbeef));
+        assert_eq!((packet.sequence, packet.timestamp), (65535, 0xffffff00));
+        assert_eq!(packet.payload, &payload(0)[..]);
+
+        // Two 20 ms frames, wrapping the sequence number and timestamp.
+        let data = packetizer.packetize(&[0xfd, 0x55, 0xaa]).unwrap();
+        let packet = RtpPacket::parse(&data).unwrap();
+        assert_eq!((packet.marker, packet.sequence, packet.timestamp), (false, 0, 0x2c0));
+        assert_eq!((packetizer.sequence, packetizer.timestamp), (1, 0xa40));
+        assert_eq!(packetizer.packetize(&[]), Err(Error::InvalidPacket));
+
+        // A new talkspurt after DTX.
+        packetizer.skip(0);
+        let data = packetizer.packetize(&payload(1)).unwrap();
+        assert!(!RtpPacket::parse(&data).unwrap().marker);
+        packetizer.skip(9600);
+        let data = packetizer.packetize(&payload(2)).unwrap();
+        let packet = RtpPacket::parse(&data).unwrap();
+        assert_eq!((packet.marker, packet.sequence), (true, 2));
+        assert_eq!(packet.timestamp, 0xa40 + 960 + 9600);
+    }
+
+    #[test]
+    fn fmtp() {
+        assert_eq!(Fmtp::parse(""), Ok(Fmtp::default()));
+        let fmtp = Fmtp::parse("maxplaybackrate=16000; stereo=1;sprop-stereo=0 ; usedtx=1; \
+                                minptime=10")
+            .unwrap();
+        assert_eq!(fmtp,
+                   Fmtp {
+                       maxplaybackrate: 16000,
+                       stereo: true,
+                       sprop_stereo: false,
+                       useinbandfec: false,
+                       usedtx: true,
+                   });
+        assert_eq!(fmtp.to_string(), "maxplaybackrate=16000; stereo=1; usedtx=1");
+        assert_eq!(Fmtp::parse(&fmtp.to_string()), Ok(fmtp));
+        assert_eq!(Fmtp::default().to_string(), "");
+        assert_eq!(Fmtp::parse("stereo=2"), Err(Error::BadArg));
+        assert_eq!(Fmtp::parse("useinbandfec"), Err(Error::BadArg));
+        assert_eq!(Fmtp::parse("maxplaybackrate=fast"), Err(Error::BadArg));
+    }
+}
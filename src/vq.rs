@@ -106,7 +106,8 @@ pub fn alg_unquant(x: &mut [f32],
     cwrs::decode_pulses(x, k, ec, v);
     let mask = extract_collapse_mask(x, stride);
     utils::renormalise(x, gain);
-    if 2 * k < x.len() { // TODO: why?
+    // A spreading value of 0 leaves the vector unrotated.
+    if 2 * k < x.len() && spread != 0 { // TODO: why?
         spread_vector(x, stride, k, spread);
     }
     return mask;
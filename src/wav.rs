@@ -0,0 +1,371 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Sample format of a WAV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16,
+    I24,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+            SampleFormat::F32 => 4,
+        }
+    }
+
+    /// Append interleaved samples in [-1, 1] to `data`, little-endian, clipped and rounded to
+    /// integer formats.
+    pub fn encode(self, pcm: &[f32], data: &mut Vec<u8>) {
+        for &s in pcm {
+            match self {
+                SampleFormat::I16 => {
+                    let s = (s * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+                    data.extend_from_slice(&s.to_le_bytes());
+                }
+                SampleFormat::I24 => {
+                    let s = (s * 8388608.0).round().clamp(-8388608.0, 8388607.0) as i32;
+                    data.extend_from_slice(&s.to_le_bytes()[..3]);
+                }
+                SampleFormat::F32 => data.extend_from_slice(&s.to_le_bytes()),
+            }
+        }
+    }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Size of the ds64 chunk body, also reserved by a JUNK chunk in a RIFF header.
+const DS64_SIZE: usize = 28;
+
+/// Writer of a WAV file.
+///
+/// The header is written first, with the sizes of the data when known:
+///
+///     RIFF size WAVE
+///     JUNK (28 bytes, reserved for ds64)
+///     fmt  (WAVEFORMATEX or WAVEFORMATEXTENSIBLE)
+///     fact (float samples only)
+///     data size samples ...
+///
+/// When the length is unknown, the sizes are 0xffffffff, which most readers take as "up to the
+/// end of the file", so that a non-seekable output is still usable. A seekable output is
+/// patched by `finish`. Past 4 GB, the file becomes RF64 (EBU Tech 3306): the JUNK chunk is
+/// replaced by a ds64 chunk holding the 64-bit sizes, and the 32-bit sizes are 0xffffffff.
+pub struct WavWriter<W> {
+    writer: W,
+    channels: usize,
+    rate: u32,
+    format: SampleFormat,
+    /// Bytes of sample data written.
+    data_size: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> WavWriter<W> {
+    /// Write the header of a file of unknown length.
+    pub fn new(writer: W,
+               channels: usize,
+               rate: u32,
+               format: SampleFormat)
+               -> io::Result<WavWriter<W>> {
+        WavWriter::start(writer, channels, rate, format, None)
+    }
+
+    /// Write the header of a file of `length` samples per channel.
+    pub fn with_length(writer: W,
+                       channels: usize,
+                       rate: u32,
+                       format: SampleFormat,
+                       length: u64)
+                       -> io::Result<WavWriter<W>> {
+        WavWriter::start(writer, channels, rate, format, Some(length))
+    }
+
+    fn start(writer: W,
+             channels: usize,
+             rate: u32,
+             format: SampleFormat,
+             length: Option<u64>)
+             -> io::Result<WavWriter<W>> {
+        if channels == 0 || channels > 0xffff {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid channel count"));
+        }
+        let mut wav = WavWriter {
+            writer,
+            channels,
+            rate,
+            format,
+            data_size: 0,
+            buffer: vec![],
+        };
+        let header = wav.header(length.map(|length| length * wav.block_align() as u64));
+        wav.writer.write_all(&header)?;
+        Ok(wav)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    fn block_align(&self) -> usize {
+        self.channels * self.format.bytes()
+    }
+
+    /// Number of samples per channel written.
+    pub fn length(&self) -> u64 {
+        self.data_size / self.block_align() as u64
+    }
+
+    /// The header for `data_size` bytes of samples, if known.
+    fn header(&self, data_size: Option<u64>) -> Vec<u8> {
+        let float = self.format == SampleFormat::F32;
+        let extensible = self.channels > 2 || self.format == SampleFormat::I24;
+        let fmt_size = if extensible {
+            40
+        } else if float {
+            18
+        } else {
+            16
+        };
+        let fact_size = if float { 12 } else { 0 };
+        let header_size = 12 + 8 + DS64_SIZE + 8 + fmt_size + fact_size + 8;
+        let pad = data_size.map_or(0, |size| size & 1);
+        let riff_size = data_size.map(|size| header_size as u64 - 8 + size + pad);
+        let rf64 = riff_size.is_some_and(|size| size > u32::MAX as u64);
+        let length = data_size.map(|size| size / self.block_align() as u64);
+        let size32 = |size: Option<u64>| match size {
+            Some(size) if !rf64 => size as u32,
+            _ => u32::MAX,
+        };
+
+        let mut header = Vec::with_capacity(header_size);
+        header.extend_from_slice(if rf64 { b"RF64" } else { b"RIFF" });
+        header.extend_from_slice(&size32(riff_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        if rf64 {
+            header.extend_from_slice(b"ds64");
+            header.extend_from_slice(&(DS64_SIZE as u32).to_le_bytes());
+            header.extend_from_slice(&riff_size.unwrap_or(0).to_le_bytes());
+            header.extend_from_slice(&data_size.unwrap_or(0).to_le_bytes());
+            header.extend_from_slice(&length.unwrap_or(0).to_le_bytes());
+            // No table of other chunk sizes.
+            header.extend_from_slice(&0u32.to_le_bytes());
+        } else {
+            header.extend_from_slice(b"JUNK");
+            header.extend_from_slice(&(DS64_SIZE as u32).to_le_bytes());
+            header.extend_from_slice(&[0; DS64_SIZE]);
+        }
+
+        let bits = 8 * self.format.bytes() as u16;
+        let tag = if float {
+            WAVE_FORMAT_IEEE_FLOAT
+        } else {
+            WAVE_FORMAT_PCM
+        };
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&(fmt_size as u32).to_le_bytes());
+        header.extend_from_slice(&(if extensible {
+                                      WAVE_FORMAT_EXTENSIBLE
+                                  } else {
+                                      tag
+                                  })
+                                  .to_le_bytes());
+        header.extend_from_slice(&(self.channels as u16).to_le_bytes());
+        header.extend_from_slice(&self.rate.to_le_bytes());
+        header.extend_from_slice(&(self.rate * self.block_align() as u32).to_le_bytes());
+        header.extend_from_slice(&(self.block_align() as u16).to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        if extensible {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            // Valid bits per sample.
+            header.extend_from_slice(&bits.to_le_bytes());
+            // Channel mask: front left and right, or unassigned channels.
+            let mask: u32 = match self.channels {
+                1 => 0x4,
+                2 => 0x3,
+                _ => 0,
+            };
+            header.extend_from_slice(&mask.to_le_bytes());
+            // Sub-format GUID: the format tag followed by 00000000-0010-8000-00AA00389B71.
+            header.extend_from_slice(&(tag as u32).to_le_bytes());
+            header.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00,
+                                       0x38, 0x9b, 0x71]);
+        } else if float {
+            header.extend_from_slice(&0u16.to_le_bytes());
+        }
+        if float {
+            header.extend_from_slice(b"fact");
+            header.extend_from_slice(&4u32.to_le_bytes());
+            header.extend_from_slice(&size32(length).to_le_bytes());
+        }
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&size32(data_size).to_le_bytes());
+        header
+    }
+
+    /// Write interleaved samples in [-1, 1], clipped and rounded to integer formats.
+    pub fn write(&mut self, pcm: &[f32]) -> io::Result<()> {
+        self.buffer.clear();
+        self.format.encode(pcm, &mut self.buffer);
+        self.writer.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Write interleaved 16-bit samples, which must be the format of the file.
+    pub fn write_i16(&mut self, pcm: &[i16]) -> io::Result<()> {
+        if self.format != SampleFormat::I16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a 16-bit file"));
+        }
+        self.buffer.clear();
+        for &s in pcm {
+            self.buffer.extend_from_slice(&s.to_le_bytes());
+        }
+        self.writer.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Pad the data to an even size and return the output, leaving the header as written.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        if self.data_size & 1 != 0 {
+            self.writer.write_all(&[0])?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Pad the data, write the header again with the final sizes, and return the output.
+    pub fn finish(self) -> io::Result<W> {
+        let header = self.header(Some(self.data_size));
+        let size = header.len() as u64 + self.data_size + (self.data_size & 1);
+        let mut writer = self.into_inner()?;
+        let end = writer.stream_position()?;
+        writer.seek(SeekFrom::Start(end - size))?;
+        writer.write_all(&header)?;
+        writer.seek(SeekFrom::Start(end))?;
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    /// Position of the body of the chunk `id`, and its size.
+    fn chunk(data: &[u8], id: &[u8; 4]) -> Option<(usize, u32)> {
+        let mut pos = 12;
+        while pos + 8 <= data.len() {
+            let size = u32_at(data, pos + 4);
+            if &data[pos..pos + 4] == id {
+                return Some((pos + 8, size));
+            }
+            pos += 8 + size as usize + (size as usize & 1);
+        }
+        None
+    }
+
+    #[test]
+    fn encode() {
+        let pcm = [0.0, 0.5, -1.0, 1.0, 2.0, -1e-5];
+        let mut data = vec![];
+        SampleFormat::I16.encode(&pcm, &mut data);
+        let samples: Vec<i16> = data.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(samples, [0, 16384, -32768, 32767, 32767, 0]);
+        data.clear();
+        SampleFormat::I24.encode(&[0.5, -1.0, 1.0], &mut data);
+        assert_eq!(data, [0, 0, 0x40, 0, 0, 0x80, 0xff, 0xff, 0x7f]);
+        data.clear();
+        SampleFormat::F32.encode(&[2.0], &mut data);
+        assert_eq!(data, 2f32.to_le_bytes());
+    }
+
+    #[test]
+    fn known_length() {
+        let mut wav = WavWriter::with_length(vec![], 2, 48000, SampleFormat::I16, 3).unwrap();
+        wav.write(&[0.0; 4]).unwrap();
+        wav.write_i16(&[1, 2]).unwrap();
+        assert_eq!(wav.length(), 3);
+        let data = wav.into_inner().unwrap();
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(chunk(&data, b"JUNK"), Some((20, DS64_SIZE as u32)));
+        let (fmt, size) = chunk(&data, b"fmt ").unwrap();
+        assert_eq!(size, 16);
+        assert_eq!(&data[fmt..fmt + 4], [1, 0, 2, 0]);
+        assert_eq!((u32_at(&data, fmt + 4), u32_at(&data, fmt + 8)), (48000, 192000));
+        assert_eq!(&data[fmt + 12..fmt + 16], [4, 0, 16, 0]);
+        assert_eq!(chunk(&data, b"fact"), None);
+        let (pos, size) = chunk(&data, b"data").unwrap();
+        assert_eq!((size, data.len() - pos), (12, 12));
+        assert_eq!(&data[pos + 8..], [1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn unknown_length() {
+        let mut wav = WavWriter::new(io::Cursor::new(vec![]), 1, 16000, SampleFormat::I24)
+            .unwrap();
+        wav.write(&[0.5]).unwrap();
+        let written = wav.get_ref().get_ref().clone();
+        assert_eq!(u32_at(&written, 4), u32::MAX);
+        assert_eq!(u32_at(&written, written.len() - 7), u32::MAX);
+
+        // The odd data size is padded, and the sizes patched.
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(data.len(), written.len() + 1);
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        // WAVEFORMATEXTENSIBLE, with the PCM sub-format and a mask of front center.
+        let (fmt, size) = chunk(&data, b"fmt ").unwrap();
+        assert_eq!((size, &data[fmt..fmt + 2]), (40, &[0xfe, 0xff][..]));
+        assert_eq!((u32_at(&data, fmt + 20), u32_at(&data, fmt + 24)), (4, 1));
+        assert_eq!(chunk(&data, b"data").map(|(_, size)| size), Some(3));
+        assert_eq!(&data[data.len() - 4..], [0, 0, 0x40, 0]);
+    }
+
+    #[test]
+    fn float() {
+        let mut wav = WavWriter::new(io::Cursor::new(vec![]), 2, 48000, SampleFormat::F32)
+            .unwrap();
+        wav.write(&[0.25; 10]).unwrap();
+        assert!(wav.write_i16(&[0; 2]).is_err());
+        let data = wav.finish().unwrap().into_inner();
+        let (fmt, size) = chunk(&data, b"fmt ").unwrap();
+        assert_eq!((size, &data[fmt..fmt + 2]), (18, &[3, 0][..]));
+        let (fact, size) = chunk(&data, b"fact").unwrap();
+        assert_eq!((size, u32_at(&data, fact)), (4, 5));
+        assert_eq!(chunk(&data, b"data").map(|(_, size)| size), Some(40));
+    }
+
+    #[test]
+    fn rf64() {
+        let length = 1 << 30;
+        let wav = WavWriter::with_length(vec![], 2, 48000, SampleFormat::F32, length).unwrap();
+        let data = wav.into_inner().unwrap();
+        let u64_at = |pos| u32_at(&data, pos) as u64 | (u32_at(&data, pos + 4) as u64) << 32;
+        assert_eq!((&data[..4], u32_at(&data, 4)), (&b"RF64"[..], u32::MAX));
+        let (ds64, size) = chunk(&data, b"ds64").unwrap();
+        assert_eq!(size as usize, DS64_SIZE);
+        assert_eq!(u64_at(ds64), data.len() as u64 - 8 + 8 * length);
+        assert_eq!((u64_at(ds64 + 8), u64_at(ds64 + 16)), (8 * length, length));
+        assert_eq!(chunk(&data, b"data"), Some((data.len(), u32::MAX)));
+        assert_eq!(u32_at(&data, chunk(&data, b"fact").unwrap().0), u32::MAX);
+    }
+
+    #[test]
+    fn invalid_channels() {
+        assert!(WavWriter::new(vec![], 0, 48000, SampleFormat::I16).is_err());
+        assert!(WavWriter::new(vec![], 0x10000, 48000, SampleFormat::I16).is_err());
+    }
+}
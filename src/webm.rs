@@ -0,0 +1,585 @@
+use header::OpusHead;
+use packet;
+use std;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+const EBML: u32 = 0x1A45DFA3;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549A966;
+const TIMECODE_SCALE: u32 = 0x2AD7B1;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const DISCARD_PADDING: u32 = 0x75A2;
+
+/// Elements whose children are read. The others are read whole, or skipped.
+const MASTERS: [u32; 6] = [SEGMENT, INFO, TRACKS, TRACK_ENTRY, CLUSTER, BLOCK_GROUP];
+
+/// Leaves larger than this are skipped rather than read.
+const MAX_ELEMENT_SIZE: u64 = 1 << 24;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Convert nanoseconds to samples at 48 kHz, rounded to the nearest, as durations in samples
+/// are stored truncated to the nanosecond.
+pub fn ns_to_samples(ns: i64) -> i64 {
+    (ns * 48 + 500_000).div_euclid(1_000_000)
+}
+
+/// Convert samples at 48 kHz to nanoseconds.
+pub fn samples_to_ns(samples: i64) -> i64 {
+    samples * 1_000_000 / 48
+}
+
+/// The Opus track of a Matroska file, as described by its TrackEntry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusTrack {
+    pub number: u64,
+    /// The identification header, held in CodecPrivate.
+    pub head: OpusHead,
+    /// CodecDelay, in ns: the pre-skip.
+    pub codec_delay: u64,
+    /// SeekPreRoll, in ns: the audio to decode before a seek target.
+    pub seek_pre_roll: u64,
+}
+
+/// A packet of the Opus track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebmPacket {
+    pub data: Vec<u8>,
+    /// Timestamp of the packet, in ns, including the codec delay.
+    pub timestamp: i64,
+    /// Duration to discard from the end of the packet, in ns.
+    pub discard_padding: i64,
+}
+
+#[derive(Default)]
+struct TrackEntry {
+    number: u64,
+    codec_id: Vec<u8>,
+    codec_private: Vec<u8>,
+    codec_delay: u64,
+    seek_pre_roll: u64,
+}
+
+/// Reader of the Opus track of a WebM or Matroska file.
+///
+/// The EBML elements are read in order, descending only into those leading to the track
+/// entries and the blocks, so that the source need not be seekable. Elements of unknown size,
+/// as written by live encoders, are supported for segments and clusters. The source is read in
+/// small pieces, and is best buffered.
+pub struct WebmReader<R> {
+    reader: R,
+    pos: u64,
+    /// Open master elements, with their end.
+    stack: Vec<(u32, Option<u64>)>,
+    track: Option<OpusTrack>,
+    entry: TrackEntry,
+    /// Duration of a timecode unit, in ns.
+    timecode_scale: u64,
+    cluster_timecode: u64,
+    /// Block and discard padding of the current BlockGroup.
+    block: Option<Vec<u8>>,
+    discard_padding: i64,
+    queue: VecDeque<WebmPacket>,
+}
+
+impl<R: Read> WebmReader<R> {
+    /// Read the file up to the first cluster, and find its first Opus track.
+    pub fn new(reader: R) -> io::Result<WebmReader<R>> {
+        let mut webm = WebmReader {
+            reader,
+            pos: 0,
+            stack: vec![],
+            track: None,
+            entry: Default::default(),
+            timecode_scale: 1_000_000,
+            cluster_timecode: 0,
+            block: None,
+            discard_padding: 0,
+            queue: VecDeque::new(),
+        };
+        match webm.read_header()? {
+            Some((EBML, Some(size))) => webm.skip(size)?,
+            _ => return Err(invalid_data("not an EBML file")),
+        }
+        while !webm.stack.iter().any(|&(id, _)| id == CLUSTER) {
+            if !webm.step()? {
+                break;
+            }
+        }
+        if webm.track.is_none() {
+            return Err(invalid_data("no Opus track"));
+        }
+        Ok(webm)
+    }
+
+    pub fn track(&self) -> &OpusTrack {
+        self.track.as_ref().unwrap()
+    }
+
+    /// Read the next packet of the Opus track. Returns `None` at the end of the file.
+    pub fn next_packet(&mut self) -> io::Result<Option<WebmPacket>> {
+        loop {
+            if let Some(packet) = self.queue.pop_front() {
+                return Ok(Some(packet));
+            }
+            if !self.step()? {
+                // Closing the open elements may have completed a last block.
+                return Ok(self.queue.pop_front());
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read_exact(&mut byte) {
+            Ok(()) => {
+                self.pos += 1;
+                Ok(Some(byte[0]))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Variable size integer, with its length marker kept for IDs. Returns the value and whether
+    /// all its value bits are set, or `None` at the end of the file.
+    fn read_vint(&mut self, keep_marker: bool) -> io::Result<Option<(u64, bool)>> {
+        let first = match self.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let len = first.leading_zeros() as usize + 1;
+        if len > 8 {
+            return Err(invalid_data("invalid EBML integer"));
+        }
+        let mask = (0xffu16 >> len) as u8;
+        let mut value = if keep_marker {
+            first as u64
+        } else {
+            (first & mask) as u64
+        };
+        let mut all_ones = first | !mask == 0xff;
+        for _ in 1..len {
+            let byte = self.read_byte()?.ok_or_else(|| invalid_data("truncated EBML integer"))?;
+            value = value << 8 | byte as u64;
+            all_ones &= byte == 0xff;
+        }
+        Ok(Some((value, all_ones)))
+    }
+
+    /// Element ID and size, `None` for an unknown size.
+    fn read_header(&mut self) -> io::Result<Option<(u32, Option<u64>)>> {
+        let id = match self.read_vint(true)? {
+            Some((id, _)) => id as u32,
+            None => return Ok(None),
+        };
+        match self.read_vint(false)? {
+            Some((_, true)) => Ok(Some((id, None))),
+            Some((size, false)) => Ok(Some((id, Some(size)))),
+            None => Err(invalid_data("truncated EBML element")),
+        }
+    }
+
+    fn read_data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; size as usize];
+        self.reader.read_exact(&mut data)?;
+        self.pos += size;
+        Ok(data)
+    }
+
+    fn skip(&mut self, size: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        self.pos += skipped;
+        if skipped < size {
+            return Err(invalid_data("truncated EBML element"));
+        }
+        Ok(())
+    }
+
+    /// Close the master element on top of the stack.
+    fn close(&mut self) {
+        match self.stack.pop() {
+            Some((TRACK_ENTRY, _)) => {
+                let entry = std::mem::take(&mut self.entry);
+                if self.track.is_none() && entry.codec_id == b"A_OPUS" {
+                    if let Ok(head) = OpusHead::parse(&entry.codec_private) {
+                        self.track = Some(OpusTrack {
+                            number: entry.number,
+                            head,
+                            codec_delay: entry.codec_delay,
+                            seek_pre_roll: entry.seek_pre_roll,
+                        });
+                    }
+                }
+            }
+            Some((BLOCK_GROUP, _)) => {
+                if let Some(block) = self.block.take() {
+                    let discard_padding = self.discard_padding;
+                    self.push_block(&block, discard_padding);
+                }
+                self.discard_padding = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Process the next element. Returns false at the end of the file.
+    fn step(&mut self) -> io::Result<bool> {
+        while let Some(&(_, Some(end))) = self.stack.last() {
+            if self.pos < end {
+                break;
+            }
+            self.close();
+        }
+        let (id, size) = match self.read_header()? {
+            Some(header) => header,
+            None => {
+                while !self.stack.is_empty() {
+                    self.close();
+                }
+                return Ok(false);
+            }
+        };
+        // A cluster of unknown size ends at the next cluster.
+        if id == CLUSTER {
+            while self.stack.last().is_some_and(|&(id, _)| id != SEGMENT) {
+                self.close();
+            }
+        }
+
+        if MASTERS.contains(&id) {
+            if id == CLUSTER {
+                self.cluster_timecode = 0;
+            }
+            self.stack.push((id, size.map(|size| self.pos + size)));
+            return Ok(true);
+        }
+        let size = size.ok_or_else(|| invalid_data("unknown size for a non-master element"))?;
+        let wanted = matches!(id,
+                              TIMECODE_SCALE | TIMECODE | SIMPLE_BLOCK | BLOCK | DISCARD_PADDING |
+                              TRACK_NUMBER | CODEC_ID | CODEC_PRIVATE | CODEC_DELAY |
+                              SEEK_PRE_ROLL);
+        if !wanted || size > MAX_ELEMENT_SIZE {
+            self.skip(size)?;
+            return Ok(true);
+        }
+        let data = self.read_data(size)?;
+        let uint = || data.iter().fold(0u64, |value, &byte| value << 8 | byte as u64);
+        match id {
+            TIMECODE_SCALE => self.timecode_scale = uint(),
+            TIMECODE => self.cluster_timecode = uint(),
+            TRACK_NUMBER => self.entry.number = uint(),
+            CODEC_ID => self.entry.codec_id = data.clone(),
+            CODEC_PRIVATE => self.entry.codec_private = data.clone(),
+            CODEC_DELAY => self.entry.codec_delay = uint(),
+            SEEK_PRE_ROLL => self.entry.seek_pre_roll = uint(),
+            SIMPLE_BLOCK => self.push_block(&data, 0),
+            BLOCK => self.block = Some(data.clone()),
+            DISCARD_PADDING => {
+                // A signed integer, sign-extended from its size.
+                let shift = 64 - 8 * std::cmp::min(data.len(), 8) as u32;
+                self.discard_padding = if shift == 64 {
+                    0
+                } else {
+                    (uint() << shift) as i64 >> shift
+                };
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Queue the frames of a SimpleBlock or Block of the Opus track.
+    ///
+    ///      0                   1                   2                   3
+    ///      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    ///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    ///     | track number  |  relative timecode (s16)      |     flags     |
+    ///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    ///     :                  lacing, then frames ...                      :
+    ///     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    fn push_block(&mut self, data: &[u8], discard_padding: i64) {
+        let number = match self.track {
+            Some(ref track) => track.number,
+            None => return,
+        };
+        if let Some((frames, timecode)) = parse_block(data, number) {
+            let mut timestamp = (self.cluster_timecode as i64 + timecode as i64) *
+                                self.timecode_scale as i64;
+            let count = frames.len();
+            for (i, frame) in frames.into_iter().enumerate() {
+                let duration = packet::duration(frame).unwrap_or(0) as i64;
+                self.queue.push_back(WebmPacket {
+                    data: frame.to_vec(),
+                    timestamp,
+                    discard_padding: if i == count - 1 { discard_padding } else { 0 },
+                });
+                timestamp += samples_to_ns(duration);
+            }
+        }
+    }
+}
+
+/// Split a block into its frames, if it belongs to track `number`, and read its relative
+/// timecode.
+fn parse_block(data: &[u8], number: u64) -> Option<(Vec<&[u8]>, i16)> {
+    let (track, mut pos) = read_vint(data)?;
+    if track != number || data.len() < pos + 3 {
+        return None;
+    }
+    let timecode = i16::from_be_bytes([data[pos], data[pos + 1]]);
+    let flags = data[pos + 2];
+    pos += 3;
+    let lacing = (flags >> 1) & 3;
+    if lacing == 0 {
+        return Some((vec![&data[pos..]], timecode));
+    }
+    let count = *data.get(pos)? as usize + 1;
+    pos += 1;
+    let mut sizes = Vec::with_capacity(count);
+    match lacing {
+        // Xiph lacing: sizes as sums of bytes up to one below 255.
+        1 => {
+            for _ in 0..count - 1 {
+                let mut size = 0;
+                loop {
+                    let byte = *data.get(pos)?;
+                    pos += 1;
+                    size += byte as usize;
+                    if byte < 255 {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // EBML lacing: the first size, then signed differences.
+        3 => {
+            let (first, len) = read_vint(&data[pos..])?;
+            pos += len;
+            let mut size = first as i64;
+            sizes.push(first as usize);
+            for _ in 1..count - 1 {
+                let (diff, len) = read_vint(&data[pos..])?;
+                let bias = (1i64 << (7 * len - 1)) - 1;
+                size += diff as i64 - bias;
+                pos += len;
+                if size < 0 {
+                    return None;
+                }
+                sizes.push(size as usize);
+            }
+        }
+        // Fixed-size lacing.
+        _ => {
+            let size = (data.len() - pos) / count;
+            sizes = vec![size; count - 1];
+        }
+    }
+    let mut frames = Vec::with_capacity(count);
+    for size in sizes {
+        if data.len() < pos + size {
+            return None;
+        }
+        frames.push(&data[pos..pos + size]);
+        pos += size;
+    }
+    frames.push(&data[pos..]);
+    Some((frames, timecode))
+}
+
+/// Variable size integer in a buffer, without its length marker, and its length.
+fn read_vint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len {
+        return None;
+    }
+    let value = data[1..len]
+        .iter()
+        .fold((first & (0xffu16 >> len) as u8) as u64, |value, &byte| value << 8 | byte as u64);
+    Some((value, len))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An element with its ID and an 8-byte size, or an unknown size for `None`.
+    pub fn element(id: u32, data: Option<&[u8]>) -> Vec<u8> {
+        let mut element = id.to_be_bytes()[id.leading_zeros() as usize / 8..].to_vec();
+        match data {
+            Some(data) => {
+                element.push(0x01);
+                element.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+                element.extend_from_slice(data);
+            }
+            None => element.extend_from_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+        }
+        element
+    }
+
+    pub fn uint(id: u32, value: u64) -> Vec<u8> {
+        element(id, Some(&value.to_be_bytes()))
+    }
+
+    /// A block of track `track`, with `lacing` and its lacing header already in `frames`.
+    pub fn block(track: u8, timecode: i16, lacing: u8, frames: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x80 | track];
+        data.extend_from_slice(&timecode.to_be_bytes());
+        data.push(0x80 | lacing << 1);
+        data.extend_from_slice(frames);
+        data
+    }
+
+    /// A simple block of the Opus track, of a single packet.
+    pub fn simple_block(timecode: i16, data: &[u8]) -> Vec<u8> {
+        element(SIMPLE_BLOCK, Some(&block(1, timecode, 0, data)))
+    }
+
+    /// A block group of the Opus track, of a single packet with its discard padding in ns.
+    pub fn block_group(timecode: i16, data: &[u8], discard_padding: i64) -> Vec<u8> {
+        let mut group = element(BLOCK, Some(&block(1, timecode, 0, data)));
+        group.extend(element(DISCARD_PADDING, Some(&discard_padding.to_be_bytes())));
+        element(BLOCK_GROUP, Some(&group))
+    }
+
+    /// A WebM file with an Opus track 1 of header `head`, a track 2 of another codec, and the
+    /// given cluster contents, each with its timecode, at the default scale of 1 ms.
+    pub fn webm(head: &OpusHead, codec_delay: u64, clusters: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut data = element(EBML, Some(&element(0x4282, Some(b"webm"))));
+        let mut video = uint(TRACK_NUMBER, 2);
+        video.extend(element(CODEC_ID, Some(b"V_VP9")));
+        let mut audio = uint(TRACK_NUMBER, 1);
+        audio.extend(element(CODEC_ID, Some(b"A_OPUS")));
+        audio.extend(element(CODEC_PRIVATE, Some(&head.to_bytes())));
+        audio.extend(uint(CODEC_DELAY, codec_delay));
+        audio.extend(uint(SEEK_PRE_ROLL, 80_000_000));
+        let mut tracks = element(TRACK_ENTRY, Some(&video));
+        tracks.extend(element(TRACK_ENTRY, Some(&audio)));
+        let mut segment = element(INFO, Some(&uint(TIMECODE_SCALE, 1_000_000)));
+        segment.extend(element(TRACKS, Some(&tracks)));
+        for &(timecode, ref blocks) in clusters {
+            let mut cluster = uint(TIMECODE, timecode);
+            cluster.extend_from_slice(blocks);
+            segment.extend(element(CLUSTER, Some(&cluster)));
+        }
+        data.extend(element(SEGMENT, Some(&segment)));
+        data
+    }
+
+    pub fn head() -> OpusHead {
+        OpusHead::parse(b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00").unwrap()
+    }
+
+    fn packets(data: &[u8]) -> Vec<WebmPacket> {
+        let mut reader = WebmReader::new(data).unwrap();
+        std::iter::from_fn(|| reader.next_packet().unwrap()).collect()
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(ns_to_samples(20_000_000), 960);
+        // Timestamps rounded to the millisecond, and durations truncated to the nanosecond.
+        assert_eq!(ns_to_samples(6_499_999), 312);
+        assert_eq!(ns_to_samples(samples_to_ns(312)), 312);
+        assert_eq!(ns_to_samples(-20_000_000), -960);
+        assert_eq!(samples_to_ns(48), 1_000_000);
+    }
+
+    #[test]
+    fn track_and_blocks() {
+        let mut blocks = element(SIMPLE_BLOCK, Some(&block(1, 0, 0, &[0xfc, 1])));
+        blocks.extend(element(SIMPLE_BLOCK, Some(&block(2, 0, 0, &[0xff; 10]))));
+        blocks.extend(element(SIMPLE_BLOCK, Some(&block(1, 20, 0, &[0xfc, 2]))));
+        let mut group = element(BLOCK, Some(&block(1, 40, 0, &[0xfc, 3])));
+        group.extend(element(DISCARD_PADDING, Some(&[0x7d])));
+        blocks.extend(element(BLOCK_GROUP, Some(&group)));
+        let data = webm(&head(), 6_500_000, &[(1000, blocks)]);
+
+        let reader = WebmReader::new(&data[..]).unwrap();
+        assert_eq!(reader.track(),
+                   &OpusTrack {
+                       number: 1,
+                       head: head(),
+                       codec_delay: 6_500_000,
+                       seek_pre_roll: 80_000_000,
+                   });
+        let packets = packets(&data);
+        let summary: Vec<_> = packets.iter()
+            .map(|p| (p.data[1], p.timestamp, p.discard_padding))
+            .collect();
+        assert_eq!(summary,
+                   [(1, 1_000_000_000, 0), (2, 1_020_000_000, 0), (3, 1_040_000_000, 125)]);
+    }
+
+    #[test]
+    fn lacing() {
+        // Three packets of 20, 10 and 20 ms, in the three kinds of lacing.
+        let frames: [&[u8]; 3] = [&[0xfc, 1, 1], &[0xf4; 300], &[0xfc, 3]];
+        let mut xiph = vec![2, 3, 255, 45];
+        let mut ebml = vec![2, 0x83, 0x61, 0x28];
+        for frame in &frames {
+            xiph.extend_from_slice(frame);
+            ebml.extend_from_slice(frame);
+        }
+        let mut fixed = vec![2];
+        fixed.extend_from_slice(&[0xfc, 1, 0xf8, 2, 0xfc, 3]);
+        let mut blocks = element(SIMPLE_BLOCK, Some(&block(1, 0, 1, &xiph)));
+        blocks.extend(element(SIMPLE_BLOCK, Some(&block(1, 50, 3, &ebml))));
+        blocks.extend(element(SIMPLE_BLOCK, Some(&block(1, 100, 2, &fixed))));
+        let data = webm(&head(), 0, &[(0, blocks)]);
+
+        let packets = packets(&data);
+        let data: Vec<&[u8]> = packets.iter().map(|p| &p.data[..]).collect();
+        assert_eq!(data[..3], frames);
+        assert_eq!(data[3..6], frames);
+        assert_eq!(data[6..], [&[0xfc, 1][..], &[0xf8, 2][..], &[0xfc, 3][..]]);
+        let timestamps: Vec<i64> = packets.iter().map(|p| p.timestamp / 1_000_000).collect();
+        assert_eq!(timestamps, [0, 20, 30, 50, 70, 80, 100, 120, 140]);
+    }
+
+    #[test]
+    fn unknown_sizes() {
+        // A live stream: a segment and clusters of unknown size.
+        let mut data = element(EBML, Some(&[]));
+        let mut entry = uint(TRACK_NUMBER, 1);
+        entry.extend(element(CODEC_ID, Some(b"A_OPUS")));
+        entry.extend(element(CODEC_PRIVATE, Some(&head().to_bytes())));
+        let mut segment = element(TRACKS, Some(&element(TRACK_ENTRY, Some(&entry))));
+        for i in 0..3 {
+            segment.extend(element(CLUSTER, None));
+            segment.extend(uint(TIMECODE, 100 * i));
+            segment.extend(element(SIMPLE_BLOCK, Some(&block(1, 0, 0, &[0xfc, i as u8]))));
+        }
+        data.extend(element(SEGMENT, None));
+        data.extend(segment);
+        let timestamps: Vec<i64> = packets(&data).iter().map(|p| p.timestamp / 1_000_000).collect();
+        assert_eq!(timestamps, [0, 100, 200]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(WebmReader::new(&b"OggS"[..]).is_err());
+        let data = webm(&head(), 0, &[]);
+        // Cut in the track entries.
+        let truncated = &data[..data.len() / 2];
+        assert_eq!(WebmReader::new(truncated).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::InvalidData));
+        assert_eq!(packets(&data), []);
+    }
+}